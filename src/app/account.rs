@@ -0,0 +1,567 @@
+use glib;
+use gtk::{self, prelude::*};
+use url::Url;
+
+use crate::bg_thread::{
+    ConnectError,
+    ConnectionMethod,
+    HomeserverAddress,
+    InternalUserId,
+    MatrixCommand,
+    RegistrationResponse,
+    RegistrationStage,
+    UserSpecificCommand,
+};
+
+/// The "account_new" and "new_password" pages of user_menu, and the progress
+/// of logging in shown in greeter_view.
+///
+/// All of its methods take `&self`, like `InvitePopover`, because the dialogs
+/// it opens call back into it.
+pub(super) struct AccountMenu {
+    menu: gtk::PopoverMenu,
+    /// The widget the menu belongs to when it isn't opened from elsewhere.
+    menu_button: gtk::Widget,
+    stack: gtk::Stack,
+    login_id_entry: gtk::Entry,
+    login_password_entry: gtk::Entry,
+    login_button: gtk::Button,
+    sso_button: gtk::Button,
+    hs_server_entry: gtk::Entry,
+    /// What `hs_server_entry` contains initially.
+    default_homeserver: String,
+    ident_server_entry: gtk::Entry,
+    register_name_entry: gtk::Entry,
+    register_email_entry: gtk::Entry,
+    regpass_entry: gtk::Entry,
+    regpass_confirm_entry: gtk::Entry,
+    regpass_button: gtk::Button,
+    main_window_stack: gtk::Stack,
+    greeter_buttons: gtk::ButtonBox,
+    greeter_spinner: gtk::Spinner,
+    greeter_error_label: gtk::Label,
+    window: gtk::ApplicationWindow,
+}
+
+impl AccountMenu {
+    pub fn new(gtk_builder: &gtk::Builder) -> Self {
+        let hs_server_entry: gtk::Entry = gtk_builder
+            .get_object("u_hs_server_entry")
+            .expect("Couldn't find homeserver entry in ui file.");
+        let default_homeserver = entry_text(&hs_server_entry);
+
+        let account_menu = AccountMenu {
+            menu: gtk_builder
+                .get_object("user_menu")
+                .expect("Couldn't find user menu in ui file."),
+            menu_button: gtk_builder
+                .get_object("header_accounts_button")
+                .expect("Couldn't find header accounts button in ui file."),
+            stack: gtk_builder
+                .get_object("user_menu_stack")
+                .expect("Couldn't find user menu stack in ui file."),
+            login_id_entry: gtk_builder
+                .get_object("u_login_id_entry")
+                .expect("Couldn't find login id entry in ui file."),
+            login_password_entry: gtk_builder
+                .get_object("u_login_password_entry")
+                .expect("Couldn't find login password entry in ui file."),
+            login_button: gtk_builder
+                .get_object("u_login_button")
+                .expect("Couldn't find login button in ui file."),
+            sso_button: gtk_builder
+                .get_object("u_login_sso_button")
+                .expect("Couldn't find single sign-on button in ui file."),
+            hs_server_entry,
+            default_homeserver,
+            ident_server_entry: gtk_builder
+                .get_object("u_ident_server_entry")
+                .expect("Couldn't find identity server entry in ui file."),
+            register_name_entry: gtk_builder
+                .get_object("u_register_name_entry")
+                .expect("Couldn't find register user name entry in ui file."),
+            register_email_entry: gtk_builder
+                .get_object("u_register_email_entry")
+                .expect("Couldn't find register email entry in ui file."),
+            regpass_entry: gtk_builder
+                .get_object("u_regpass_entry")
+                .expect("Couldn't find new password entry in ui file."),
+            regpass_confirm_entry: gtk_builder
+                .get_object("u_regpass_confirm_entry")
+                .expect("Couldn't find new password confirmation entry in ui file."),
+            regpass_button: gtk_builder
+                .get_object("u_regpass_button")
+                .expect("Couldn't find new password register button in ui file."),
+            main_window_stack: gtk_builder
+                .get_object("main_window_stack")
+                .expect("Couldn't find main window stack in ui file."),
+            greeter_buttons: gtk_builder
+                .get_object("gv_button_box")
+                .expect("Couldn't find greeter view button box in ui file."),
+            greeter_spinner: gtk_builder
+                .get_object("gv_spinner")
+                .expect("Couldn't find greeter view spinner in ui file."),
+            greeter_error_label: gtk_builder
+                .get_object("gv_error_label")
+                .expect("Couldn't find greeter view error label in ui file."),
+            window: gtk_builder
+                .get_object("main_window")
+                .expect("Couldn't find main_window in ui file."),
+        };
+
+        // The warning icons are only shown for invalid input
+        set_warning(&account_menu.login_id_entry, None);
+        set_warning(&account_menu.register_name_entry, None);
+        set_warning(&account_menu.register_email_entry, None);
+        set_warning(&account_menu.regpass_confirm_entry, None);
+
+        account_menu
+    }
+
+    /// Open the login form next to `relative_to`.
+    pub fn open_login<W: IsA<gtk::Widget>>(&self, relative_to: &W) {
+        self.open_form(relative_to, "login");
+    }
+
+    /// Open the registration form next to `relative_to`.
+    pub fn open_registration<W: IsA<gtk::Widget>>(&self, relative_to: &W) {
+        self.open_form(relative_to, "register");
+    }
+
+    fn open_form<W: IsA<gtk::Widget>>(&self, relative_to: &W, page: &str) {
+        self.menu.set_relative_to(Some(relative_to));
+        self.stack.set_visible_child_name(page);
+        self.menu.open_submenu("account_new");
+        self.menu.show();
+    }
+
+    /// Attach the menu to the header bar again after it was opened from
+    /// elsewhere.
+    pub fn reset_position(&self) {
+        self.menu.set_relative_to(Some(&self.menu_button));
+    }
+
+    /// Create the command for logging in with what was entered in the menu,
+    /// if everything needed was entered.
+    pub fn login_command(&self) -> Option<MatrixCommand> {
+        let username = entry_text(&self.login_id_entry);
+        let password = self.login_password_entry.get_text().unwrap_or_default();
+
+        set_warning(
+            &self.login_id_entry,
+            if username.is_empty() {
+                Some("A user name is required.")
+            } else {
+                None
+            },
+        );
+        if username.is_empty() || password.is_empty() {
+            return None;
+        }
+
+        // A full Matrix ID says where the account is, unless a homeserver was
+        // entered explicitly
+        let homeserver = if username.starts_with('@') && !self.hs_server_entry_changed() {
+            HomeserverAddress::parse(&username)
+        } else {
+            None
+        };
+        let homeserver = match homeserver {
+            Some(homeserver) => homeserver,
+            None => self.homeserver()?,
+        };
+        self.menu.hide();
+        self.connecting();
+
+        Some(MatrixCommand::Connect {
+            homeserver,
+            connection_method: ConnectionMethod::Login { username, password },
+        })
+    }
+
+    /// Create the command for logging in via single sign-on on the homeserver
+    /// entered in the menu.
+    pub fn sso_command(&self) -> Option<MatrixCommand> {
+        let homeserver = self.homeserver()?;
+        self.menu.hide();
+        self.connecting();
+
+        Some(MatrixCommand::Connect {
+            homeserver,
+            connection_method: ConnectionMethod::SingleSignOn,
+        })
+    }
+
+    /// Open the homeserver's login page in the browser. Logging in continues
+    /// once the browser is redirected back to us.
+    pub fn sso_started(&self, url: &str) {
+        if let Err(e) = gtk::show_uri_on_window(Some(&self.window), url, 0) {
+            error!("Failed to open {} in the browser: {}", url, e);
+
+            let dialog = gtk::MessageDialog::new(
+                Some(&self.window),
+                gtk::DialogFlags::MODAL | gtk::DialogFlags::DESTROY_WITH_PARENT,
+                gtk::MessageType::Info,
+                gtk::ButtonsType::Close,
+                "Log in in your browser",
+            );
+            dialog.set_property_secondary_use_markup(true);
+            dialog.set_property_secondary_text(Some(
+                format!(
+                    "Open <a href=\"{0}\">{0}</a> in your browser to log in.",
+                    glib::markup_escape_text(url)
+                )
+                .as_str(),
+            ));
+            dialog.connect_response(|dialog, _| dialog.destroy());
+            dialog.show();
+        }
+    }
+
+    /// Create the command for creating a guest account on the homeserver
+    /// entered in the menu.
+    pub fn guest_command(&self) -> Option<MatrixCommand> {
+        let homeserver = self.homeserver()?;
+        self.connecting();
+
+        Some(MatrixCommand::Connect {
+            homeserver,
+            connection_method: ConnectionMethod::Guest,
+        })
+    }
+
+    /// Show that an account is being connected until that succeeds or fails.
+    fn connecting(&self) {
+        self.greeter_error_label.hide();
+        self.greeter_spinner.show();
+        self.greeter_spinner.start();
+        self.greeter_buttons.set_sensitive(false);
+        self.login_button.set_sensitive(false);
+        self.sso_button.set_sensitive(false);
+    }
+
+    fn done_connecting(&self) {
+        self.greeter_spinner.stop();
+        self.greeter_spinner.hide();
+        self.greeter_buttons.set_sensitive(true);
+        self.login_button.set_sensitive(true);
+        self.sso_button.set_sensitive(true);
+    }
+
+    pub fn login_succeeded(&self) {
+        self.done_connecting();
+        self.login_password_entry.set_text("");
+    }
+
+    pub fn login_failed(&self, message: &str) {
+        self.done_connecting();
+
+        if self.is_greeter_shown() {
+            self.greeter_error_label.set_text(message);
+            self.greeter_error_label.show();
+        } else {
+            self.show_error(message);
+        }
+    }
+
+    /// Show why connecting an account failed, unless the user cancelled.
+    pub fn connect_failed(&self, reason: &ConnectError) {
+        // Registering can be retried with the same details
+        self.regpass_button.set_sensitive(true);
+
+        match *reason {
+            ConnectError::Cancelled => self.done_connecting(),
+            ref reason => self.login_failed(&reason.to_string()),
+        }
+    }
+
+    pub fn is_greeter_shown(&self) -> bool {
+        self.main_window_stack.get_visible_child_name() == Some("greeter_view".to_owned())
+    }
+
+    /// Check the user name and email address of the registration form,
+    /// returning whether the password can be asked for.
+    pub fn check_registration_details(&self) -> bool {
+        let username = entry_text(&self.register_name_entry);
+        let username_ok = !username.is_empty() && !username.contains(|c| c == ':' || c == '@');
+        set_warning(
+            &self.register_name_entry,
+            if username.is_empty() {
+                Some("A user name is required.")
+            } else if !username_ok {
+                Some("Enter just the user name, without @ or server name.")
+            } else {
+                None
+            },
+        );
+
+        // The email address is optional, but some homeservers require it
+        let email = entry_text(&self.register_email_entry);
+        let email_ok = email.is_empty() || email.contains('@');
+        set_warning(
+            &self.register_email_entry,
+            if email_ok {
+                None
+            } else {
+                Some("This is not a valid email address.")
+            },
+        );
+
+        username_ok && email_ok
+    }
+
+    /// React to a change of either password entry, returning whether the
+    /// passwords match.
+    pub fn check_passwords(&self) -> bool {
+        let password = self.regpass_entry.get_text().unwrap_or_default();
+        let confirmation = self.regpass_confirm_entry.get_text().unwrap_or_default();
+
+        let is_match = password == confirmation;
+        set_warning(
+            &self.regpass_confirm_entry,
+            if is_match || confirmation.is_empty() {
+                None
+            } else {
+                Some("The passwords don't match.")
+            },
+        );
+
+        let is_valid = is_match && !password.is_empty();
+        self.regpass_button.set_sensitive(is_valid);
+        is_valid
+    }
+
+    /// Create the command for registering an account with what was entered
+    /// in the menu, if everything needed was entered.
+    ///
+    /// If `guest` is set, that guest account is upgraded to a full account
+    /// instead, keeping its rooms.
+    pub fn register_command(&self, guest: Option<InternalUserId>) -> Option<MatrixCommand> {
+        if !self.check_registration_details() || !self.check_passwords() {
+            return None;
+        }
+
+        let homeserver = self.homeserver()?;
+
+        let email = Some(entry_text(&self.register_email_entry)).filter(|e| !e.is_empty());
+        // The identity server is only needed to validate the email address
+        let id_server = Url::parse(&entry_text(&self.ident_server_entry))
+            .ok()
+            .and_then(|url| url.host_str().map(ToOwned::to_owned));
+        if email.is_some() && id_server.is_none() {
+            self.show_error("The identity server URL is not valid.");
+            return None;
+        }
+
+        self.regpass_button.set_sensitive(false);
+        self.menu.hide();
+
+        let username = entry_text(&self.register_name_entry);
+        let password = self.regpass_entry.get_text().unwrap_or_default();
+
+        if let Some(user_id) = guest {
+            return Some(MatrixCommand::UserSpecificCommand {
+                user_id,
+                command: UserSpecificCommand::UpgradeGuest {
+                    username,
+                    password,
+                    email,
+                    id_server,
+                },
+            });
+        }
+
+        Some(MatrixCommand::Connect {
+            homeserver,
+            connection_method: ConnectionMethod::Register {
+                username,
+                password,
+                email,
+                id_server,
+            },
+        })
+    }
+
+    /// Ask the user to complete a stage of the registration, calling
+    /// `answer` with their response.
+    pub fn show_registration_stage<F>(
+        &self,
+        stage: RegistrationStage,
+        error: Option<String>,
+        answer: F,
+    ) where
+        F: Fn(RegistrationResponse) + 'static,
+    {
+        let (text, details) = match stage {
+            RegistrationStage::AcceptTerms { policies } => {
+                let links: Vec<_> = policies
+                    .iter()
+                    .map(|policy| {
+                        format!(
+                            "<a href=\"{}\">{}</a>",
+                            glib::markup_escape_text(&policy.url),
+                            glib::markup_escape_text(&policy.name)
+                        )
+                    })
+                    .collect();
+
+                (
+                    "Accept the homeserver's policies",
+                    format!(
+                        "To register, you have to accept the following policies:\n{}",
+                        links.join("\n")
+                    ),
+                )
+            }
+            RegistrationStage::ValidateEmail { email } => (
+                "Validate your email address",
+                format!(
+                    "An email was sent to {}. Open the link in it, then continue.",
+                    glib::markup_escape_text(&email)
+                ),
+            ),
+        };
+
+        let details = match error {
+            Some(error) => format!("{}\n\n{}", glib::markup_escape_text(&error), details),
+            None => details,
+        };
+
+        let dialog = gtk::MessageDialog::new(
+            Some(&self.window),
+            gtk::DialogFlags::MODAL | gtk::DialogFlags::DESTROY_WITH_PARENT,
+            gtk::MessageType::Question,
+            gtk::ButtonsType::None,
+            text,
+        );
+        dialog.set_property_secondary_use_markup(true);
+        dialog.set_property_secondary_text(Some(details.as_str()));
+        dialog.add_button("Cancel", gtk::ResponseType::Cancel.into());
+        dialog.add_button("Continue", gtk::ResponseType::Accept.into());
+        dialog.set_default_response(gtk::ResponseType::Accept.into());
+
+        let regpass_button = self.regpass_button.clone();
+        dialog.connect_response(move |dialog, response| {
+            if gtk::ResponseType::from(response) == gtk::ResponseType::Accept {
+                answer(RegistrationResponse::Continue);
+            } else {
+                answer(RegistrationResponse::Cancel);
+                regpass_button.set_sensitive(true);
+            }
+
+            dialog.destroy();
+        });
+
+        dialog.show();
+    }
+
+    /// Ask the user whether to log out of the account, calling `log_out`
+    /// with whether to log out all devices if they confirm.
+    pub fn confirm_logout<F>(&self, is_guest: bool, log_out: F)
+    where
+        F: Fn(bool) + 'static,
+    {
+        let details = if is_guest {
+            "You won't be able to use this guest account again. Register it first to keep it."
+        } else {
+            "You can log in again later with your user name and password."
+        };
+
+        let dialog = gtk::MessageDialog::new(
+            Some(&self.window),
+            gtk::DialogFlags::MODAL | gtk::DialogFlags::DESTROY_WITH_PARENT,
+            gtk::MessageType::Question,
+            gtk::ButtonsType::None,
+            "Log out of this account?",
+        );
+        dialog.set_property_secondary_text(Some(details));
+
+        let all_devices_check = gtk::CheckButton::new_with_label("Log out on all devices");
+        all_devices_check.set_visible(!is_guest);
+        if let Some(Ok(message_area)) = dialog
+            .get_message_area()
+            .map(|area| area.downcast::<gtk::Box>())
+        {
+            message_area.pack_start(&all_devices_check, false, false, 0);
+        }
+
+        dialog.add_button("Cancel", gtk::ResponseType::Cancel.into());
+        dialog.add_button("Log Out", gtk::ResponseType::Accept.into());
+        if let Some(style_context) = dialog
+            .get_widget_for_response(gtk::ResponseType::Accept.into())
+            .and_then(|button| button.get_style_context())
+        {
+            style_context.add_class("destructive-action");
+        }
+
+        dialog.connect_response(move |dialog, response| {
+            if gtk::ResponseType::from(response) == gtk::ResponseType::Accept {
+                log_out(all_devices_check.get_active());
+            }
+
+            dialog.destroy();
+        });
+
+        dialog.show();
+    }
+
+    pub fn registration_failed(&self, message: &str) {
+        self.regpass_button.set_sensitive(true);
+        self.show_error(&format!("Registration failed: {}", message));
+    }
+
+    /// Clear the registration form after an account was registered.
+    pub fn registration_completed(&self) {
+        for entry in &[
+            &self.register_name_entry,
+            &self.register_email_entry,
+            &self.regpass_entry,
+            &self.regpass_confirm_entry,
+        ] {
+            entry.set_text("");
+        }
+        self.regpass_button.set_sensitive(true);
+    }
+
+    /// The homeserver entered in the menu, either as URL or server name.
+    fn homeserver(&self) -> Option<HomeserverAddress> {
+        let homeserver = HomeserverAddress::parse(&entry_text(&self.hs_server_entry));
+        if homeserver.is_none() {
+            self.show_error("Enter a homeserver URL or a server name like example.org.");
+        }
+
+        homeserver
+    }
+
+    fn hs_server_entry_changed(&self) -> bool {
+        entry_text(&self.hs_server_entry) != self.default_homeserver
+    }
+
+    fn show_error(&self, message: &str) {
+        let dialog = gtk::MessageDialog::new(
+            Some(&self.window),
+            gtk::DialogFlags::MODAL | gtk::DialogFlags::DESTROY_WITH_PARENT,
+            gtk::MessageType::Error,
+            gtk::ButtonsType::Close,
+            message,
+        );
+        dialog.connect_response(|dialog, _| dialog.destroy());
+        dialog.show();
+    }
+}
+
+/// Show or hide the warning icon of an entry, with `message` as its tooltip.
+fn set_warning(entry: &gtk::Entry, message: Option<&str>) {
+    entry.set_icon_from_icon_name(
+        gtk::EntryIconPosition::Secondary,
+        message.map(|_| "dialog-warning"),
+    );
+    entry.set_icon_tooltip_text(gtk::EntryIconPosition::Secondary, message);
+}
+
+fn entry_text(entry: &gtk::Entry) -> String {
+    entry
+        .get_text()
+        .map(|text| text.trim().to_owned())
+        .unwrap_or_default()
+}
@@ -0,0 +1,148 @@
+use std::cell::RefCell;
+
+use gtk::{self, prelude::*};
+use ruma_identifiers::UserId;
+
+use crate::bg_thread::{InternalUserId, MatrixCommand, UserSpecificCommand};
+
+struct AccountEntry {
+    user_id: InternalUserId,
+    row: gtk::ListBoxRow,
+    label: gtk::Label,
+    is_guest: bool,
+}
+
+/// The list of connected accounts on the "main" page of user_menu.
+///
+/// The selected account is the one whose rooms are shown, and which
+/// user-specific commands are sent for. All of its methods take `&self`,
+/// because changing the selection emits signals whose handlers access the
+/// switcher again.
+pub(super) struct AccountSwitcher {
+    list_box: gtk::ListBox,
+    accounts: RefCell<Vec<AccountEntry>>,
+}
+
+impl AccountSwitcher {
+    pub fn new(gtk_builder: &gtk::Builder) -> Self {
+        AccountSwitcher {
+            list_box: gtk_builder
+                .get_object("u_account_list")
+                .expect("Couldn't find account list in ui file."),
+            accounts: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Call `f` whenever a different account is selected.
+    pub fn connect_changed<F: Fn() + 'static>(&self, f: F) {
+        self.list_box.connect_row_selected(move |_, _| f());
+    }
+
+    /// Add an account that just connected and switch to it.
+    pub fn add_account(&self, user_id: InternalUserId, matrix_user_id: &UserId, is_guest: bool) {
+        if self.update_account(user_id, matrix_user_id, is_guest) {
+            return;
+        }
+
+        let label = gtk::Label::new(Some(account_label(matrix_user_id, is_guest).as_str()));
+        label.set_halign(gtk::Align::Start);
+        label.set_margin_top(6);
+        label.set_margin_bottom(6);
+        label.set_margin_start(6);
+        label.set_margin_end(6);
+
+        let row = gtk::ListBoxRow::new();
+        row.add(&label);
+        row.show_all();
+        self.list_box.insert(&row, -1);
+
+        self.accounts.borrow_mut().push(AccountEntry {
+            user_id,
+            row: row.clone(),
+            label,
+            is_guest,
+        });
+        self.list_box.select_row(Some(&row));
+    }
+
+    /// Update the user ID and guest status of an account, e.g. after a guest
+    /// account was registered. Returns whether the account is known.
+    pub fn update_account(
+        &self,
+        user_id: InternalUserId,
+        matrix_user_id: &UserId,
+        is_guest: bool,
+    ) -> bool {
+        let mut accounts = self.accounts.borrow_mut();
+        match accounts.iter_mut().find(|entry| entry.user_id == user_id) {
+            Some(entry) => {
+                entry
+                    .label
+                    .set_text(&account_label(matrix_user_id, is_guest));
+                entry.is_guest = is_guest;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Remove an account that was logged out of, switching to another one if
+    /// it was selected.
+    pub fn remove_account(&self, user_id: InternalUserId) {
+        let (row, next_row) = {
+            let mut accounts = self.accounts.borrow_mut();
+            let row = match accounts.iter().position(|entry| entry.user_id == user_id) {
+                Some(index) => accounts.remove(index).row,
+                None => return,
+            };
+
+            (row, accounts.first().map(|entry| entry.row.clone()))
+        };
+
+        let was_selected = row.is_selected();
+        self.list_box.remove(&row);
+        if was_selected {
+            self.list_box.select_row(next_row.as_ref());
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.accounts.borrow().is_empty()
+    }
+
+    /// The selected account, if there is any.
+    pub fn current(&self) -> Option<InternalUserId> {
+        let row = self.list_box.get_selected_row()?;
+        self.accounts
+            .borrow()
+            .iter()
+            .find(|entry| entry.row == row)
+            .map(|entry| entry.user_id)
+    }
+
+    /// The selected account, if it is a guest account.
+    pub fn current_guest(&self) -> Option<InternalUserId> {
+        let user_id = self.current()?;
+        self.accounts
+            .borrow()
+            .iter()
+            .find(|entry| entry.user_id == user_id && entry.is_guest)
+            .map(|entry| entry.user_id)
+    }
+
+    /// Address `command` to the selected account.
+    pub fn command(&self, command: UserSpecificCommand) -> Option<MatrixCommand> {
+        Some(MatrixCommand::UserSpecificCommand {
+            user_id: self.current()?,
+            command,
+        })
+    }
+}
+
+fn account_label(matrix_user_id: &UserId, is_guest: bool) -> String {
+    if is_guest {
+        format!("{} (guest)", matrix_user_id)
+    } else {
+        matrix_user_id.to_string()
+    }
+}
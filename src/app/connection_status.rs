@@ -0,0 +1,83 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use gtk::{self, prelude::*};
+
+use crate::bg_thread::{ConnectionState, InternalUserId};
+
+/// The connection state of the selected account, shown in the header bar
+/// unless the account is connected.
+pub(super) struct ConnectionStatus {
+    label: gtk::Label,
+    /// The latest state of each account, and when it was reported.
+    states: HashMap<InternalUserId, (ConnectionState, Instant)>,
+    shown_account: Option<InternalUserId>,
+}
+
+impl ConnectionStatus {
+    pub fn new(gtk_builder: &gtk::Builder) -> Self {
+        ConnectionStatus {
+            label: gtk_builder
+                .get_object("header_connection_label")
+                .expect("Couldn't find header connection label in ui file."),
+            states: HashMap::new(),
+            shown_account: None,
+        }
+    }
+
+    pub fn set_state(&mut self, user_id: InternalUserId, state: ConnectionState) {
+        self.states.insert(user_id, (state, Instant::now()));
+        if self.shown_account == Some(user_id) {
+            self.update();
+        }
+    }
+
+    /// Show the state of `user_id`, or nothing if no account is selected.
+    pub fn show_account(&mut self, user_id: Option<InternalUserId>) {
+        self.shown_account = user_id;
+        self.update();
+    }
+
+    /// Forget the state of an account that was logged out of.
+    pub fn remove_account(&mut self, user_id: InternalUserId) {
+        self.states.remove(&user_id);
+        self.update();
+    }
+
+    /// Update the label, which counts down to the next attempt at syncing.
+    pub fn update(&self) {
+        let state = self
+            .shown_account
+            .and_then(|user_id| self.states.get(&user_id));
+
+        let text = match state {
+            Some(&(ConnectionState::Reconnecting { delay }, since)) => {
+                format!("Reconnecting{}", countdown(delay, since))
+            }
+            Some(&(ConnectionState::Offline { delay }, since)) => {
+                format!("Offline, retrying{}", countdown(delay, since))
+            }
+            Some(&(ConnectionState::LoggedOut, _)) => "Logged out".to_owned(),
+            Some(&(ConnectionState::Connected, _)) | None => {
+                self.label.hide();
+                return;
+            }
+        };
+
+        self.label.set_text(&text);
+        self.label.show();
+    }
+}
+
+fn countdown(delay: Duration, since: Instant) -> String {
+    match delay.checked_sub(since.elapsed()) {
+        Some(remaining) if remaining > Duration::from_secs(0) => {
+            // Round up, so it never says "in 0 s"
+            let seconds = remaining.as_secs() + u64::from(remaining.subsec_nanos() > 0);
+            format!(" in {} s", seconds)
+        }
+        _ => "…".to_owned(),
+    }
+}
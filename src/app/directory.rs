@@ -0,0 +1,191 @@
+use glib::{self, ToVariant};
+use gtk::{self, prelude::*};
+
+use crate::bg_thread::{PublicRoom, UserSpecificCommand};
+
+/// The list of public rooms in directory_view.
+pub(super) struct DirectoryView {
+    search_entry: gtk::SearchEntry,
+    server_entry: gtk::Entry,
+    list_box: gtk::ListBox,
+    status_label: gtk::Label,
+    load_more_button: gtk::Button,
+    /// Pagination token for the next page of the current search.
+    next_batch: Option<String>,
+    /// Counts the requests sent, so only the response to the latest one is
+    /// shown. Responses can arrive out of order while the user is typing.
+    generation: u32,
+    /// The server whose directory the current results come from, `None` for
+    /// the user's own homeserver.
+    server: Option<String>,
+}
+
+impl DirectoryView {
+    pub fn new(gtk_builder: &gtk::Builder) -> Self {
+        DirectoryView {
+            search_entry: gtk_builder
+                .get_object("dir_search_entry")
+                .expect("Couldn't find directory search entry in ui file."),
+            server_entry: gtk_builder
+                .get_object("dir_server_entry")
+                .expect("Couldn't find directory server entry in ui file."),
+            list_box: gtk_builder
+                .get_object("dir_room_list")
+                .expect("Couldn't find directory room list in ui file."),
+            status_label: gtk_builder
+                .get_object("dir_status_label")
+                .expect("Couldn't find directory status label in ui file."),
+            load_more_button: gtk_builder
+                .get_object("dir_load_more_button")
+                .expect("Couldn't find directory load more button in ui file."),
+            next_batch: None,
+            generation: 0,
+            server: None,
+        }
+    }
+
+    /// Create the command for fetching the first page of results for what is
+    /// currently entered in the search and server entries, or the next page
+    /// of the current results if `next_page` is true.
+    pub fn fetch_command(&mut self, next_page: bool) -> Option<UserSpecificCommand> {
+        let since = if next_page {
+            Some(self.next_batch.take()?)
+        } else {
+            for row in self.list_box.get_children() {
+                self.list_box.remove(&row);
+            }
+            self.next_batch = None;
+            self.server = non_empty_text(&self.server_entry);
+            None
+        };
+
+        self.status_label.set_text("Loading…");
+        self.load_more_button.hide();
+        self.generation = self.generation.wrapping_add(1);
+
+        Some(UserSpecificCommand::FetchDirectory {
+            server: self.server.clone(),
+            search_term: non_empty_text(&self.search_entry),
+            since,
+            generation: self.generation,
+        })
+    }
+
+    pub fn show_results(
+        &mut self,
+        generation: u32,
+        rooms: Vec<PublicRoom>,
+        next_batch: Option<String>,
+        is_next_page: bool,
+    ) {
+        if generation != self.generation {
+            return;
+        }
+
+        if !is_next_page {
+            for row in self.list_box.get_children() {
+                self.list_box.remove(&row);
+            }
+        }
+
+        for room in &rooms {
+            self.list_box
+                .insert(&room_row(room, self.server.as_ref().map(String::as_str)), -1);
+        }
+
+        if self.list_box.get_children().is_empty() {
+            self.status_label.set_text("No rooms found.");
+        } else {
+            self.status_label.set_text("");
+        }
+
+        self.load_more_button.set_visible(next_batch.is_some());
+        self.next_batch = next_batch;
+    }
+
+    pub fn show_error(&mut self, generation: u32, message: &str) {
+        if generation != self.generation {
+            return;
+        }
+
+        self.status_label
+            .set_text(&format!("Failed to load the room directory: {}", message));
+        self.load_more_button.hide();
+    }
+}
+
+fn non_empty_text<E: EntryExt>(entry: &E) -> Option<String> {
+    entry
+        .get_text()
+        .map(|text| text.trim().to_owned())
+        .filter(|text| !text.is_empty())
+}
+
+fn room_row(room: &PublicRoom, server: Option<&str>) -> gtk::ListBoxRow {
+    let row = gtk::ListBoxRow::new();
+    let hbox = gtk::Box::new(gtk::Orientation::Horizontal, 12);
+    let vbox = gtk::Box::new(gtk::Orientation::Vertical, 3);
+
+    let alias = room
+        .canonical_alias
+        .as_ref()
+        .or_else(|| room.aliases.first())
+        .map(ToString::to_string);
+    let name = room
+        .name
+        .clone()
+        .or_else(|| alias.clone())
+        .unwrap_or_else(|| room.room_id.to_string());
+
+    let name_label = gtk::Label::new(None);
+    name_label.set_markup(&format!("<b>{}</b>", glib::markup_escape_text(&name)));
+    name_label.set_xalign(0.0);
+    vbox.pack_start(&name_label, false, false, 0);
+
+    if let Some(ref alias) = alias {
+        let alias_label = gtk::Label::new(Some(alias.as_str()));
+        alias_label.set_xalign(0.0);
+        alias_label.set_selectable(true);
+        if let Some(style_context) = alias_label.get_style_context() {
+            style_context.add_class("dim-label");
+        }
+        vbox.pack_start(&alias_label, false, false, 0);
+    }
+
+    if let Some(ref topic) = room.topic {
+        let topic_label = gtk::Label::new(Some(topic.as_str()));
+        topic_label.set_xalign(0.0);
+        topic_label.set_line_wrap(true);
+        vbox.pack_start(&topic_label, false, false, 0);
+    }
+
+    hbox.pack_start(&vbox, true, true, 0);
+
+    let members_label = gtk::Label::new(Some(
+        format!("{} members", room.num_joined_members).as_str(),
+    ));
+    if let Some(style_context) = members_label.get_style_context() {
+        style_context.add_class("dim-label");
+    }
+    hbox.pack_start(&members_label, false, false, 0);
+
+    // Joining by alias lets the homeserver find a server to join through,
+    // which is not possible with just the room ID. The server whose
+    // directory lists the room is in it as well, so it is used as a hint.
+    let mut join_target = alias.unwrap_or_else(|| room.room_id.to_string());
+    if let Some(server) = server {
+        join_target.push_str("?via=");
+        join_target.push_str(server);
+    }
+    let join_button = gtk::Button::new_with_label("Join");
+    join_button.set_valign(gtk::Align::Center);
+    join_button.set_action_name(Some("win.join_room"));
+    join_button.set_action_target_value(Some(&join_target.to_variant()));
+    hbox.pack_start(&join_button, false, false, 0);
+
+    hbox.set_border_width(6);
+    row.add(&hbox);
+    row.set_activatable(false);
+    row.show_all();
+    row
+}
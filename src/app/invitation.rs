@@ -0,0 +1,200 @@
+use std::collections::HashMap;
+
+use gdk_pixbuf::{Pixbuf, PixbufLoader, PixbufLoaderExt};
+use gtk::{self, prelude::*};
+use ruma_identifiers::{RoomId, UserId};
+
+use crate::bg_thread::{InternalUserId, UserSpecificCommand};
+
+/// Size of the room avatar shown for an invitation, in pixels.
+const AVATAR_SIZE: u32 = 96;
+
+/// An invitation to a room we haven't joined yet.
+pub(super) struct Invitation {
+    pub display_name: String,
+    pub inviter: Option<UserId>,
+    pub inviter_name: Option<String>,
+    pub avatar_url: Option<String>,
+}
+
+/// The "invite" page of room_view_stack, shown instead of the chat when an
+/// invitation is selected in the room list.
+pub(super) struct InvitationView {
+    stack: gtk::Stack,
+    avatar_image: gtk::Image,
+    room_name_label: gtk::Label,
+    inviter_label: gtk::Label,
+    ignore_check: gtk::CheckButton,
+    /// Invitations by the account that was invited and the room.
+    invitations: HashMap<(InternalUserId, RoomId), Invitation>,
+    /// Room avatars by `mxc://` URL.
+    avatars: HashMap<String, Pixbuf>,
+    current_room: Option<(InternalUserId, RoomId)>,
+}
+
+impl InvitationView {
+    pub fn new(gtk_builder: &gtk::Builder) -> Self {
+        InvitationView {
+            stack: gtk_builder
+                .get_object("room_view_stack")
+                .expect("Couldn't find room view stack in ui file."),
+            avatar_image: gtk_builder
+                .get_object("iv_avatar_image")
+                .expect("Couldn't find invitation avatar image in ui file."),
+            room_name_label: gtk_builder
+                .get_object("iv_room_name_label")
+                .expect("Couldn't find invitation room name label in ui file."),
+            inviter_label: gtk_builder
+                .get_object("iv_inviter_label")
+                .expect("Couldn't find invitation inviter label in ui file."),
+            ignore_check: gtk_builder
+                .get_object("iv_ignore_check")
+                .expect("Couldn't find invitation ignore check button in ui file."),
+            invitations: HashMap::new(),
+            avatars: HashMap::new(),
+            current_room: None,
+        }
+    }
+
+    pub fn add_invitation(
+        &mut self,
+        account: InternalUserId,
+        room_id: RoomId,
+        invitation: Invitation,
+    ) {
+        let key = (account, room_id);
+        let is_current = self.current_room.as_ref() == Some(&key);
+        self.invitations.insert(key, invitation);
+
+        if is_current {
+            self.update();
+        }
+    }
+
+    pub fn rename_invitation(
+        &mut self,
+        account: InternalUserId,
+        room_id: &RoomId,
+        display_name: &str,
+    ) {
+        let key = (account, room_id.clone());
+        if let Some(invitation) = self.invitations.get_mut(&key) {
+            invitation.display_name = display_name.to_owned();
+        }
+
+        if self.current_room.as_ref() == Some(&key) {
+            self.update();
+        }
+    }
+
+    /// Forget about an invitation after it was accepted or declined.
+    pub fn remove_invitation(&mut self, account: InternalUserId, room_id: &RoomId) {
+        let key = (account, room_id.clone());
+        if self.invitations.remove(&key).is_some() && self.current_room.as_ref() == Some(&key) {
+            self.stack.set_visible_child_name("chat");
+        }
+    }
+
+    /// Show the invitation of `account` to `room_id` if there is one, or the
+    /// chat otherwise.
+    ///
+    /// Returns the command to fetch the room's avatar, if it isn't known yet.
+    pub fn show_room(
+        &mut self,
+        account: InternalUserId,
+        room_id: RoomId,
+    ) -> Option<UserSpecificCommand> {
+        let key = (account, room_id);
+        if self.current_room.as_ref() != Some(&key) {
+            self.ignore_check.set_active(false);
+        }
+
+        self.current_room = Some(key);
+        self.update()
+    }
+
+    /// The account and room of the invitation that is shown, if any.
+    pub fn current_invitation(&self) -> Option<(InternalUserId, RoomId)> {
+        self.current_room
+            .as_ref()
+            .filter(|key| self.invitations.contains_key(key))
+            .cloned()
+    }
+
+    /// The user to ignore when declining the current invitation, if the user
+    /// asked for that.
+    pub fn user_to_ignore(&self) -> Option<UserId> {
+        if !self.ignore_check.get_active() {
+            return None;
+        }
+
+        self.invitations
+            .get(self.current_room.as_ref()?)?
+            .inviter
+            .clone()
+    }
+
+    pub fn set_avatar(&mut self, mxc_url: String, data: &[u8]) {
+        let loader = PixbufLoader::new();
+        let pixbuf = loader
+            .write(data)
+            .and_then(|()| loader.close())
+            .map_err(|e| {
+                error!("Failed to load room avatar {}: {}", mxc_url, e);
+            })
+            .ok()
+            .and_then(|()| loader.get_pixbuf());
+
+        if let Some(pixbuf) = pixbuf {
+            self.avatars.insert(mxc_url, pixbuf);
+            self.update();
+        }
+    }
+
+    fn update(&self) -> Option<UserSpecificCommand> {
+        let invitation = match self
+            .current_room
+            .as_ref()
+            .and_then(|key| self.invitations.get(key))
+        {
+            Some(invitation) => invitation,
+            None => {
+                self.stack.set_visible_child_name("chat");
+                return None;
+            }
+        };
+
+        self.room_name_label.set_text(&invitation.display_name);
+
+        let inviter_text = match (&invitation.inviter, &invitation.inviter_name) {
+            (Some(inviter), Some(name)) => format!("{} ({}) invited you", name, inviter),
+            (Some(inviter), None) => format!("{} invited you", inviter),
+            _ => "You were invited".to_owned(),
+        };
+        self.inviter_label.set_text(&inviter_text);
+
+        self.ignore_check.set_visible(invitation.inviter.is_some());
+
+        let mut fetch_command = None;
+        match invitation.avatar_url {
+            Some(ref url) => match self.avatars.get(url) {
+                Some(pixbuf) => self.avatar_image.set_from_pixbuf(Some(pixbuf)),
+                None => {
+                    self.avatar_image
+                        .set_from_icon_name("face-smile", gtk::IconSize::Dialog.into());
+                    fetch_command = Some(UserSpecificCommand::FetchThumbnail {
+                        mxc_url: url.clone(),
+                        width: AVATAR_SIZE,
+                        height: AVATAR_SIZE,
+                    });
+                }
+            },
+            None => self
+                .avatar_image
+                .set_from_icon_name("face-smile", gtk::IconSize::Dialog.into()),
+        }
+
+        self.stack.set_visible_child_name("invite");
+        fetch_command
+    }
+}
@@ -0,0 +1,145 @@
+use std::convert::TryFrom;
+
+use gtk::{self, prelude::*};
+use ruma_identifiers::{RoomId, UserId};
+
+use crate::bg_thread::{DirectoryUser, UserSpecificCommand};
+
+// Columns of the completion model
+const USER_ID_COLUMN: u32 = 0;
+const DISPLAY_NAME_COLUMN: u32 = 1;
+
+/// Search terms shorter than this don't trigger a user directory search.
+const MIN_SEARCH_TERM_LEN: usize = 2;
+
+/// The "invite" page of room_details_popover.
+///
+/// All of its methods take `&self`, because changing the entry's text from
+/// inside them emits signals whose handlers access the popover again.
+pub(super) struct InvitePopover {
+    entry: gtk::Entry,
+    invite_button: gtk::Button,
+    error_label: gtk::Label,
+    completion_store: gtk::ListStore,
+}
+
+impl InvitePopover {
+    pub fn new(gtk_builder: &gtk::Builder) -> Self {
+        let entry: gtk::Entry = gtk_builder
+            .get_object("rdi_invite_entry")
+            .expect("Couldn't find room invite entry in ui file.");
+        let invite_button: gtk::Button = gtk_builder
+            .get_object("rdi_invite_button")
+            .expect("Couldn't find room invite button in ui file.");
+        let error_label: gtk::Label = gtk_builder
+            .get_object("rdi_error_label")
+            .expect("Couldn't find room invite error label in ui file.");
+
+        let completion_store = gtk::ListStore::new(&[gtk::Type::String, gtk::Type::String]);
+        let completion = gtk::EntryCompletion::new();
+        completion.set_model(Some(&completion_store));
+        completion.set_text_column(USER_ID_COLUMN as i32);
+        // The user directory already did the matching, which also takes
+        // display names into account.
+        completion.set_match_func(|_, _, _| true);
+
+        let name_renderer = gtk::CellRendererText::new();
+        completion.pack_start(&name_renderer, false);
+        completion.add_attribute(&name_renderer, "text", DISPLAY_NAME_COLUMN as i32);
+
+        entry.set_completion(Some(&completion));
+        invite_button.set_sensitive(false);
+
+        InvitePopover {
+            entry,
+            invite_button,
+            error_label,
+            completion_store,
+        }
+    }
+
+    /// React to a change of the entry's text.
+    ///
+    /// Returns the command for searching the user directory for what was
+    /// entered, if it is worth searching for.
+    pub fn text_changed(&self) -> Option<UserSpecificCommand> {
+        self.error_label.hide();
+
+        let text = self.text();
+        self.invite_button
+            .set_sensitive(UserId::try_from(text.as_str()).is_ok());
+
+        // TODO: Support inviting by email address (third party invites)
+        let is_email = !text.starts_with('@') && text.contains('@');
+        if text.chars().count() < MIN_SEARCH_TERM_LEN || is_email {
+            self.completion_store.clear();
+            return None;
+        }
+
+        Some(UserSpecificCommand::SearchUserDirectory { search_term: text })
+    }
+
+    pub fn show_search_results(&self, search_term: &str, users: Vec<DirectoryUser>) {
+        // Results for what was entered before aren't interesting anymore
+        if self.text() != search_term {
+            return;
+        }
+
+        self.completion_store.clear();
+        for user in users {
+            let user_id = user.user_id.to_string();
+            let display_name = user.display_name.unwrap_or_default();
+
+            self.completion_store.insert_with_values(
+                None,
+                &[USER_ID_COLUMN, DISPLAY_NAME_COLUMN],
+                &[&user_id, &display_name],
+            );
+        }
+
+        if let Some(completion) = self.entry.get_completion() {
+            completion.complete();
+        }
+    }
+
+    /// Create the command for inviting the user that was entered to `room_id`,
+    /// if a valid user ID was entered.
+    pub fn invite_command(&self, room_id: RoomId) -> Option<UserSpecificCommand> {
+        let user_id = match UserId::try_from(self.text().as_str()) {
+            Ok(user_id) => user_id,
+            Err(_) => {
+                self.show_error("This is not a valid Matrix ID, e.g. @alice:example.org.");
+                return None;
+            }
+        };
+
+        self.error_label.hide();
+        self.invite_button.set_sensitive(false);
+
+        Some(UserSpecificCommand::InviteUser { room_id, user_id })
+    }
+
+    /// Reset the popover after an invite went through.
+    pub fn invite_succeeded(&self) {
+        self.entry.set_text("");
+        self.completion_store.clear();
+        self.error_label.hide();
+    }
+
+    pub fn invite_failed(&self, user_id: &UserId, message: &str) {
+        self.invite_button.set_sensitive(true);
+        self.show_error(&format!("Couldn't invite {}: {}", user_id, message));
+    }
+
+    fn show_error(&self, message: &str) {
+        self.error_label.set_text(message);
+        self.error_label.show();
+    }
+
+    fn text(&self) -> String {
+        self.entry
+            .get_text()
+            .map(|text| text.trim().to_owned())
+            .unwrap_or_default()
+    }
+}
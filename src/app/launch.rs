@@ -1,16 +1,56 @@
+use std::{cell::RefCell, convert::TryFrom, rc::Rc};
+
 use futures::{self, Sink};
 use gio::{self, prelude::*};
+use glib::{self, ToVariant};
 use gtk::{self, prelude::*};
-
-use crate::bg_thread::{MatrixCommand, UserSpecificCommand};
+use ruma_identifiers::{RoomAliasId, RoomId};
+use url::percent_encoding::percent_decode;
+
+use super::{
+    account::AccountMenu,
+    account_switcher::AccountSwitcher,
+    directory::DirectoryView,
+    invitation::InvitationView,
+    invite::InvitePopover,
+    member_list::MemberList,
+    message_view::MessageView,
+    room_list,
+};
+use crate::bg_thread::{
+    self,
+    ConnectionMethod,
+    HomeserverAddress,
+    InternalUserId,
+    MatrixCommand,
+    RoomPreset,
+    RoomVisibility,
+    UserSpecificCommand,
+};
 
 /// Connect signals which are activated when the application is launched.
 pub(super) fn connect(
     gtk_app: gtk::Application,
     gtk_builder: gtk::Builder,
     backend_chan_tx: futures::sync::mpsc::Sender<MatrixCommand>,
+    message_view: Rc<RefCell<MessageView>>,
+    member_list: Rc<RefCell<MemberList>>,
+    directory_view: Rc<RefCell<DirectoryView>>,
+    invite_popover: Rc<InvitePopover>,
+    invitation_view: Rc<RefCell<InvitationView>>,
+    account_menu: Rc<AccountMenu>,
+    account_switcher: Rc<AccountSwitcher>,
 ) {
-    gtk_app.connect_activate(clone!(gtk_builder, backend_chan_tx => move |app| {
+    gtk_app.connect_activate(clone!(
+        gtk_builder,
+        backend_chan_tx,
+        message_view,
+        member_list,
+        directory_view,
+        invite_popover,
+        invitation_view,
+        account_menu,
+        account_switcher => move |app| {
         // Add app actions
         // TODO: Implement prefs, shortcuts, and about actions
         let _act_prefs = gio::SimpleAction::new("preferences", None);
@@ -76,6 +116,9 @@ pub(super) fn connect(
         }));
 
         // Set up popover for inviting people to a room
+        let rooms_tree_view: gtk::TreeView = gtk_builder.get_object("rooms_tree_view")
+            .expect("Couldn't find rooms tree view in ui file.");
+
         let act_show_rd_invite = gio::SimpleAction::new("show_rd_invite", None);
         let rd_invite_button: gtk::Button = gtk_builder.get_object("rd_invite_button")
             .expect("Couldn't find room invite button in ui file.");
@@ -99,12 +142,59 @@ pub(super) fn connect(
             rd_popover.hide();
         }));
 
+        let rdi_invite_entry: gtk::Entry = gtk_builder.get_object("rdi_invite_entry")
+            .expect("Couldn't find room invite entry in ui file.");
+        let rdi_invite_button: gtk::Button = gtk_builder.get_object("rdi_invite_button")
+            .expect("Couldn't find room invite button in ui file.");
+
+        rdi_invite_entry.connect_changed(clone!(
+            account_switcher,
+            backend_chan_tx,
+            invite_popover => move |_| {
+            if let Some(command) = invite_popover.text_changed() {
+                send_user_command(&backend_chan_tx, &account_switcher, command);
+            }
+        }));
+
+        let invite = clone!(
+            account_switcher,
+            backend_chan_tx,
+            invite_popover,
+            rooms_tree_view => move || {
+            let room_id = match room_list::selected_room(&rooms_tree_view) {
+                Some((room_id, _)) => room_id,
+                None => return,
+            };
+
+            if let Some(command) = invite_popover.invite_command(room_id) {
+                send_user_command(&backend_chan_tx, &account_switcher, command);
+            }
+        });
+
+        rdi_invite_entry.connect_activate(clone!(invite => move |_| {
+            invite();
+        }));
+        rdi_invite_button.connect_clicked(move |_| invite());
+
         // Set up popover for leaving a room
         let act_show_rd_leave = gio::SimpleAction::new("show_rd_leave", None);
         let rd_leave_button: gtk::Button = gtk_builder.get_object("rd_leave_button")
             .expect("Couldn't find room leave button in ui file.");
 
-        act_show_rd_leave.connect_activate(clone!(rd_popover, rd_stack => move |_, _| {
+        let rdl_leave_label: gtk::Label = gtk_builder.get_object("rdl_leave_label")
+            .expect("Couldn't find room leave label in ui file.");
+
+        act_show_rd_leave.connect_activate(clone!(
+            rd_popover,
+            rd_stack,
+            rdl_leave_label,
+            rooms_tree_view => move |_, _| {
+            let room_name = match room_list::selected_room(&rooms_tree_view) {
+                Some((_, name)) => name,
+                None => return,
+            };
+
+            rdl_leave_label.set_text(&format!("Are you sure you want to leave '{}'?", room_name));
             rd_stack.set_visible_child_name("leave");
             if !rd_popover.is_visible() {
                 rd_popover.show();
@@ -123,6 +213,137 @@ pub(super) fn connect(
             rd_popover.hide();
         }));
 
+        let rdl_leave_button: gtk::Button = gtk_builder.get_object("rdl_leave_butto")
+            .expect("Couldn't find room leave button in ui file.");
+
+        rdl_leave_button.connect_clicked(clone!(
+            account_switcher,
+            backend_chan_tx,
+            rd_popover,
+            rooms_tree_view => move |_| {
+            rd_popover.hide();
+
+            if let Some((room_id, _)) = room_list::selected_room(&rooms_tree_view) {
+                send_user_command(
+                    &backend_chan_tx,
+                    &account_switcher,
+                    UserSpecificCommand::LeaveRoom { room_id },
+                );
+            }
+        }));
+
+        // Set up joining rooms, from the room add menu or the directory
+        let act_join_room =
+            gio::SimpleAction::new("join_room", Some(glib::VariantTy::new("s").unwrap()));
+        let room_add_menu: gtk::PopoverMenu = gtk_builder.get_object("room_add_menu")
+            .expect("Couldn't find room add menu in ui file.");
+        let ra_join_invite_entry: gtk::Entry = gtk_builder.get_object("ra_join_invite_entry")
+            .expect("Couldn't find room join entry in ui file.");
+        let ra_join_invite_button: gtk::Button = gtk_builder.get_object("ra_join_invite_button")
+            .expect("Couldn't find room join button in ui file.");
+
+        act_join_room.connect_activate(clone!(account_switcher, backend_chan_tx => move |_, param| {
+            let (room_id_or_alias, server_names) = match param
+                .as_ref()
+                .and_then(|v| v.get_str())
+                .and_then(parse_room_reference)
+            {
+                Some(room) => room,
+                None => return,
+            };
+
+            send_user_command(
+                &backend_chan_tx,
+                &account_switcher,
+                UserSpecificCommand::JoinRoom {
+                    room_id_or_alias,
+                    server_names,
+                },
+            );
+        }));
+        window.add_action(&act_join_room);
+
+        // TODO: Also accept user IDs and email addresses to start direct chats
+        ra_join_invite_entry.connect_changed(clone!(ra_join_invite_button => move |entry| {
+            let is_valid = entry
+                .get_text()
+                .and_then(|text| parse_room_reference(&text))
+                .is_some();
+            ra_join_invite_button.set_sensitive(is_valid);
+        }));
+
+        let join_from_entry = clone!(
+            act_join_room,
+            ra_join_invite_entry,
+            room_add_menu => move || {
+            let text = match ra_join_invite_entry.get_text() {
+                Some(ref text) if parse_room_reference(text).is_some() => text.clone(),
+                _ => return,
+            };
+
+            act_join_room.activate(Some(&text.to_variant()));
+            ra_join_invite_entry.set_text("");
+            room_add_menu.hide();
+        });
+
+        ra_join_invite_entry.connect_activate(clone!(join_from_entry => move |_| {
+            join_from_entry();
+        }));
+        ra_join_invite_button.connect_clicked(move |_| join_from_entry());
+
+        // Set up creating rooms
+        let ra_create_entry: gtk::Entry = gtk_builder.get_object("ra_create_entry")
+            .expect("Couldn't find room create name entry in ui file.");
+        let ra_create_alias_entry: gtk::Entry = gtk_builder.get_object("ra_create_alias_entry")
+            .expect("Couldn't find room create alias entry in ui file.");
+        let ra_create_public_check: gtk::CheckButton =
+            gtk_builder.get_object("ra_create_public_check")
+                .expect("Couldn't find room create public check button in ui file.");
+        let ra_create_button: gtk::Button = gtk_builder.get_object("ra_create_button")
+            .expect("Couldn't find room create button in ui file.");
+
+        ra_create_button.connect_clicked(clone!(
+            account_switcher,
+            backend_chan_tx,
+            ra_create_entry,
+            ra_create_alias_entry,
+            ra_create_public_check,
+            room_add_menu => move |_| {
+            let name = ra_create_entry
+                .get_text()
+                .map(|text| text.trim().to_owned())
+                .filter(|text| !text.is_empty());
+            // Only the localpart is sent, the homeserver adds its own name
+            let alias = ra_create_alias_entry
+                .get_text()
+                .and_then(|text| {
+                    let localpart = text.trim().trim_start_matches('#').split(':').next()?;
+                    Some(localpart.to_owned())
+                })
+                .filter(|localpart| !localpart.is_empty());
+            let (visibility, preset) = if ra_create_public_check.get_active() {
+                (RoomVisibility::Public, RoomPreset::PublicChat)
+            } else {
+                (RoomVisibility::Private, RoomPreset::PrivateChat)
+            };
+
+            send_user_command(
+                &backend_chan_tx,
+                &account_switcher,
+                UserSpecificCommand::CreateRoom {
+                    name,
+                    alias,
+                    visibility,
+                    preset,
+                },
+            );
+
+            ra_create_entry.set_text("");
+            ra_create_alias_entry.set_text("");
+            ra_create_public_check.set_active(false);
+            room_add_menu.hide();
+        }));
+
         // Set up room pins toggle
         let act_toggle_room_pins = gio::SimpleAction::new("toggle_room_pins", None);
         let pins_revealer: gtk::Revealer = gtk_builder.get_object("pins_revealer")
@@ -150,9 +371,28 @@ pub(super) fn connect(
         }));
         window.add_action(&act_toggle_right_pane);
 
-        rp_toggle.connect_toggled(clone!(rp_revealer => move |toggle| {
-            rp_revealer.set_reveal_child(toggle.get_active())
-        }));
+        rp_toggle.connect_toggled(clone!(
+            backend_chan_tx,
+            member_list,
+            rp_revealer => move |toggle| {
+                rp_revealer.set_reveal_child(toggle.get_active());
+
+                if let Some((account, command)) = member_list.borrow_mut().fetch_members() {
+                    send_account_command(&backend_chan_tx, account, command);
+                }
+            }
+        ));
+
+        let rp_stack: gtk::Stack = gtk_builder.get_object("right_pane_stack")
+            .expect("Couldn't find right pane stack in ui file.");
+        rp_stack.connect_property_visible_child_name_notify(clone!(
+            backend_chan_tx,
+            member_list => move |_| {
+                if let Some((account, command)) = member_list.borrow_mut().fetch_members() {
+                    send_account_command(&backend_chan_tx, account, command);
+                }
+            }
+        ));
 
         // Set up room settings view
         let act_toggle_room_settings = gio::SimpleAction::new("toggle_room_settings", None);
@@ -204,10 +444,90 @@ pub(super) fn connect(
         }));
         window.add_action(&act_show_user_menu);
 
-        u_register_button.connect_clicked(clone!(u_menu => move |_| {
-            u_menu.open_submenu("new_password");
+        let act_remove_account = gio::SimpleAction::new("remove_account", None);
+        act_remove_account.connect_activate(clone!(
+            account_menu,
+            account_switcher,
+            backend_chan_tx => move |_, _| {
+            let user_id = match account_switcher.current() {
+                Some(user_id) => user_id,
+                None => return,
+            };
+            let is_guest = account_switcher.current_guest().is_some();
+
+            account_menu.confirm_logout(is_guest, clone!(backend_chan_tx => move |all_devices| {
+                // TODO: Do we want to handle send errors?
+                let _ = backend_chan_tx.clone().wait().send(MatrixCommand::Logout {
+                    user_id,
+                    all_devices,
+                });
+            }));
+        }));
+        app.add_action(&act_remove_account);
+
+        let act_full_resync = gio::SimpleAction::new("full_resync", None);
+        act_full_resync.connect_activate(clone!(account_switcher, backend_chan_tx => move |_, _| {
+            send_user_command(&backend_chan_tx, &account_switcher, UserSpecificCommand::FullResync);
+        }));
+        app.add_action(&act_full_resync);
+
+        u_register_button.connect_clicked(clone!(account_menu, u_menu => move |_| {
+            if account_menu.check_registration_details() {
+                u_menu.open_submenu("new_password");
+            }
         }));
 
+        // The menu is opened next to the greeter's register button as well
+        u_menu.connect_hide(clone!(account_menu => move |_| {
+            account_menu.reset_position();
+        }));
+
+        let u_login_password_entry: gtk::Entry = gtk_builder.get_object("u_login_password_entry")
+            .expect("Couldn't find login password entry in ui file.");
+        let u_login_button: gtk::Button = gtk_builder.get_object("u_login_button")
+            .expect("Couldn't find login button in ui file.");
+
+        let log_in = clone!(account_menu, backend_chan_tx => move || {
+            if let Some(command) = account_menu.login_command() {
+                // TODO: Do we want to handle send errors?
+                let _ = backend_chan_tx.clone().wait().send(command);
+            }
+        });
+        u_login_password_entry.connect_activate(clone!(log_in => move |_| log_in()));
+        u_login_button.connect_clicked(move |_| log_in());
+
+        let u_login_sso_button: gtk::Button = gtk_builder.get_object("u_login_sso_button")
+            .expect("Couldn't find single sign-on button in ui file.");
+        u_login_sso_button.connect_clicked(clone!(account_menu, backend_chan_tx => move |_| {
+            if let Some(command) = account_menu.sso_command() {
+                // TODO: Do we want to handle send errors?
+                let _ = backend_chan_tx.clone().wait().send(command);
+            }
+        }));
+
+        let u_regpass_entry: gtk::Entry = gtk_builder.get_object("u_regpass_entry")
+            .expect("Couldn't find new password entry in ui file.");
+        let u_regpass_confirm_entry: gtk::Entry = gtk_builder.get_object("u_regpass_confirm_entry")
+            .expect("Couldn't find new password confirmation entry in ui file.");
+        let u_regpass_button: gtk::Button = gtk_builder.get_object("u_regpass_button")
+            .expect("Couldn't find new password register button in ui file.");
+
+        account_menu.check_passwords();
+        for entry in &[&u_regpass_entry, &u_regpass_confirm_entry] {
+            entry.connect_changed(clone!(account_menu => move |_| {
+                account_menu.check_passwords();
+            }));
+        }
+
+        let register = clone!(account_menu, account_switcher, backend_chan_tx => move || {
+            if let Some(command) = account_menu.register_command(account_switcher.current_guest()) {
+                // TODO: Do we want to handle send errors?
+                let _ = backend_chan_tx.clone().wait().send(command);
+            }
+        });
+        u_regpass_confirm_entry.connect_activate(clone!(register => move |_| register()));
+        u_regpass_button.connect_clicked(move |_| register());
+
         // When switching the main window stack child, we need to modify the
         // header bar to match
         let h_bar: gtk::HeaderBar = gtk_builder.get_object("header_bar")
@@ -267,14 +587,30 @@ pub(super) fn connect(
         let lp_directory_button: gtk::Button = gtk_builder.get_object("lp_directory_button")
             .expect("Couldn't find directory button in ui file.");
 
-        act_show_dir_view.connect_activate(clone!(view_switcher, backend_chan_tx => move |_, _| {
+        let dir_search_entry: gtk::SearchEntry = gtk_builder.get_object("dir_search_entry")
+            .expect("Couldn't find directory search entry in ui file.");
+        let dir_server_entry: gtk::Entry = gtk_builder.get_object("dir_server_entry")
+            .expect("Couldn't find directory server entry in ui file.");
+        let dir_load_more_button: gtk::Button = gtk_builder.get_object("dir_load_more_button")
+            .expect("Couldn't find directory load more button in ui file.");
+
+        let fetch_directory = Rc::new(clone!(
+            account_switcher,
+            backend_chan_tx,
+            directory_view => move |next_page| {
+            // Nothing would answer, and the directory would keep loading
+            if account_switcher.current().is_none() {
+                return;
+            }
+
+            if let Some(command) = directory_view.borrow_mut().fetch_command(next_page) {
+                send_user_command(&backend_chan_tx, &account_switcher, command);
+            }
+        }));
+
+        act_show_dir_view.connect_activate(clone!(view_switcher, fetch_directory => move |_, _| {
             view_switcher("directory_view", "Directory", "", Some("Back"));
-            // TODO: Do we want to handle send errors?
-            // TODO: Replace 0, it is just a dummy User ID
-            let _ = backend_chan_tx.clone().wait().send(MatrixCommand::UserSpecificCommand {
-                user_id: 0,
-                command: UserSpecificCommand::FetchDirectory,
-            });
+            fetch_directory(false);
         }));
         window.add_action(&act_show_dir_view);
 
@@ -282,19 +618,141 @@ pub(super) fn connect(
             act_show_dir_view.activate(None);
         }));
 
+        // search-changed is already delayed until the user stops typing
+        dir_search_entry.connect_search_changed(clone!(fetch_directory => move |_| {
+            fetch_directory(false);
+        }));
+        dir_server_entry.connect_activate(clone!(fetch_directory => move |_| {
+            fetch_directory(false);
+        }));
+        dir_load_more_button.connect_clicked(clone!(fetch_directory => move |_| {
+            fetch_directory(true);
+        }));
+
         // Set up room view
         let act_show_room_view = gio::SimpleAction::new("show_room_view", None);
+        let title_name_label: gtk::Label = gtk_builder.get_object("title_name_label")
+            .expect("Couldn't find room title name label in ui file.");
+
+        act_show_room_view.connect_activate(clone!(
+            account_switcher,
+            backend_chan_tx,
+            invitation_view,
+            member_list,
+            rooms_tree_view,
+            title_name_label,
+            view_switcher => move |_, _| {
+                let selected_room = room_list::selected_room(&rooms_tree_view);
+                let room_name = selected_room
+                    .as_ref()
+                    .map(|(_, name)| name.clone())
+                    .unwrap_or_else(|| "Fest".to_owned());
+
+                title_name_label.set_text(&room_name);
+                view_switcher("room_view", &room_name, "", None);
+
+                if let (Some(account), Some((room_id, _))) =
+                    (account_switcher.current(), selected_room)
+                {
+                    let command = member_list.borrow_mut().show_room(account, room_id.clone());
+                    if let Some((account, command)) = command {
+                        send_account_command(&backend_chan_tx, account, command);
+                    }
 
-        act_show_room_view.connect_activate(clone!(view_switcher => move |_, _| {
-            // TODO: Set to actual room name
-            view_switcher("room_view", "Fest", "", None);
-        }));
+                    // Invitations are shown instead of the chat
+                    let command = invitation_view.borrow_mut().show_room(account, room_id);
+                    if let Some(command) = command {
+                        send_account_command(&backend_chan_tx, account, command);
+                    }
+                }
+            }
+        ));
         window.add_action(&act_show_room_view);
 
+        // Set up accepting and declining invitations
+        let iv_accept_button: gtk::Button = gtk_builder.get_object("iv_accept_button")
+            .expect("Couldn't find invitation accept button in ui file.");
+        let iv_decline_button: gtk::Button = gtk_builder.get_object("iv_decline_button")
+            .expect("Couldn't find invitation decline button in ui file.");
+
+        iv_accept_button.connect_clicked(clone!(backend_chan_tx, invitation_view => move |_| {
+            let (account, room_id) = match invitation_view.borrow().current_invitation() {
+                Some(invitation) => invitation,
+                None => return,
+            };
+
+            send_account_command(
+                &backend_chan_tx,
+                account,
+                UserSpecificCommand::JoinRoom {
+                    room_id_or_alias: room_id.to_string(),
+                    // The inviting server is tried by the homeserver anyway
+                    server_names: Vec::new(),
+                },
+            );
+        }));
+
+        iv_decline_button.connect_clicked(clone!(backend_chan_tx, invitation_view => move |_| {
+            let ((account, room_id), user_to_ignore) = {
+                let invitation_view = invitation_view.borrow();
+                match invitation_view.current_invitation() {
+                    Some(invitation) => (invitation, invitation_view.user_to_ignore()),
+                    None => return,
+                }
+            };
+
+            send_account_command(
+                &backend_chan_tx,
+                account,
+                UserSpecificCommand::LeaveRoom { room_id },
+            );
+
+            if let Some(user_id) = user_to_ignore {
+                send_account_command(
+                    &backend_chan_tx,
+                    account,
+                    UserSpecificCommand::IgnoreUser { user_id },
+                );
+            }
+        }));
+
+        rooms_tree_view.get_selection().connect_changed(clone!(
+            act_show_room_view,
+            rooms_tree_view => move |_| {
+                // Selecting a section header doesn't switch rooms
+                if room_list::selected_room(&rooms_tree_view).is_some() {
+                    act_show_room_view.activate(None);
+                }
+            }
+        ));
+
         h_back_button.connect_clicked(clone!(act_show_room_view => move |_| {
             act_show_room_view.activate(None);
         }));
 
+        // Fetch older messages when scrolling close to the top, which is also
+        // where the list is if the messages don't fill it
+        let ml_scroll: gtk::ScrolledWindow = gtk_builder.get_object("message_list_scroll")
+            .expect("Couldn't find message list scrolled window in ui file.");
+        if let Some(adjustment) = ml_scroll.get_vadjustment() {
+            let paginate = Rc::new(clone!(
+                backend_chan_tx,
+                message_view => move || {
+                    // The adjustment can change while the view is being updated
+                    let command = match message_view.try_borrow_mut() {
+                        Ok(mut message_view) => message_view.paginate(),
+                        Err(_) => None,
+                    };
+                    if let Some((account, command)) = command {
+                        send_account_command(&backend_chan_tx, account, command);
+                    }
+                }
+            ));
+
+            adjustment.connect_value_changed(clone!(paginate => move |_| paginate()));
+            adjustment.connect_changed(move |_| paginate());
+        }
+
         // Set up composer callbacks
         let ri_popover: gtk::Popover = gtk_builder.get_object("room_interactions_popover")
             .expect("Couldn't find room interactions popover in ui file.");
@@ -343,10 +801,72 @@ pub(super) fn connect(
             act_voice_call.activate(None);
         }));
 
+        // Set up sending messages
+        let composer_entry: gtk::Entry = gtk_builder.get_object("composer_entry")
+            .expect("Couldn't find composer entry in ui file.");
+
+        composer_entry.connect_activate(clone!(
+            account_switcher,
+            backend_chan_tx,
+            rooms_tree_view => move |entry| {
+            let message_content = match entry.get_text() {
+                Some(ref text) if !text.trim().is_empty() => text.clone(),
+                _ => return,
+            };
+            let room_id = match room_list::selected_room(&rooms_tree_view) {
+                Some((room_id, _)) => room_id,
+                None => return,
+            };
+
+            send_user_command(
+                &backend_chan_tx,
+                &account_switcher,
+                UserSpecificCommand::SendTextMessage {
+                    room_id,
+                    message_content,
+                },
+            );
+
+            entry.set_text("");
+        }));
+
+        // Set up actions for messages that failed to send
+        let act_retry_message =
+            gio::SimpleAction::new("retry_message", Some(glib::VariantTy::new("s").unwrap()));
+        let act_discard_message =
+            gio::SimpleAction::new("discard_message", Some(glib::VariantTy::new("s").unwrap()));
+
+        act_retry_message.connect_activate(clone!(backend_chan_tx, message_view => move |_, param| {
+            let local_id = match param.as_ref().and_then(|v| v.get_str()) {
+                Some(local_id) => local_id.to_owned(),
+                None => return,
+            };
+
+            if let Some((account, room_id, message_content)) =
+                message_view.borrow_mut().retry_local_echo(&local_id)
+            {
+                send_account_command(
+                    &backend_chan_tx,
+                    account,
+                    UserSpecificCommand::RetryTextMessage {
+                        room_id,
+                        local_id,
+                        message_content,
+                    },
+                );
+            }
+        }));
+        window.add_action(&act_retry_message);
+
+        act_discard_message.connect_activate(clone!(message_view => move |_, param| {
+            if let Some(local_id) = param.as_ref().and_then(|v| v.get_str()) {
+                message_view.borrow_mut().discard_local_echo(local_id);
+            }
+        }));
+        window.add_action(&act_discard_message);
+
         // Set up markdown formatting toggling and notification
         let act_toggle_markdown = gio::SimpleAction::new("toggle_markdown", None);
-        let _composer_entry: gtk::Entry = gtk_builder.get_object("composer_entry")
-            .expect("Couldn't find composer entry in ui file.");
         let rvc_notif_revealer: gtk::Revealer = gtk_builder.get_object("rvc_notif_revealer")
             .expect("Couldn't find chat notification revealer in ui file.");
         let rvc_notif_label: gtk::Label = gtk_builder.get_object("rvc_notif_label")
@@ -387,15 +907,51 @@ pub(super) fn connect(
             rvc_notif_revealer.set_reveal_child(false);
         }));
 
-        // Set up greeter and related functions
-        // TODO: Make this is only show on first run
-        view_switcher("greeter_view", "Fest", "Matrix chat client", None);
+        // Set up greeter and related functions, which are only needed if
+        // there is no session left from a previous run, or after logging out
+        let act_show_greeter = gio::SimpleAction::new("show_greeter", None);
+        act_show_greeter.connect_activate(clone!(view_switcher => move |_, _| {
+            view_switcher("greeter_view", "Fest", "Matrix chat client", None);
+        }));
+        window.add_action(&act_show_greeter);
+
+        let stored_sessions = bg_thread::load_sessions();
+        if stored_sessions.is_empty() {
+            act_show_greeter.activate(None);
+        } else {
+            for stored_session in stored_sessions {
+                // TODO: Do we want to handle send errors?
+                let _ = backend_chan_tx.clone().wait().send(MatrixCommand::Connect {
+                    homeserver: HomeserverAddress::Url(stored_session.homeserver_url.clone()),
+                    connection_method: ConnectionMethod::RestoreSession(stored_session),
+                });
+            }
+
+            act_show_room_view.activate(None);
+        }
 
         let gv_guest_button: gtk::Button = gtk_builder.get_object("gv_guest_button")
             .expect("Couldn't find greeter view guest button in ui file.");
 
-        gv_guest_button.connect_clicked(clone!(view_switcher => move |_| {
-            view_switcher("directory_view", "Directory", "", Some("Skip"));
+        gv_guest_button.connect_clicked(clone!(account_menu, backend_chan_tx => move |_| {
+            if let Some(command) = account_menu.guest_command() {
+                // TODO: Do we want to handle send errors?
+                let _ = backend_chan_tx.clone().wait().send(command);
+            }
+        }));
+
+        let gv_login_button: gtk::Button = gtk_builder.get_object("gv_login_button")
+            .expect("Couldn't find greeter view login button in ui file.");
+
+        gv_login_button.connect_clicked(clone!(account_menu => move |button| {
+            account_menu.open_login(button);
+        }));
+
+        let gv_register_button: gtk::Button = gtk_builder.get_object("gv_register_button")
+            .expect("Couldn't find greeter view register button in ui file.");
+
+        gv_register_button.connect_clicked(clone!(account_menu => move |button| {
+            account_menu.open_registration(button);
         }));
 
         // Set up action accelerators
@@ -426,3 +982,65 @@ pub(super) fn connect(
         window.present();
     }));
 }
+
+/// Send a user-specific command for the account selected in the account
+/// switcher, if there is one.
+fn send_user_command(
+    backend_chan_tx: &futures::sync::mpsc::Sender<MatrixCommand>,
+    account_switcher: &AccountSwitcher,
+    command: UserSpecificCommand,
+) {
+    if let Some(command) = account_switcher.command(command) {
+        // TODO: Do we want to handle send errors?
+        let _ = backend_chan_tx.clone().wait().send(command);
+    }
+}
+
+/// Send a user-specific command for `account`, which doesn't need to be the
+/// selected one.
+fn send_account_command(
+    backend_chan_tx: &futures::sync::mpsc::Sender<MatrixCommand>,
+    account: InternalUserId,
+    command: UserSpecificCommand,
+) {
+    // TODO: Do we want to handle send errors?
+    let _ = backend_chan_tx.clone().wait().send(MatrixCommand::UserSpecificCommand {
+        user_id: account,
+        command,
+    });
+}
+
+/// Parse a reference to a room as entered by the user or used as target of
+/// `win.join_room`: a room ID or alias, optionally as matrix.to link and
+/// optionally followed by `?via=<server>` parameters.
+///
+/// Returns the room ID or alias and the servers to join through.
+fn parse_room_reference(text: &str) -> Option<(String, Vec<String>)> {
+    let text = text.trim().trim_start_matches("https://matrix.to/#/");
+    let mut parts = text.splitn(2, '?');
+    let room = percent_decode(parts.next()?.as_bytes())
+        .decode_utf8()
+        .ok()?
+        .into_owned();
+
+    let mut server_names: Vec<String> = parts
+        .next()
+        .unwrap_or("")
+        .split('&')
+        .filter(|param| param.starts_with("via="))
+        .map(|param| param["via=".len()..].to_owned())
+        .filter(|server| !server.is_empty())
+        .collect();
+
+    if RoomId::try_from(room.as_str()).is_ok() {
+        // The server that created the room is the best guess for joining a
+        // room by ID if there are no other hints.
+        if server_names.is_empty() {
+            server_names.extend(room.splitn(2, ':').nth(1).map(ToOwned::to_owned));
+        }
+    } else if RoomAliasId::try_from(room.as_str()).is_err() {
+        return None;
+    }
+
+    Some((room, server_names))
+}
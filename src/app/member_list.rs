@@ -0,0 +1,171 @@
+use std::collections::{HashMap, HashSet};
+
+use gtk::{self, prelude::*};
+use ruma_events::room::member::MembershipState;
+use ruma_identifiers::{RoomId, UserId};
+
+use crate::bg_thread::{InternalUserId, RoomStateChange, UserSpecificCommand};
+
+type RoomKey = (InternalUserId, RoomId);
+
+/// The "People" page of the right pane, listing the members of the selected
+/// room.
+///
+/// Sync responses only contain the members needed to show the timeline, so
+/// the others are fetched the first time the list is opened for a room.
+pub(super) struct MemberList {
+    revealer: gtk::Revealer,
+    stack: gtk::Stack,
+    list_box: gtk::ListBox,
+    /// The display names of the joined and invited members of each room, by
+    /// the account that is in it and the room.
+    members: HashMap<RoomKey, HashMap<UserId, Option<String>>>,
+    /// Rooms whose members were already requested with `FetchMembers`,
+    /// unless that failed.
+    requested_rooms: HashSet<RoomKey>,
+    current_room: Option<RoomKey>,
+}
+
+impl MemberList {
+    pub fn new(gtk_builder: &gtk::Builder) -> Self {
+        MemberList {
+            revealer: gtk_builder
+                .get_object("right_pane_revealer")
+                .expect("Couldn't find right pane revealer in ui file."),
+            stack: gtk_builder
+                .get_object("right_pane_stack")
+                .expect("Couldn't find right pane stack in ui file."),
+            list_box: gtk_builder
+                .get_object("rp_user_list")
+                .expect("Couldn't find right pane user list in ui file."),
+            members: HashMap::new(),
+            requested_rooms: HashSet::new(),
+            current_room: None,
+        }
+    }
+
+    /// Show the members of a room of `account`.
+    ///
+    /// Returns the command to fetch them, if the list is open and they
+    /// weren't fetched yet.
+    pub fn show_room(
+        &mut self,
+        account: InternalUserId,
+        room_id: RoomId,
+    ) -> Option<(InternalUserId, UserSpecificCommand)> {
+        let key = (account, room_id);
+        if self.current_room.as_ref() != Some(&key) {
+            self.current_room = Some(key);
+            self.update();
+        }
+
+        self.fetch_members()
+    }
+
+    /// Show no room at all, e.g. after switching to an account without a
+    /// selected room.
+    pub fn clear(&mut self) {
+        self.current_room = None;
+        self.update();
+    }
+
+    /// Forget the members of a room of an account that was logged out of.
+    pub fn remove_room(&mut self, account: InternalUserId, room_id: &RoomId) {
+        let key = (account, room_id.clone());
+        self.members.remove(&key);
+        self.requested_rooms.remove(&key);
+
+        if self.current_room.as_ref() == Some(&key) {
+            self.clear();
+        }
+    }
+
+    /// The command to fetch the members of the current room, and the account
+    /// to send it for, if the list was just opened and they weren't fetched
+    /// yet.
+    pub fn fetch_members(&mut self) -> Option<(InternalUserId, UserSpecificCommand)> {
+        let is_open = self.revealer.get_reveal_child()
+            && self.stack.get_visible_child_name() == Some("people".to_owned());
+        if !is_open {
+            return None;
+        }
+
+        let (account, room_id) = self.current_room.clone()?;
+        if !self.requested_rooms.insert((account, room_id.clone())) {
+            return None;
+        }
+
+        Some((account, UserSpecificCommand::FetchMembers { room_id }))
+    }
+
+    /// Fetch the members of a room again the next time the list is opened
+    /// for it, because fetching them failed.
+    pub fn fetch_failed(&mut self, account: InternalUserId, room_id: &RoomId) {
+        self.requested_rooms.remove(&(account, room_id.clone()));
+    }
+
+    pub fn apply_changes<'a, I>(&mut self, account: InternalUserId, room_id: &RoomId, changes: I)
+    where
+        I: IntoIterator<Item = &'a RoomStateChange>,
+    {
+        let key = (account, room_id.clone());
+        let members = self.members.entry(key.clone()).or_insert_with(HashMap::new);
+        let mut changed = false;
+
+        for change in changes {
+            if let RoomStateChange::Member {
+                ref user_id,
+                ref display_name,
+                ref membership,
+            } = *change
+            {
+                match *membership {
+                    MembershipState::Join | MembershipState::Invite => {
+                        members.insert(user_id.clone(), display_name.clone());
+                    }
+                    _ => {
+                        members.remove(user_id);
+                    }
+                }
+                changed = true;
+            }
+        }
+
+        if changed && self.current_room.as_ref() == Some(&key) {
+            self.update();
+        }
+    }
+
+    fn update(&self) {
+        for row in self.list_box.get_children() {
+            self.list_box.remove(&row);
+        }
+
+        let mut members: Vec<_> = self
+            .current_room
+            .as_ref()
+            .and_then(|key| self.members.get(key))
+            .map(|members| {
+                members
+                    .iter()
+                    .map(|(user_id, display_name)| {
+                        let name = display_name.clone().unwrap_or_else(|| user_id.to_string());
+                        (name, user_id)
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        members.sort_by_key(|&(ref name, _)| name.to_lowercase());
+
+        for (name, user_id) in &members {
+            let label = gtk::Label::new(Some(name.as_str()));
+            label.set_xalign(0.0);
+            label.set_tooltip_text(Some(user_id.to_string().as_str()));
+            label.show();
+            self.list_box.insert(&label, -1);
+        }
+
+        let title = format!("People ({})", members.len());
+        self.stack.set_child_title(&self.list_box, Some(title.as_str()));
+    }
+}
@@ -0,0 +1,539 @@
+use std::{
+    cell::Cell,
+    collections::{HashMap, HashSet},
+    rc::Rc,
+};
+
+use chrono::{DateTime, Local, TimeZone};
+use glib::{self, ToVariant};
+use gtk::{self, prelude::*};
+use ruma_events::room::member::MembershipState;
+use ruma_identifiers::{EventId, RoomId};
+
+use crate::bg_thread::{
+    InternalUserId, RoomStateChange, TimelineEvent, TimelineEventContent, UserSpecificCommand,
+};
+
+/// How many older events to fetch at once when scrolling up.
+const PAGINATION_LIMIT: u32 = 30;
+
+/// A single entry in the scrollback of a room.
+pub(super) struct Message {
+    pub author: String,
+    pub timestamp: DateTime<Local>,
+    pub body: String,
+    /// `None` for local echoes that the server didn't confirm yet.
+    pub event_id: Option<EventId>,
+    pub local_echo: Option<LocalEcho>,
+}
+
+/// Information about a message that was sent from this session.
+pub(super) struct LocalEcho {
+    pub local_id: String,
+    pub state: LocalEchoState,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub(super) enum LocalEchoState {
+    Sending,
+    /// The server accepted the message, but we didn't see it in a sync
+    /// response yet.
+    Sent,
+    Failed,
+}
+
+impl Message {
+    pub fn local_echo(author: String, body: String, local_id: String) -> Self {
+        Message {
+            author,
+            timestamp: Local::now(),
+            body,
+            event_id: None,
+            local_echo: Some(LocalEcho {
+                local_id,
+                state: LocalEchoState::Sending,
+            }),
+        }
+    }
+
+    fn has_local_id(&self, local_id: &str) -> bool {
+        self.local_echo
+            .as_ref()
+            .map_or(false, |local_echo| local_echo.local_id == local_id)
+    }
+
+    pub fn from_timeline_event(event: TimelineEvent) -> Self {
+        let author = event
+            .sender_name
+            .unwrap_or_else(|| event.sender.to_string());
+        // The timestamp comes from other servers, which may send anything
+        let timestamp = Local
+            .timestamp_millis_opt(event.origin_server_ts as i64)
+            .single()
+            .unwrap_or_else(Local::now);
+
+        let body = match event.content {
+            TimelineEventContent::Text { body }
+            | TimelineEventContent::Notice { body }
+            | TimelineEventContent::OtherMessage { body } => body,
+            TimelineEventContent::Emote { body } => format!("* {} {}", author, body),
+            TimelineEventContent::StateChange(change) => describe_state_change(&author, change),
+        };
+
+        Message {
+            author,
+            timestamp,
+            body,
+            event_id: Some(event.event_id),
+            local_echo: None,
+        }
+    }
+}
+
+fn describe_state_change(author: &str, change: RoomStateChange) -> String {
+    match change {
+        RoomStateChange::Name(Some(name)) => format!("{} changed the room name to {}", author, name),
+        RoomStateChange::Name(None) => format!("{} removed the room name", author),
+        RoomStateChange::Topic(topic) => format!("{} changed the topic to \"{}\"", author, topic),
+        RoomStateChange::CanonicalAlias(alias) => {
+            format!("{} set the main address of this room to {}", author, alias)
+        }
+        RoomStateChange::Avatar(_) => format!("{} changed the room avatar", author),
+        RoomStateChange::Member {
+            user_id,
+            display_name,
+            membership,
+        } => {
+            let target = display_name.unwrap_or_else(|| user_id.to_string());
+            match membership {
+                MembershipState::Join => format!("{} joined the room", target),
+                MembershipState::Invite => format!("{} invited {}", author, target),
+                MembershipState::Leave if author == target => format!("{} left the room", target),
+                MembershipState::Leave => format!("{} removed {} from the room", author, target),
+                MembershipState::Ban => format!("{} banned {}", author, target),
+                _ => format!("{} changed the membership of {}", author, target),
+            }
+        }
+    }
+}
+
+/// A room of one of the accounts. Accounts can be in the same rooms, but
+/// each has its own timeline of them.
+type RoomKey = (InternalUserId, RoomId);
+
+/// The scrollback of all rooms, shown in message_list one room at a time.
+pub(super) struct MessageView {
+    list_box: gtk::ListBox,
+    adjustment: Option<gtk::Adjustment>,
+    /// Set before adding rows at the top, so the messages that were shown
+    /// stay in place.
+    distance_from_bottom: Rc<Cell<Option<f64>>>,
+    timelines: HashMap<RoomKey, Vec<Message>>,
+    /// Rooms whose older events are being fetched.
+    paginating_rooms: HashSet<RoomKey>,
+    /// Rooms whose timeline goes back to the start of the room.
+    complete_rooms: HashSet<RoomKey>,
+    current_room: Option<RoomKey>,
+}
+
+impl MessageView {
+    pub fn new(gtk_builder: &gtk::Builder) -> Self {
+        let list_box: gtk::ListBox = gtk_builder
+            .get_object("message_list")
+            .expect("Couldn't find message list in ui file.");
+        let scrolled_window: gtk::ScrolledWindow = gtk_builder
+            .get_object("message_list_scroll")
+            .expect("Couldn't find message list scrolled window in ui file.");
+
+        let adjustment = scrolled_window.get_vadjustment();
+        let distance_from_bottom = Rc::new(Cell::new(None));
+
+        // Keep the view scrolled to the bottom when new messages arrive, but
+        // only if the user didn't scroll up to read older messages.
+        if let Some(ref adjustment) = adjustment {
+            let stick_to_bottom = Rc::new(Cell::new(true));
+
+            adjustment.connect_value_changed(clone!(stick_to_bottom => move |adj| {
+                stick_to_bottom.set(adj.get_value() + adj.get_page_size() >= adj.get_upper() - 1.0);
+            }));
+
+            adjustment.connect_changed(clone!(distance_from_bottom, stick_to_bottom => move |adj| {
+                if let Some(distance) = distance_from_bottom.take() {
+                    adj.set_value(adj.get_upper() - distance);
+                } else if stick_to_bottom.get() {
+                    adj.set_value(adj.get_upper() - adj.get_page_size());
+                }
+            }));
+        }
+
+        MessageView {
+            list_box,
+            adjustment,
+            distance_from_bottom,
+            timelines: HashMap::new(),
+            paginating_rooms: HashSet::new(),
+            complete_rooms: HashSet::new(),
+            current_room: None,
+        }
+    }
+
+    /// Show the scrollback of `room_id` as seen by `account` in the message
+    /// list.
+    pub fn show_room(&mut self, account: InternalUserId, room_id: RoomId) {
+        let key = (account, room_id);
+        if self.current_room.as_ref() == Some(&key) {
+            return;
+        }
+
+        self.current_room = Some(key);
+        self.reload();
+    }
+
+    fn reload(&self) {
+        for row in self.list_box.get_children() {
+            self.list_box.remove(&row);
+        }
+
+        if let Some(messages) = self
+            .current_room
+            .as_ref()
+            .and_then(|key| self.timelines.get(key))
+        {
+            for message in messages {
+                self.list_box.insert(&message_row(message), -1);
+            }
+        }
+    }
+
+    /// Show no room at all, e.g. after switching to an account without a
+    /// selected room.
+    pub fn clear(&mut self) {
+        for row in self.list_box.get_children() {
+            self.list_box.remove(&row);
+        }
+        self.current_room = None;
+    }
+
+    /// Forget the scrollback of a room of an account that was logged out of.
+    pub fn remove_room(&mut self, account: InternalUserId, room_id: &RoomId) {
+        let key = (account, room_id.clone());
+        self.timelines.remove(&key);
+        self.paginating_rooms.remove(&key);
+        self.complete_rooms.remove(&key);
+
+        if self.current_room.as_ref() == Some(&key) {
+            self.clear();
+        }
+    }
+
+    pub fn append_message(&mut self, account: InternalUserId, room_id: RoomId, message: Message) {
+        let key = (account, room_id);
+        if self.current_room.as_ref() == Some(&key) {
+            self.list_box.insert(&message_row(&message), -1);
+        }
+
+        self.timelines.entry(key).or_insert_with(Vec::new).push(message);
+    }
+
+    /// Append an event from the server to the timeline of a room, replacing
+    /// the local echo of it if there is one.
+    pub fn append_timeline_event(
+        &mut self,
+        account: InternalUserId,
+        room_id: RoomId,
+        event: TimelineEvent,
+    ) {
+        let key = (account, room_id);
+        let position = self.timelines.get(&key).and_then(|messages| {
+            messages.iter().position(|message| {
+                message.event_id.as_ref() == Some(&event.event_id)
+                    || event
+                        .transaction_id
+                        .as_ref()
+                        .map_or(false, |txn_id| message.has_local_id(txn_id))
+            })
+        });
+
+        let message = Message::from_timeline_event(event);
+        match position {
+            Some(index) => self.replace_message(&key, index, message),
+            None => self.append_message(key.0, key.1, message),
+        }
+    }
+
+    /// The command to fetch older events of the current room, and the
+    /// account to send it for, if the list is scrolled close to the top and
+    /// they aren't being fetched already.
+    pub fn paginate(&mut self) -> Option<(InternalUserId, UserSpecificCommand)> {
+        let adjustment = self.adjustment.as_ref()?;
+        if adjustment.get_value() - adjustment.get_lower() > adjustment.get_page_size() {
+            return None;
+        }
+
+        let key = self.current_room.clone()?;
+        if self.complete_rooms.contains(&key) || !self.paginating_rooms.insert(key.clone()) {
+            return None;
+        }
+
+        let (account, room_id) = key;
+        Some((
+            account,
+            UserSpecificCommand::PaginateBackwards {
+                room_id,
+                limit: PAGINATION_LIMIT,
+            },
+        ))
+    }
+
+    /// Add older events to the start of a room's timeline, skipping those
+    /// that are already there.
+    pub fn prepend_timeline_events(
+        &mut self,
+        account: InternalUserId,
+        room_id: RoomId,
+        events: Vec<TimelineEvent>,
+        reached_start: bool,
+    ) {
+        let key = (account, room_id);
+        self.paginating_rooms.remove(&key);
+        if reached_start {
+            self.complete_rooms.insert(key.clone());
+        }
+
+        let messages = self.timelines.entry(key.clone()).or_insert_with(Vec::new);
+        let new_messages: Vec<_> = events
+            .into_iter()
+            .filter(|event| {
+                !messages
+                    .iter()
+                    .any(|message| message.event_id.as_ref() == Some(&event.event_id))
+            })
+            .map(Message::from_timeline_event)
+            .collect();
+
+        if self.current_room.as_ref() == Some(&key) && !new_messages.is_empty() {
+            if let Some(ref adjustment) = self.adjustment {
+                self.distance_from_bottom
+                    .set(Some(adjustment.get_upper() - adjustment.get_value()));
+            }
+
+            for (i, message) in new_messages.iter().enumerate() {
+                self.list_box.insert(&message_row(message), i as i32);
+            }
+        }
+
+        messages.splice(0..0, new_messages);
+    }
+
+    pub fn pagination_failed(&mut self, account: InternalUserId, room_id: &RoomId) {
+        self.paginating_rooms.remove(&(account, room_id.clone()));
+    }
+
+    /// Drop the events of a room before a gap in its timeline. Only messages
+    /// that weren't confirmed by the server are kept.
+    pub fn clear_timeline(&mut self, account: InternalUserId, room_id: &RoomId) {
+        let key = (account, room_id.clone());
+        self.complete_rooms.remove(&key);
+
+        if let Some(messages) = self.timelines.get_mut(&key) {
+            messages.retain(|message| message.event_id.is_none());
+        }
+
+        if self.current_room.as_ref() == Some(&key) {
+            self.reload();
+        }
+    }
+
+    pub fn set_local_echo_sent(
+        &mut self,
+        account: InternalUserId,
+        room_id: &RoomId,
+        local_id: &str,
+        event_id: EventId,
+    ) {
+        let key = (account, room_id.clone());
+        if let Some(index) = self.local_echo_index(&key, local_id) {
+            self.update_message(&key, index, |message| {
+                message.event_id = Some(event_id);
+                if let Some(ref mut local_echo) = message.local_echo {
+                    local_echo.state = LocalEchoState::Sent;
+                }
+            });
+        }
+    }
+
+    pub fn set_local_echo_failed(
+        &mut self,
+        account: InternalUserId,
+        room_id: &RoomId,
+        local_id: &str,
+    ) {
+        let key = (account, room_id.clone());
+        if let Some(index) = self.local_echo_index(&key, local_id) {
+            self.update_message(&key, index, |message| {
+                if let Some(ref mut local_echo) = message.local_echo {
+                    local_echo.state = LocalEchoState::Failed;
+                }
+            });
+        }
+    }
+
+    /// Mark a failed message in the current room as being sent again.
+    ///
+    /// Returns the account, room and body of the message, so it can be sent
+    /// again.
+    pub fn retry_local_echo(&mut self, local_id: &str) -> Option<(InternalUserId, RoomId, String)> {
+        let (key, index) = self.find_failed_local_echo(local_id)?;
+        let mut body = String::new();
+
+        self.update_message(&key, index, |message| {
+            body = message.body.clone();
+            if let Some(ref mut local_echo) = message.local_echo {
+                local_echo.state = LocalEchoState::Sending;
+            }
+        });
+
+        Some((key.0, key.1, body))
+    }
+
+    /// Remove a message in the current room that failed to send from the
+    /// timeline.
+    pub fn discard_local_echo(&mut self, local_id: &str) {
+        if let Some((key, index)) = self.find_failed_local_echo(local_id) {
+            if let Some(messages) = self.timelines.get_mut(&key) {
+                messages.remove(index);
+            }
+
+            if let Some(row) = self.list_box.get_row_at_index(index as i32) {
+                self.list_box.remove(&row);
+            }
+        }
+    }
+
+    fn local_echo_index(&self, key: &RoomKey, local_id: &str) -> Option<usize> {
+        self.timelines
+            .get(key)?
+            .iter()
+            .position(|message| message.has_local_id(local_id))
+    }
+
+    /// Only the current room is searched: its messages are the only ones
+    /// with buttons to retry or discard them, and local IDs are only unique
+    /// per account.
+    fn find_failed_local_echo(&self, local_id: &str) -> Option<(RoomKey, usize)> {
+        let key = self.current_room.clone()?;
+        let index = self.timelines.get(&key)?.iter().position(|message| {
+            message.local_echo.as_ref().map_or(false, |local_echo| {
+                local_echo.local_id == local_id && local_echo.state == LocalEchoState::Failed
+            })
+        })?;
+
+        Some((key, index))
+    }
+
+    fn replace_message(&mut self, key: &RoomKey, index: usize, message: Message) {
+        self.update_message(key, index, |old_message| *old_message = message);
+    }
+
+    /// Modify a message in place, updating its row if it is currently shown.
+    fn update_message<F>(&mut self, key: &RoomKey, index: usize, f: F)
+    where
+        F: FnOnce(&mut Message),
+    {
+        let message = match self
+            .timelines
+            .get_mut(key)
+            .and_then(|messages| messages.get_mut(index))
+        {
+            Some(message) => message,
+            None => return,
+        };
+
+        f(message);
+
+        if self.current_room.as_ref() == Some(key) {
+            if let Some(row) = self.list_box.get_row_at_index(index as i32) {
+                self.list_box.remove(&row);
+            }
+            self.list_box.insert(&message_row(message), index as i32);
+        }
+    }
+}
+
+fn message_row(message: &Message) -> gtk::ListBoxRow {
+    let row = gtk::ListBoxRow::new();
+    let vbox = gtk::Box::new(gtk::Orientation::Vertical, 3);
+    let header = gtk::Box::new(gtk::Orientation::Horizontal, 6);
+
+    let author_label = gtk::Label::new(None);
+    author_label.set_markup(&format!(
+        "<b>{}</b>",
+        glib::markup_escape_text(&message.author)
+    ));
+    author_label.set_xalign(0.0);
+    header.pack_start(&author_label, false, false, 0);
+
+    let time_label = gtk::Label::new(Some(
+        message.timestamp.format("%H:%M").to_string().as_str(),
+    ));
+    if let Some(style_context) = time_label.get_style_context() {
+        style_context.add_class("dim-label");
+    }
+    time_label.set_tooltip_text(Some(
+        message.timestamp.format("%Y-%m-%d %H:%M:%S").to_string().as_str(),
+    ));
+    header.pack_end(&time_label, false, false, 0);
+
+    let body_label = gtk::Label::new(Some(message.body.as_str()));
+    body_label.set_xalign(0.0);
+    body_label.set_line_wrap(true);
+    body_label.set_selectable(true);
+
+    vbox.pack_start(&header, false, false, 0);
+    vbox.pack_start(&body_label, false, false, 0);
+    vbox.set_border_width(6);
+
+    if let Some(ref local_echo) = message.local_echo {
+        match local_echo.state {
+            LocalEchoState::Sending => {
+                time_label.set_text("Sending…");
+                vbox.set_opacity(0.5);
+            }
+            LocalEchoState::Sent => {}
+            LocalEchoState::Failed => {
+                vbox.pack_start(&failed_message_bar(&local_echo.local_id), false, false, 0);
+            }
+        }
+    }
+
+    row.add(&vbox);
+    row.set_activatable(false);
+    row.show_all();
+    row
+}
+
+fn failed_message_bar(local_id: &str) -> gtk::Box {
+    let hbox = gtk::Box::new(gtk::Orientation::Horizontal, 6);
+
+    let label = gtk::Label::new(Some("Failed to send message."));
+    if let Some(style_context) = label.get_style_context() {
+        style_context.add_class("error");
+    }
+    hbox.pack_start(&label, false, false, 0);
+
+    // The buttons activate window actions with the local ID as the target, so
+    // the message view doesn't need to know how to talk to the backend.
+    let target = local_id.to_variant();
+
+    let discard_button = gtk::Button::new_with_label("Discard");
+    discard_button.set_action_name(Some("win.discard_message"));
+    discard_button.set_action_target_value(Some(&target));
+    hbox.pack_end(&discard_button, false, false, 0);
+
+    let retry_button = gtk::Button::new_with_label("Retry");
+    retry_button.set_action_name(Some("win.retry_message"));
+    retry_button.set_action_target_value(Some(&target));
+    hbox.pack_end(&retry_button, false, false, 0);
+
+    hbox
+}
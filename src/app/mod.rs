@@ -1,14 +1,48 @@
+mod account;
+mod account_switcher;
+mod connection_status;
+mod directory;
+mod invitation;
+mod invite;
 mod launch;
+mod member_list;
+mod message_view;
+mod room_list;
 
-use std::{self, env, thread, time::Duration};
+use std::{self, cell::RefCell, env, rc::Rc, thread, time::Duration};
 
 use futures::{self, Sink};
 use gio::{self, prelude::*};
 use glib;
 use gtk::{self, prelude::*};
-use ruma_identifiers::RoomId;
+use ruma_identifiers::{EventId, RoomId, UserId};
 
-use crate::bg_thread::{self, MatrixCommand};
+use self::{
+    account::AccountMenu,
+    account_switcher::AccountSwitcher,
+    connection_status::ConnectionStatus,
+    directory::DirectoryView,
+    invitation::{Invitation, InvitationView},
+    invite::InvitePopover,
+    member_list::MemberList,
+    message_view::{Message, MessageView},
+    room_list::{selected_room, RoomList},
+};
+use crate::bg_thread::{
+    self,
+    ConnectError,
+    ConnectionState,
+    DirectoryUser,
+    InternalUserId,
+    MatrixCommand,
+    PublicRoom,
+    RegistrationStage,
+    RoomSection,
+    RoomStateChange,
+    TimelineEvent,
+    TimelineEventContent,
+    UserSpecificCommand,
+};
 
 const APP_ID: &'static str = "org.fest-im.fest";
 
@@ -18,6 +52,183 @@ pub enum FrontendCommand {
         author_name: String,
         // [...]
         message_content: String,
+        /// Used to refer to this message until it's confirmed by the server.
+        local_id: String,
+    },
+    /// The server accepted a message that was previously displayed with
+    /// `DisplayTextMessage`.
+    LocalEchoSent {
+        room_id: RoomId,
+        local_id: String,
+        event_id: EventId,
+    },
+    LocalEchoFailed {
+        room_id: RoomId,
+        local_id: String,
+    },
+    RoomJoined {
+        room_id: RoomId,
+        display_name: String,
+        section: RoomSection,
+    },
+    RoomInvited {
+        room_id: RoomId,
+        display_name: String,
+        /// Who sent the invite, if known.
+        inviter: Option<UserId>,
+        inviter_name: Option<String>,
+        avatar_url: Option<String>,
+    },
+    RoomLeft {
+        room_id: RoomId,
+    },
+    RoomRenamed {
+        room_id: RoomId,
+        display_name: String,
+    },
+    RoomSectionChanged {
+        room_id: RoomId,
+        section: RoomSection,
+    },
+    RoomUnreadCountChanged {
+        room_id: RoomId,
+        notification_count: u64,
+        highlight_count: u64,
+    },
+    RoomStateChanged {
+        room_id: RoomId,
+        changes: Vec<RoomStateChange>,
+    },
+    /// Fetching the members of a room with `FetchMembers` failed.
+    MembersFetchFailed {
+        room_id: RoomId,
+    },
+    TimelineEventsAppended {
+        room_id: RoomId,
+        events: Vec<TimelineEvent>,
+    },
+    /// Older events of a room, requested with `PaginateBackwards`.
+    TimelineEventsPrepended {
+        room_id: RoomId,
+        /// Oldest first.
+        events: Vec<TimelineEvent>,
+        /// Whether there are no older events.
+        reached_start: bool,
+    },
+    PaginationFailed {
+        room_id: RoomId,
+    },
+    /// Events were skipped between those appended so far and the next ones.
+    /// The events before the gap are dropped, the older events are fetched
+    /// from the gap instead.
+    TimelineGap {
+        room_id: RoomId,
+    },
+    /// The user joined a room after requesting it with `JoinRoom`.
+    RoomJoinSucceeded {
+        room_id: RoomId,
+    },
+    RoomJoinFailed {
+        room_id_or_alias: String,
+        message: String,
+    },
+    RoomCreated {
+        room_id: RoomId,
+    },
+    RoomCreationFailed {
+        message: String,
+    },
+    RoomLeaveSucceeded {
+        room_id: RoomId,
+    },
+    RoomLeaveFailed {
+        room_id: RoomId,
+        message: String,
+    },
+    InviteSucceeded {
+        room_id: RoomId,
+        user_id: UserId,
+    },
+    InviteFailed {
+        room_id: RoomId,
+        user_id: UserId,
+        message: String,
+    },
+    UserDirectoryResults {
+        /// What was searched for.
+        search_term: String,
+        users: Vec<DirectoryUser>,
+    },
+    UserIgnored {
+        user_id: UserId,
+    },
+    IgnoreUserFailed {
+        user_id: UserId,
+        message: String,
+    },
+    ThumbnailFetched {
+        /// The `mxc://` URL of the image.
+        mxc_url: String,
+        data: Vec<u8>,
+    },
+    DirectoryResults {
+        generation: u32,
+        rooms: Vec<PublicRoom>,
+        /// Token for fetching more results, if there are any.
+        next_batch: Option<String>,
+        /// Whether the results continue the previous ones instead of
+        /// replacing them.
+        is_next_page: bool,
+    },
+    DirectoryFetchFailed {
+        generation: u32,
+        message: String,
+    },
+    /// `MatrixCommand::Connect` failed, the account can't be used.
+    ConnectFailed {
+        reason: ConnectError,
+    },
+    /// Single sign-on waits for the user to log in on the page at `url`.
+    SingleSignOnStarted {
+        url: String,
+    },
+    /// The user has to do something before the registration can continue.
+    ///
+    /// The registration waits until it is answered with
+    /// `UserSpecificCommand::AnswerRegistrationStage`.
+    RegistrationStage {
+        stage: RegistrationStage,
+        /// Why the previous attempt at this stage failed, if it did.
+        error: Option<String>,
+    },
+    RegistrationCompleted {
+        user_id: UserId,
+    },
+    /// The account is logged in, whether it was just added or restored from
+    /// a previous run. Sent before anything about its rooms.
+    Connected {
+        matrix_user_id: UserId,
+        device_id: String,
+        is_guest: bool,
+    },
+    ConnectionStateChanged {
+        state: ConnectionState,
+    },
+    /// The guest account was turned into a full account, which may have a
+    /// different user ID.
+    GuestUpgraded {
+        user_id: UserId,
+    },
+    /// Upgrading a guest account failed.
+    RegistrationFailed {
+        message: String,
+    },
+    /// An account was logged out of, and its rooms should be removed.
+    LoggedOut {
+        room_ids: Vec<RoomId>,
+        /// Set if the homeserver couldn't be told, so the access token may
+        /// still be valid.
+        server_error: Option<String>,
     },
 }
 
@@ -42,11 +253,35 @@ pub struct App {
     /// Long polling is required to receive messages from the rooms and so they have to
     /// run in separate threads.  In order to allow those threads to modify the gtk content,
     /// they will send commands to the main thread using this channel.
-    frontend_chan_rx: std::sync::mpsc::Receiver<FrontendCommand>,
+    ///
+    /// Every command is tagged with the account it is about.
+    frontend_chan_rx: std::sync::mpsc::Receiver<(InternalUserId, FrontendCommand)>,
 
     /// Matrix communication thread join handler used to clean up the tread when
     /// closing the application.
     bg_thread_join_handle: thread::JoinHandle<()>,
+
+    /// The scrollback of all rooms, shared with the UI callbacks.
+    message_view: Rc<RefCell<MessageView>>,
+
+    /// The members of all rooms, shared with the UI callbacks.
+    member_list: Rc<RefCell<MemberList>>,
+
+    /// The public room directory, shared with the UI callbacks.
+    directory_view: Rc<RefCell<DirectoryView>>,
+
+    /// The popover for inviting users, shared with the UI callbacks.
+    invite_popover: Rc<InvitePopover>,
+
+    /// Invitations to rooms, shared with the UI callbacks.
+    invitation_view: Rc<RefCell<InvitationView>>,
+
+    /// The forms for adding accounts, shared with the UI callbacks.
+    account_menu: Rc<AccountMenu>,
+
+    /// The connected accounts and which of them is selected, shared with
+    /// the UI callbacks.
+    account_switcher: Rc<AccountSwitcher>,
 }
 
 impl App {
@@ -68,11 +303,25 @@ impl App {
         let gtk_builder = gtk::Builder::new_from_resource("/org/fest-im/fest/main_window.glade");
 
         let (backend_chan_tx, backend_chan_rx) = futures::sync::mpsc::channel(1);
+        let message_view = Rc::new(RefCell::new(MessageView::new(&gtk_builder)));
+        let member_list = Rc::new(RefCell::new(MemberList::new(&gtk_builder)));
+        let directory_view = Rc::new(RefCell::new(DirectoryView::new(&gtk_builder)));
+        let invite_popover = Rc::new(InvitePopover::new(&gtk_builder));
+        let invitation_view = Rc::new(RefCell::new(InvitationView::new(&gtk_builder)));
+        let account_menu = Rc::new(AccountMenu::new(&gtk_builder));
+        let account_switcher = Rc::new(AccountSwitcher::new(&gtk_builder));
 
         launch::connect(
             gtk_app.clone(),
             gtk_builder.clone(),
             backend_chan_tx.clone(),
+            message_view.clone(),
+            member_list.clone(),
+            directory_view.clone(),
+            invite_popover.clone(),
+            invitation_view.clone(),
+            account_menu.clone(),
+            account_switcher.clone(),
         );
 
         // Create channel to allow the matrix connection thread to send closures to the main loop.
@@ -87,6 +336,13 @@ impl App {
             backend_chan_tx: backend_chan_tx.wait(),
             frontend_chan_rx,
             bg_thread_join_handle,
+            message_view,
+            member_list,
+            directory_view,
+            invite_popover,
+            invitation_view,
+            account_menu,
+            account_switcher,
         }
     }
 
@@ -94,15 +350,398 @@ impl App {
         // Poll the matrix communication thread channel and run the closures to allow
         // the threads to run actions in the main loop.
         let frontend_chan_rx = self.frontend_chan_rx;
+        let room_list = Rc::new(RefCell::new(RoomList::new(&self.gtk_builder)));
+        let connection_status = Rc::new(RefCell::new(ConnectionStatus::new(&self.gtk_builder)));
+        let message_view = self.message_view;
+        let member_list = self.member_list;
+        let directory_view = self.directory_view;
+        let invite_popover = self.invite_popover;
+        let invitation_view = self.invitation_view;
+        let account_menu = self.account_menu;
+        let account_switcher = self.account_switcher;
+        let backend_chan_tx = self.backend_chan_tx.get_ref().clone();
+        let window: gtk::ApplicationWindow = self
+            .gtk_builder
+            .get_object("main_window")
+            .expect("Couldn't find main_window in ui file.");
+        let rd_popover: gtk::Popover = self
+            .gtk_builder
+            .get_object("room_details_popover")
+            .expect("Couldn't find room details popover in ui file.");
+
+        let rvc_notif_revealer: gtk::Revealer = self
+            .gtk_builder
+            .get_object("rvc_notif_revealer")
+            .expect("Couldn't find chat notification revealer in ui file.");
+        let rvc_notif_label: gtk::Label = self
+            .gtk_builder
+            .get_object("rvc_notif_label")
+            .expect("Couldn't find chat notification label in ui file.");
+        let notify = move |message: &str| {
+            rvc_notif_label.set_text(message);
+            rvc_notif_revealer.set_reveal_child(true);
+        };
+
+        let rooms_tree_view: gtk::TreeView = self
+            .gtk_builder
+            .get_object("rooms_tree_view")
+            .expect("Couldn't find rooms tree view in ui file.");
+        rooms_tree_view
+            .get_selection()
+            .connect_changed(clone!(account_switcher, message_view, rooms_tree_view => move |_| {
+                // Only the rooms of the selected account are listed
+                let account = match account_switcher.current() {
+                    Some(account) => account,
+                    None => return,
+                };
+
+                if let Some((room_id, _)) = selected_room(&rooms_tree_view) {
+                    message_view.borrow_mut().show_room(account, room_id);
+                }
+            }));
+
+        // Show the rooms of the account selected in the account switcher
+        let main_window_stack: gtk::Stack = self
+            .gtk_builder
+            .get_object("main_window_stack")
+            .expect("Couldn't find main window stack in ui file.");
+        account_switcher.connect_changed(clone!(
+            account_switcher,
+            connection_status,
+            main_window_stack,
+            member_list,
+            message_view,
+            room_list,
+            rooms_tree_view,
+            window => move || {
+                room_list.borrow_mut().show_account(account_switcher.current());
+                connection_status
+                    .borrow_mut()
+                    .show_account(account_switcher.current());
+                if selected_room(&rooms_tree_view).is_none() {
+                    message_view.borrow_mut().clear();
+                    member_list.borrow_mut().clear();
+                }
+
+                // Update the title, which shows the selected room
+                if main_window_stack.get_visible_child_name() == Some("room_view".to_owned()) {
+                    if let Some(action) = window.lookup_action("show_room_view") {
+                        action.activate(None);
+                    }
+                }
+            }
+        ));
+
+        // Count down to the next attempt at syncing
+        gtk::timeout_add_seconds(
+            1,
+            clone!(connection_status => move || {
+                connection_status.borrow().update();
+                Continue(true)
+            }),
+        );
+
         gtk::idle_add(move || {
-            if let Ok(cmd) = frontend_chan_rx.recv_timeout(Duration::from_millis(5)) {
+            if let Ok((account, cmd)) = frontend_chan_rx.recv_timeout(Duration::from_millis(5)) {
                 match cmd {
                     FrontendCommand::DisplayTextMessage {
                         room_id,
                         author_name,
                         message_content,
+                        local_id,
+                    } => {
+                        message_view.borrow_mut().append_message(
+                            account,
+                            room_id,
+                            Message::local_echo(author_name, message_content, local_id),
+                        );
+                    }
+                    FrontendCommand::LocalEchoSent {
+                        room_id,
+                        local_id,
+                        event_id,
+                    } => {
+                        message_view.borrow_mut().set_local_echo_sent(
+                            account,
+                            &room_id,
+                            &local_id,
+                            event_id,
+                        );
+                    }
+                    FrontendCommand::LocalEchoFailed { room_id, local_id } => {
+                        message_view
+                            .borrow_mut()
+                            .set_local_echo_failed(account, &room_id, &local_id);
+                    }
+                    FrontendCommand::RoomJoined {
+                        room_id,
+                        display_name,
+                        section,
+                    } => {
+                        invitation_view
+                            .borrow_mut()
+                            .remove_invitation(account, &room_id);
+                        room_list
+                            .borrow_mut()
+                            .add_room(account, room_id, display_name, section);
+                    }
+                    FrontendCommand::RoomInvited {
+                        room_id,
+                        display_name,
+                        inviter,
+                        inviter_name,
+                        avatar_url,
+                    } => {
+                        invitation_view.borrow_mut().add_invitation(
+                            account,
+                            room_id.clone(),
+                            Invitation {
+                                display_name: display_name.clone(),
+                                inviter,
+                                inviter_name,
+                                avatar_url,
+                            },
+                        );
+                        room_list.borrow_mut().add_room(
+                            account,
+                            room_id,
+                            display_name,
+                            RoomSection::Invites,
+                        );
+                    }
+                    FrontendCommand::RoomLeft { room_id } => {
+                        invitation_view
+                            .borrow_mut()
+                            .remove_invitation(account, &room_id);
+                        room_list.borrow_mut().remove_room(account, &room_id);
+                    }
+                    FrontendCommand::RoomRenamed {
+                        room_id,
+                        display_name,
+                    } => {
+                        invitation_view.borrow_mut().rename_invitation(
+                            account,
+                            &room_id,
+                            &display_name,
+                        );
+                        room_list
+                            .borrow_mut()
+                            .rename_room(account, &room_id, display_name);
+                    }
+                    FrontendCommand::RoomSectionChanged { room_id, section } => {
+                        room_list.borrow_mut().move_room(account, &room_id, section);
+                    }
+                    FrontendCommand::RoomUnreadCountChanged {
+                        room_id,
+                        notification_count,
+                        highlight_count,
+                    } => {
+                        room_list.borrow_mut().set_unread_count(
+                            account,
+                            &room_id,
+                            notification_count,
+                            highlight_count,
+                        );
+                    }
+                    FrontendCommand::RoomStateChanged { room_id, changes } => {
+                        member_list
+                            .borrow_mut()
+                            .apply_changes(account, &room_id, &changes);
+                    }
+                    FrontendCommand::MembersFetchFailed { room_id } => {
+                        member_list.borrow_mut().fetch_failed(account, &room_id);
+                    }
+                    FrontendCommand::TimelineEventsAppended { room_id, events } => {
+                        member_list.borrow_mut().apply_changes(
+                            account,
+                            &room_id,
+                            events.iter().filter_map(|event| match event.content {
+                                TimelineEventContent::StateChange(ref change) => Some(change),
+                                _ => None,
+                            }),
+                        );
+
+                        let mut message_view = message_view.borrow_mut();
+                        for event in events {
+                            message_view.append_timeline_event(account, room_id.clone(), event);
+                        }
+                    }
+                    FrontendCommand::TimelineEventsPrepended {
+                        room_id,
+                        events,
+                        reached_start,
                     } => {
-                        // TODO!
+                        message_view.borrow_mut().prepend_timeline_events(
+                            account,
+                            room_id,
+                            events,
+                            reached_start,
+                        );
+                    }
+                    FrontendCommand::PaginationFailed { room_id } => {
+                        message_view.borrow_mut().pagination_failed(account, &room_id);
+                    }
+                    FrontendCommand::TimelineGap { room_id } => {
+                        message_view.borrow_mut().clear_timeline(account, &room_id);
+                    }
+                    FrontendCommand::RoomJoinSucceeded { room_id } => {
+                        room_list.borrow_mut().select_room(account, room_id);
+                    }
+                    FrontendCommand::RoomJoinFailed {
+                        room_id_or_alias,
+                        message,
+                    } => {
+                        notify(&format!("Couldn't join {}: {}", room_id_or_alias, message));
+                    }
+                    FrontendCommand::RoomCreated { room_id } => {
+                        room_list.borrow_mut().select_room(account, room_id);
+                    }
+                    FrontendCommand::RoomCreationFailed { message } => {
+                        notify(&format!("Couldn't create the room: {}", message));
+                    }
+                    FrontendCommand::RoomLeaveSucceeded { room_id } => {
+                        let room_list = room_list.borrow();
+                        let name = room_list
+                            .display_name(account, &room_id)
+                            .unwrap_or("the room");
+                        notify(&format!("You left {}.", name));
+                    }
+                    FrontendCommand::RoomLeaveFailed { room_id, message } => {
+                        let room_list = room_list.borrow();
+                        let name = room_list
+                            .display_name(account, &room_id)
+                            .unwrap_or("the room");
+                        notify(&format!("Couldn't leave {}: {}", name, message));
+                    }
+                    FrontendCommand::InviteSucceeded { room_id, user_id } => {
+                        invite_popover.invite_succeeded();
+                        rd_popover.hide();
+
+                        let room_list = room_list.borrow();
+                        let name = room_list
+                            .display_name(account, &room_id)
+                            .unwrap_or("the room");
+                        notify(&format!("Invited {} to {}.", user_id, name));
+                    }
+                    FrontendCommand::InviteFailed {
+                        room_id: _,
+                        user_id,
+                        message,
+                    } => {
+                        // Shown in the popover, where the user can correct
+                        // the user ID and try again
+                        invite_popover.invite_failed(&user_id, &message);
+                    }
+                    FrontendCommand::UserDirectoryResults { search_term, users } => {
+                        invite_popover.show_search_results(&search_term, users);
+                    }
+                    FrontendCommand::UserIgnored { user_id } => {
+                        notify(&format!("You are now ignoring {}.", user_id));
+                    }
+                    FrontendCommand::IgnoreUserFailed { user_id, message } => {
+                        notify(&format!("Couldn't ignore {}: {}", user_id, message));
+                    }
+                    FrontendCommand::ThumbnailFetched { mxc_url, data } => {
+                        invitation_view.borrow_mut().set_avatar(mxc_url, &data);
+                    }
+                    FrontendCommand::DirectoryResults {
+                        generation,
+                        rooms,
+                        next_batch,
+                        is_next_page,
+                    } => {
+                        directory_view.borrow_mut().show_results(
+                            generation,
+                            rooms,
+                            next_batch,
+                            is_next_page,
+                        );
+                    }
+                    FrontendCommand::DirectoryFetchFailed {
+                        generation,
+                        message,
+                    } => {
+                        directory_view.borrow_mut().show_error(generation, &message);
+                    }
+                    FrontendCommand::ConnectFailed { reason } => {
+                        account_menu.connect_failed(&reason);
+                    }
+                    FrontendCommand::SingleSignOnStarted { url } => {
+                        account_menu.sso_started(&url);
+                    }
+                    FrontendCommand::RegistrationStage { stage, error } => {
+                        let backend_chan_tx = backend_chan_tx.clone();
+                        account_menu.show_registration_stage(stage, error, move |response| {
+                            // TODO: Do we want to handle send errors?
+                            let _ = backend_chan_tx.clone().wait().send(
+                                MatrixCommand::UserSpecificCommand {
+                                    user_id: account,
+                                    command: UserSpecificCommand::AnswerRegistrationStage(
+                                        response,
+                                    ),
+                                },
+                            );
+                        });
+                    }
+                    FrontendCommand::RegistrationCompleted { user_id } => {
+                        account_menu.registration_completed();
+                        if let Some(action) = window.lookup_action("show_room_view") {
+                            action.activate(None);
+                        }
+                        notify(&format!("Welcome, {}!", user_id));
+                    }
+                    FrontendCommand::RegistrationFailed { message } => {
+                        account_menu.registration_failed(&message);
+                    }
+                    FrontendCommand::Connected {
+                        matrix_user_id,
+                        device_id,
+                        is_guest,
+                    } => {
+                        info!("Connected as {} on device {}", matrix_user_id, device_id);
+                        account_menu.login_succeeded();
+                        account_switcher.add_account(account, &matrix_user_id, is_guest);
+                        if account_menu.is_greeter_shown() {
+                            if let Some(action) = window.lookup_action("show_room_view") {
+                                action.activate(None);
+                            }
+                        }
+                    }
+                    FrontendCommand::ConnectionStateChanged { state } => {
+                        connection_status.borrow_mut().set_state(account, state);
+                    }
+                    FrontendCommand::GuestUpgraded { user_id } => {
+                        account_switcher.update_account(account, &user_id, false);
+                        account_menu.registration_completed();
+                        notify(&format!("Your account is now registered as {}.", user_id));
+                    }
+                    FrontendCommand::LoggedOut {
+                        room_ids,
+                        server_error,
+                    } => {
+                        room_list.borrow_mut().remove_account(account);
+                        connection_status.borrow_mut().remove_account(account);
+                        for room_id in &room_ids {
+                            invitation_view
+                                .borrow_mut()
+                                .remove_invitation(account, room_id);
+                            message_view.borrow_mut().remove_room(account, room_id);
+                            member_list.borrow_mut().remove_room(account, room_id);
+                        }
+
+                        account_switcher.remove_account(account);
+                        if account_switcher.is_empty() {
+                            if let Some(action) = window.lookup_action("show_greeter") {
+                                action.activate(None);
+                            }
+                        }
+
+                        if let Some(e) = server_error {
+                            account_menu.login_failed(&format!(
+                                "You were logged out, but the homeserver couldn't be told: {}",
+                                e
+                            ));
+                        }
                     }
                 }
             }
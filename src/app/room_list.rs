@@ -0,0 +1,359 @@
+use std::{collections::HashMap, convert::TryFrom};
+
+use gtk::{self, prelude::*};
+use ruma_identifiers::RoomId;
+
+use crate::bg_thread::{InternalUserId, RoomSection};
+
+// Columns of the models shown in rooms_tree_view
+const NAME_COLUMN: u32 = 0;
+const ROOM_ID_COLUMN: u32 = 1;
+const UNREAD_COLUMN: u32 = 2;
+const WEIGHT_COLUMN: u32 = 3;
+
+const WEIGHT_NORMAL: i32 = 400;
+const WEIGHT_BOLD: i32 = 700;
+
+/// All sections, in the order they are shown in.
+const SECTIONS: [RoomSection; 5] = [
+    RoomSection::Invites,
+    RoomSection::Favourites,
+    RoomSection::DirectChats,
+    RoomSection::Rooms,
+    RoomSection::LowPriority,
+];
+
+fn section_title(section: RoomSection) -> &'static str {
+    match section {
+        RoomSection::Favourites => "Favourites",
+        RoomSection::DirectChats => "Direct Chats",
+        RoomSection::Rooms => "Rooms",
+        RoomSection::Invites => "Invites",
+        RoomSection::LowPriority => "Low Priority",
+    }
+}
+
+struct RoomEntry {
+    iter: gtk::TreeIter,
+    section: RoomSection,
+    display_name: String,
+    notification_count: u64,
+    highlight_count: u64,
+}
+
+/// The rooms of all accounts, shown in rooms_tree_view one account at a time.
+pub(super) struct RoomList {
+    view: gtk::TreeView,
+    accounts: HashMap<InternalUserId, AccountRooms>,
+}
+
+impl RoomList {
+    pub fn new(gtk_builder: &gtk::Builder) -> Self {
+        RoomList {
+            view: gtk_builder
+                .get_object("rooms_tree_view")
+                .expect("Couldn't find rooms tree view in ui file."),
+            accounts: HashMap::new(),
+        }
+    }
+
+    /// Show the rooms of `user_id`, or nothing if no account is selected.
+    pub fn show_account(&mut self, user_id: Option<InternalUserId>) {
+        match user_id {
+            Some(user_id) => self.account(user_id).show(),
+            None => self.view.set_model(None::<&gtk::TreeModel>),
+        }
+    }
+
+    /// Forget the rooms of an account that was logged out of.
+    pub fn remove_account(&mut self, user_id: InternalUserId) {
+        if let Some(account) = self.accounts.remove(&user_id) {
+            if account.is_shown() {
+                self.view.set_model(None::<&gtk::TreeModel>);
+            }
+        }
+    }
+
+    pub fn add_room(
+        &mut self,
+        user_id: InternalUserId,
+        room_id: RoomId,
+        display_name: String,
+        section: RoomSection,
+    ) {
+        self.account(user_id).add_room(room_id, display_name, section);
+    }
+
+    pub fn select_room(&mut self, user_id: InternalUserId, room_id: RoomId) {
+        self.account(user_id).select_room(room_id);
+    }
+
+    pub fn display_name(&self, user_id: InternalUserId, room_id: &RoomId) -> Option<&str> {
+        self.accounts.get(&user_id)?.display_name(room_id)
+    }
+
+    pub fn remove_room(&mut self, user_id: InternalUserId, room_id: &RoomId) {
+        self.account(user_id).remove_room(room_id);
+    }
+
+    pub fn rename_room(
+        &mut self,
+        user_id: InternalUserId,
+        room_id: &RoomId,
+        display_name: String,
+    ) {
+        self.account(user_id).rename_room(room_id, display_name);
+    }
+
+    pub fn move_room(&mut self, user_id: InternalUserId, room_id: &RoomId, section: RoomSection) {
+        self.account(user_id).move_room(room_id, section);
+    }
+
+    pub fn set_unread_count(
+        &mut self,
+        user_id: InternalUserId,
+        room_id: &RoomId,
+        notification_count: u64,
+        highlight_count: u64,
+    ) {
+        self.account(user_id)
+            .set_unread_count(room_id, notification_count, highlight_count);
+    }
+
+    fn account(&mut self, user_id: InternalUserId) -> &mut AccountRooms {
+        let view = &self.view;
+        self.accounts
+            .entry(user_id)
+            .or_insert_with(|| AccountRooms::new(view.clone()))
+    }
+}
+
+/// The rooms of one account, with their own model for rooms_tree_view.
+///
+/// Rooms are grouped into sections, which are only shown when they contain at
+/// least one room.
+struct AccountRooms {
+    store: gtk::TreeStore,
+    view: gtk::TreeView,
+    sections: HashMap<RoomSection, gtk::TreeIter>,
+    rooms: HashMap<RoomId, RoomEntry>,
+    /// A room to select as soon as it is added, or the account is shown.
+    pending_selection: Option<RoomId>,
+}
+
+impl AccountRooms {
+    fn new(view: gtk::TreeView) -> Self {
+        AccountRooms {
+            store: gtk::TreeStore::new(&[
+                gtk::Type::String,
+                gtk::Type::String,
+                gtk::Type::String,
+                gtk::Type::I32,
+            ]),
+            view,
+            sections: HashMap::new(),
+            rooms: HashMap::new(),
+            pending_selection: None,
+        }
+    }
+
+    fn is_shown(&self) -> bool {
+        self.view.get_model() == Some(self.store.clone().upcast::<gtk::TreeModel>())
+    }
+
+    fn show(&mut self) {
+        if self.is_shown() {
+            return;
+        }
+
+        self.view.set_model(Some(&self.store));
+        // Sections are expanded by default
+        self.view.expand_all();
+
+        if let Some(room_id) = self.pending_selection.take() {
+            self.select_room(room_id);
+        }
+    }
+
+    fn add_room(&mut self, room_id: RoomId, display_name: String, section: RoomSection) {
+        if self.rooms.contains_key(&room_id) {
+            self.move_room(&room_id, section);
+            self.rename_room(&room_id, display_name);
+            return;
+        }
+
+        let iter = self.insert_row(&room_id, &display_name, section);
+        self.rooms.insert(
+            room_id.clone(),
+            RoomEntry {
+                iter,
+                section,
+                display_name,
+                notification_count: 0,
+                highlight_count: 0,
+            },
+        );
+
+        if self.pending_selection.as_ref() == Some(&room_id) {
+            self.pending_selection = None;
+            self.select_room(room_id);
+        }
+    }
+
+    /// Select a room, or remember to select it when it is added if it isn't
+    /// in the list yet (e.g. because it was just joined and sync hasn't
+    /// caught up), or when the account is shown.
+    fn select_room(&mut self, room_id: RoomId) {
+        match self.rooms.get(&room_id) {
+            Some(entry) if self.is_shown() => {
+                self.view.get_selection().select_iter(&entry.iter);
+            }
+            _ => self.pending_selection = Some(room_id),
+        }
+    }
+
+    fn display_name(&self, room_id: &RoomId) -> Option<&str> {
+        self.rooms
+            .get(room_id)
+            .map(|entry| entry.display_name.as_str())
+    }
+
+    fn remove_room(&mut self, room_id: &RoomId) {
+        if let Some(entry) = self.rooms.remove(room_id) {
+            self.store.remove(&entry.iter);
+            self.remove_section_if_empty(entry.section);
+        }
+    }
+
+    fn rename_room(&mut self, room_id: &RoomId, display_name: String) {
+        if let Some(entry) = self.rooms.get_mut(room_id) {
+            self.store
+                .set_value(&entry.iter, NAME_COLUMN, &display_name.to_value());
+            entry.display_name = display_name;
+        }
+    }
+
+    fn move_room(&mut self, room_id: &RoomId, section: RoomSection) {
+        let (old_section, display_name, notification_count, highlight_count) =
+            match self.rooms.get(room_id) {
+                Some(entry) if entry.section != section => (
+                    entry.section,
+                    entry.display_name.clone(),
+                    entry.notification_count,
+                    entry.highlight_count,
+                ),
+                _ => return,
+            };
+
+        if let Some(entry) = self.rooms.remove(room_id) {
+            self.store.remove(&entry.iter);
+        }
+        self.remove_section_if_empty(old_section);
+
+        let iter = self.insert_row(room_id, &display_name, section);
+        self.rooms.insert(
+            room_id.clone(),
+            RoomEntry {
+                iter,
+                section,
+                display_name,
+                notification_count: 0,
+                highlight_count: 0,
+            },
+        );
+        self.set_unread_count(room_id, notification_count, highlight_count);
+    }
+
+    fn set_unread_count(
+        &mut self,
+        room_id: &RoomId,
+        notification_count: u64,
+        highlight_count: u64,
+    ) {
+        if let Some(entry) = self.rooms.get_mut(room_id) {
+            let unread = if notification_count > 0 {
+                notification_count.to_string()
+            } else {
+                String::new()
+            };
+            let weight = if highlight_count > 0 {
+                WEIGHT_BOLD
+            } else {
+                WEIGHT_NORMAL
+            };
+
+            self.store
+                .set_value(&entry.iter, UNREAD_COLUMN, &unread.to_value());
+            self.store
+                .set_value(&entry.iter, WEIGHT_COLUMN, &weight.to_value());
+
+            entry.notification_count = notification_count;
+            entry.highlight_count = highlight_count;
+        }
+    }
+
+    fn insert_row(
+        &mut self,
+        room_id: &RoomId,
+        display_name: &str,
+        section: RoomSection,
+    ) -> gtk::TreeIter {
+        let section_iter = self.section_iter(section);
+        let iter = self.store.insert_with_values(
+            Some(&section_iter),
+            None,
+            &[NAME_COLUMN, ROOM_ID_COLUMN, UNREAD_COLUMN, WEIGHT_COLUMN],
+            &[&display_name, &room_id.to_string(), &"", &WEIGHT_NORMAL],
+        );
+
+        // Sections are expanded by default
+        if let Some(path) = self.store.get_path(&section_iter).filter(|_| self.is_shown()) {
+            self.view.expand_row(&path, false);
+        }
+
+        iter
+    }
+
+    /// Get the row of a section, creating it if necessary.
+    fn section_iter(&mut self, section: RoomSection) -> gtk::TreeIter {
+        if let Some(iter) = self.sections.get(&section) {
+            return iter.clone();
+        }
+
+        let position = SECTIONS
+            .iter()
+            .take_while(|&&s| s != section)
+            .filter(|s| self.sections.contains_key(s))
+            .count();
+
+        let iter = self.store.insert_with_values(
+            None,
+            Some(position as u32),
+            &[NAME_COLUMN, ROOM_ID_COLUMN, UNREAD_COLUMN, WEIGHT_COLUMN],
+            &[&section_title(section), &"", &"", &WEIGHT_BOLD],
+        );
+        self.sections.insert(section, iter.clone());
+
+        iter
+    }
+
+    fn remove_section_if_empty(&mut self, section: RoomSection) {
+        if self.rooms.values().any(|entry| entry.section == section) {
+            return;
+        }
+
+        if let Some(iter) = self.sections.remove(&section) {
+            self.store.remove(&iter);
+        }
+    }
+}
+
+/// Get the ID and name of the room selected in `view`, if any.
+pub(super) fn selected_room(view: &gtk::TreeView) -> Option<(RoomId, String)> {
+    let (model, iter) = view.get_selection().get_selected()?;
+    let room_id = model.get_value(&iter, ROOM_ID_COLUMN as i32).get::<String>()?;
+    let name = model.get_value(&iter, NAME_COLUMN as i32).get::<String>()?;
+
+    // Section rows don't have a room ID
+    RoomId::try_from(room_id.as_str()).ok().map(|room_id| (room_id, name))
+}
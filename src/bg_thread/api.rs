@@ -0,0 +1,1144 @@
+// Access to endpoints that ruma-client doesn't support (yet), or where we
+// need more details about errors than it gives us.
+
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    error::Error as StdError,
+    fmt,
+    io,
+    time::Duration,
+};
+
+use futures::{
+    prelude::{async, await},
+    Future,
+    Stream,
+};
+use hyper::{
+    self,
+    client::HttpConnector,
+    header::{AUTHORIZATION, CONTENT_TYPE},
+    Body,
+    Method,
+    StatusCode,
+};
+use hyper_tls::HttpsConnector;
+use native_tls;
+use ruma_client::api::r0::sync::sync_events;
+use ruma_events::{collections::all::RoomEvent, room::member::MemberEvent};
+use ruma_identifiers::{RoomAliasId, RoomId, UserId};
+use serde::de::DeserializeOwned;
+use serde_json::{self, Value as JsonValue};
+use url::Url;
+
+pub type HttpClient = hyper::Client<HttpsConnector<HttpConnector>>;
+
+const UNKNOWN_TOKEN: &str = "M_UNKNOWN_TOKEN";
+
+/// How long the homeserver may hold back a sync response while waiting for
+/// something new to happen.
+const SYNC_TIMEOUT_MS: u32 = 30_000;
+
+#[derive(Debug)]
+pub enum Error {
+    Hyper(hyper::Error),
+    Http(hyper::http::Error),
+    Json(serde_json::Error),
+    /// The server answered with an error status.
+    Matrix {
+        status: StatusCode,
+        /// The `errcode` of the response, if it is a matrix error response.
+        errcode: Option<String>,
+        error: Option<String>,
+        body: JsonValue,
+    },
+    /// An `mxc://` URL that doesn't point to any content.
+    InvalidContentUrl(String),
+    /// A URL that doesn't point to a homeserver's client-server API.
+    InvalidHomeserverUrl(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Hyper(ref e) => write!(f, "{}", e),
+            Error::Http(ref e) => write!(f, "{}", e),
+            Error::Json(ref e) => write!(f, "invalid JSON: {}", e),
+            Error::Matrix {
+                status,
+                ref errcode,
+                ref error,
+                ..
+            } => match (errcode, error) {
+                (Some(errcode), Some(error)) => write!(f, "{} ({})", error, errcode),
+                (Some(errcode), None) => write!(f, "{}", errcode),
+                _ => write!(f, "server responded with {}", status),
+            },
+            Error::InvalidContentUrl(ref url) => write!(f, "invalid content URL: {}", url),
+            Error::InvalidHomeserverUrl(ref url) => write!(f, "no homeserver found at {}", url),
+        }
+    }
+}
+
+impl From<hyper::Error> for Error {
+    fn from(e: hyper::Error) -> Self {
+        Error::Hyper(e)
+    }
+}
+
+impl From<hyper::http::Error> for Error {
+    fn from(e: hyper::http::Error) -> Self {
+        Error::Http(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Json(e)
+    }
+}
+
+/// Why connecting an account failed, in a form the greeter can explain to the
+/// user.
+#[derive(Debug)]
+pub enum ConnectError {
+    /// The homeserver didn't accept the user name and password, or the
+    /// access token.
+    BadCredentials,
+    /// The homeserver couldn't be found or didn't answer.
+    Unreachable(String),
+    /// There were too many attempts recently.
+    RateLimited {
+        /// How long to wait before trying again, if the homeserver said.
+        retry_after_ms: Option<u64>,
+    },
+    /// No secure connection to the homeserver could be established, e.g.
+    /// because its certificate is not valid.
+    Tls(String),
+    /// The homeserver answered, but failed to handle the request.
+    Server(String),
+    /// The homeserver doesn't support what was tried.
+    Unsupported(String),
+    /// The user cancelled, so there is nothing to report.
+    Cancelled,
+    Other(String),
+}
+
+impl ConnectError {
+    /// Like `ConnectError::from`, but for requests where a forbidden response
+    /// means that the credentials are wrong.
+    pub fn from_login_error(e: Error) -> Self {
+        match e {
+            Error::Matrix {
+                errcode: Some(ref errcode),
+                ..
+            } if errcode == "M_FORBIDDEN" => ConnectError::BadCredentials,
+            e => e.into(),
+        }
+    }
+}
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConnectError::BadCredentials => {
+                write!(f, "The homeserver didn't accept the user name or password.")
+            }
+            ConnectError::Unreachable(ref details) => {
+                write!(f, "Couldn't reach the homeserver: {}", details)
+            }
+            ConnectError::Server(ref details) => {
+                write!(f, "The homeserver has a problem: {}", details)
+            }
+            ConnectError::RateLimited {
+                retry_after_ms: Some(ms),
+            } => write!(
+                f,
+                "Too many attempts. Try again in {} seconds.",
+                (ms + 999) / 1000
+            ),
+            ConnectError::RateLimited { retry_after_ms: None } => {
+                write!(f, "Too many attempts. Try again later.")
+            }
+            ConnectError::Tls(ref details) => write!(
+                f,
+                "Couldn't establish a secure connection to the homeserver: {}",
+                details
+            ),
+            ConnectError::Unsupported(ref message) | ConnectError::Other(ref message) => {
+                write!(f, "{}", message)
+            }
+            ConnectError::Cancelled => write!(f, "Cancelled."),
+        }
+    }
+}
+
+impl From<Error> for ConnectError {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Hyper(ref e) if is_tls_error(e) => ConnectError::Tls(e.to_string()),
+            Error::Hyper(_) | Error::InvalidHomeserverUrl(_) => {
+                ConnectError::Unreachable(e.to_string())
+            }
+            Error::Matrix {
+                status,
+                ref errcode,
+                ref body,
+                ..
+            } => match errcode.as_ref().map(String::as_str) {
+                Some(UNKNOWN_TOKEN) => ConnectError::BadCredentials,
+                Some("M_LIMIT_EXCEEDED") => ConnectError::RateLimited {
+                    retry_after_ms: body["retry_after_ms"].as_u64(),
+                },
+                Some("M_UNRECOGNIZED") => ConnectError::Unsupported(e.to_string()),
+                _ if status.is_server_error() => ConnectError::Server(e.to_string()),
+                Some(_) => ConnectError::Other(e.to_string()),
+                // Not a matrix error, so probably not the homeserver answering
+                None => ConnectError::Unreachable(e.to_string()),
+            },
+            _ => ConnectError::Other(e.to_string()),
+        }
+    }
+}
+
+/// Whether establishing the TLS connection failed.
+///
+/// hyper_tls hands the errors of the TLS backend to hyper wrapped in an
+/// `io::Error`.
+fn is_tls_error(e: &hyper::Error) -> bool {
+    let mut cause = e.cause2().map(|e| e as &(dyn StdError + 'static));
+    while let Some(e) = cause {
+        if e.is::<native_tls::Error>() {
+            return true;
+        }
+        if let Some(inner) = e.downcast_ref::<io::Error>().and_then(io::Error::get_ref) {
+            if inner.is::<native_tls::Error>() {
+                return true;
+            }
+        }
+        cause = e.source();
+    }
+
+    false
+}
+
+/// Whether a request failed because the access token is no longer valid,
+/// e.g. because the device was logged out elsewhere.
+pub fn is_unknown_token(e: &Error) -> bool {
+    match *e {
+        Error::Matrix {
+            errcode: Some(ref errcode),
+            ..
+        } => errcode == UNKNOWN_TOKEN,
+        _ => false,
+    }
+}
+
+/// How long the homeserver asked to wait before the next request, if it
+/// rejected one because of rate limiting.
+pub fn retry_after(e: &Error) -> Option<Duration> {
+    match *e {
+        Error::Matrix {
+            errcode: Some(ref errcode),
+            ref body,
+            ..
+        } if errcode == "M_LIMIT_EXCEEDED" => {
+            body["retry_after_ms"].as_u64().map(Duration::from_millis)
+        }
+        _ => None,
+    }
+}
+
+pub fn http_client() -> HttpClient {
+    // Only fails if the TLS backend can't be initialized, in which case
+    // ruma_client::Client::https would have failed before as well.
+    let connector = HttpsConnector::new(4).expect("Failed to initialize TLS backend");
+    hyper::Client::builder().keep_alive(true).build(connector)
+}
+
+/// Build the URL of a client-server API endpoint.
+///
+/// `path` is a list of path segments below `/_matrix/client/`, which are
+/// percent-encoded as necessary.
+pub fn endpoint_url(homeserver_url: &Url, path: &[&str]) -> Result<Url, Error> {
+    let mut url = homeserver_url.clone();
+    url.path_segments_mut()
+        .map_err(|()| Error::InvalidHomeserverUrl(homeserver_url.to_string()))?
+        .pop_if_empty()
+        .extend(&["_matrix", "client"])
+        .extend(path);
+    Ok(url)
+}
+
+fn build_request(
+    method: Method,
+    url: &Url,
+    access_token: Option<String>,
+    body: Option<JsonValue>,
+) -> Result<hyper::Request<Body>, Error> {
+    let mut request_builder = hyper::Request::builder();
+    request_builder.method(method).uri(url.as_str());
+
+    if let Some(access_token) = access_token {
+        request_builder.header(AUTHORIZATION, format!("Bearer {}", access_token));
+    }
+
+    Ok(match body {
+        Some(body) => request_builder
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(serde_json::to_vec(&body)?))?,
+        None => request_builder.body(Body::empty())?,
+    })
+}
+
+/// Send a request to a homeserver and parse the JSON response.
+#[async]
+pub fn request(
+    http_client: HttpClient,
+    method: Method,
+    url: Url,
+    access_token: Option<String>,
+    body: Option<JsonValue>,
+) -> Result<JsonValue, Error> {
+    let request = build_request(method, &url, access_token, body)?;
+    let response = await!(http_client.request(request))?;
+    let status = response.status();
+    let body = await!(response.into_body().concat2())?;
+
+    let json = if body.is_empty() {
+        JsonValue::Null
+    } else if status.is_success() {
+        serde_json::from_slice(&body)?
+    } else {
+        // Error responses don't necessarily come from the homeserver, they
+        // might as well be HTML pages of a reverse proxy
+        serde_json::from_slice(&body).unwrap_or(JsonValue::Null)
+    };
+
+    if status.is_success() {
+        Ok(json)
+    } else {
+        Err(Error::Matrix {
+            status,
+            errcode: json["errcode"].as_str().map(ToOwned::to_owned),
+            error: json["error"].as_str().map(ToOwned::to_owned),
+            body: json,
+        })
+    }
+}
+
+/// Look up the base URL of a server's client-server API in its
+/// `/.well-known/matrix/client` file.
+///
+/// Returns `None` if the server doesn't have that file.
+#[async]
+pub fn get_well_known(http_client: HttpClient, server_name: String) -> Result<Option<Url>, Error> {
+    let url = format!("https://{}/.well-known/matrix/client", server_name);
+    let url = Url::parse(&url).map_err(|_| Error::InvalidHomeserverUrl(url))?;
+
+    let response = match await!(request(http_client, Method::GET, url, None, None)) {
+        Ok(response) => response,
+        Err(Error::Matrix { status, .. }) if status == StatusCode::NOT_FOUND => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    let base_url = response["m.homeserver"]["base_url"]
+        .as_str()
+        .ok_or_else(|| Error::InvalidHomeserverUrl(server_name.clone()))?;
+
+    match Url::parse(base_url) {
+        Ok(ref url) if url.scheme() != "https" && url.scheme() != "http" => {
+            Err(Error::InvalidHomeserverUrl(base_url.to_owned()))
+        }
+        Ok(url) => Ok(Some(url)),
+        Err(_) => Err(Error::InvalidHomeserverUrl(base_url.to_owned())),
+    }
+}
+
+/// Get the versions of the client-server API a homeserver supports.
+///
+/// Useful to check whether there is a homeserver at `homeserver_url` at all.
+#[async]
+pub fn get_versions(http_client: HttpClient, homeserver_url: Url) -> Result<Vec<String>, Error> {
+    let response = await!(request(
+        http_client,
+        Method::GET,
+        endpoint_url(&homeserver_url, &["versions"])?,
+        None,
+        None,
+    ))
+    .map_err(|e| match e {
+        // Most likely not a homeserver, but some other web server
+        Error::Json(_) | Error::Matrix { .. } => {
+            Error::InvalidHomeserverUrl(homeserver_url.to_string())
+        }
+        e => e,
+    })?;
+
+    match response["versions"].as_array() {
+        Some(versions) => Ok(versions
+            .iter()
+            .filter_map(|version| version.as_str().map(ToOwned::to_owned))
+            .collect()),
+        None => Err(Error::InvalidHomeserverUrl(homeserver_url.to_string())),
+    }
+}
+
+/// Get the ways of logging in a homeserver supports, like `m.login.password`.
+#[async]
+pub fn get_login_types(http_client: HttpClient, homeserver_url: Url) -> Result<Vec<String>, Error> {
+    let response = await!(request(
+        http_client,
+        Method::GET,
+        endpoint_url(&homeserver_url, &["r0", "login"])?,
+        None,
+        None,
+    ))?;
+
+    Ok(response["flows"]
+        .as_array()
+        .map(|flows| {
+            flows
+                .iter()
+                .filter_map(|flow| flow["type"].as_str().map(ToOwned::to_owned))
+                .collect()
+        })
+        .unwrap_or_default())
+}
+
+/// Log in with a user name and password, reusing `device_id` if it is set.
+///
+/// Returns the response, which contains the new session.
+#[async]
+pub fn log_in_with_password(
+    http_client: HttpClient,
+    homeserver_url: Url,
+    username: String,
+    password: String,
+    device_id: Option<String>,
+    initial_device_display_name: String,
+) -> Result<JsonValue, Error> {
+    let mut body = json!({
+        "type": "m.login.password",
+        "identifier": {
+            "type": "m.id.user",
+            "user": username,
+        },
+        // Deprecated, but older homeservers only understand this
+        "user": username,
+        "password": password,
+        "initial_device_display_name": initial_device_display_name,
+    });
+    if let Some(device_id) = device_id {
+        body["device_id"] = json!(device_id);
+    }
+
+    await!(request(
+        http_client,
+        Method::POST,
+        endpoint_url(&homeserver_url, &["r0", "login"])?,
+        None,
+        Some(body),
+    ))
+}
+
+/// Log in with a token the homeserver handed out after single sign-on.
+///
+/// Without a `device_id`, the homeserver creates a new device. Returns the
+/// response, which contains the new session.
+#[async]
+pub fn log_in_with_token(
+    http_client: HttpClient,
+    homeserver_url: Url,
+    token: String,
+    device_id: Option<String>,
+    initial_device_display_name: String,
+) -> Result<JsonValue, Error> {
+    let mut body = json!({
+        "type": "m.login.token",
+        "token": token,
+        "initial_device_display_name": initial_device_display_name,
+    });
+    if let Some(device_id) = device_id {
+        body["device_id"] = json!(device_id);
+    }
+
+    await!(request(
+        http_client,
+        Method::POST,
+        endpoint_url(&homeserver_url, &["r0", "login"])?,
+        None,
+        Some(body),
+    ))
+}
+
+/// The page to send the user to for single sign-on.
+///
+/// After logging in, the homeserver redirects to `redirect_url` with a
+/// `loginToken` query parameter for `log_in_with_token`.
+pub fn sso_redirect_url(homeserver_url: &Url, redirect_url: &Url) -> Result<Url, Error> {
+    let mut url = endpoint_url(homeserver_url, &["r0", "login", "sso", "redirect"])?;
+    url.query_pairs_mut()
+        .append_pair("redirectUrl", redirect_url.as_str());
+    Ok(url)
+}
+
+/// Invalidate the access token, or all access tokens of the user if
+/// `all_devices` is set.
+#[async]
+pub fn log_out(
+    http_client: HttpClient,
+    homeserver_url: Url,
+    access_token: String,
+    all_devices: bool,
+) -> Result<(), Error> {
+    let path: &[&str] = if all_devices {
+        &["r0", "logout", "all"]
+    } else {
+        &["r0", "logout"]
+    };
+
+    await!(request(
+        http_client,
+        Method::POST,
+        endpoint_url(&homeserver_url, path)?,
+        Some(access_token),
+        Some(json!({})),
+    ))?;
+
+    Ok(())
+}
+
+/// The `summary` of a joined room in a sync response, which names the members
+/// to show the room as and counts all members, since with lazy-loaded members
+/// the others aren't known. Only what changed since the last sync response is
+/// included.
+#[derive(Debug, Default, Deserialize)]
+pub struct RoomSummary {
+    #[serde(rename = "m.heroes")]
+    pub heroes: Option<Vec<UserId>>,
+    #[serde(rename = "m.joined_member_count")]
+    pub joined_member_count: Option<u64>,
+    #[serde(rename = "m.invited_member_count")]
+    pub invited_member_count: Option<u64>,
+}
+
+/// The parts of a sync response we look at.
+#[derive(Debug)]
+pub struct SyncResponse {
+    pub next_batch: String,
+    pub account_data: sync_events::AccountData,
+    pub rooms: sync_events::Rooms,
+    /// The summaries of joined rooms, which ruma_client doesn't know about.
+    pub room_summaries: HashMap<RoomId, RoomSummary>,
+}
+
+/// Get what happened since the sync response `since` came from, or the
+/// current state of everything if it is `None`.
+#[async]
+pub fn sync(
+    http_client: HttpClient,
+    homeserver_url: Url,
+    access_token: String,
+    filter_id: Option<String>,
+    since: Option<String>,
+) -> Result<SyncResponse, Error> {
+    let mut url = endpoint_url(&homeserver_url, &["r0", "sync"])?;
+    {
+        let mut query = url.query_pairs_mut();
+        query.append_pair("timeout", &SYNC_TIMEOUT_MS.to_string());
+        if let Some(ref filter_id) = filter_id {
+            query.append_pair("filter", filter_id);
+        }
+        if let Some(ref since) = since {
+            query.append_pair("since", since);
+        }
+    }
+
+    let mut response = await!(request(
+        http_client,
+        Method::GET,
+        url,
+        Some(access_token),
+        None,
+    ))?;
+
+    let mut room_summaries = HashMap::new();
+    if let Some(joined_rooms) = response["rooms"]["join"].as_object_mut() {
+        for (room_id, joined_room) in joined_rooms {
+            let summary = match joined_room.get_mut("summary") {
+                Some(summary) => summary.take(),
+                None => continue,
+            };
+            match (RoomId::try_from(room_id.as_str()), serde_json::from_value(summary)) {
+                (Ok(room_id), Ok(summary)) => {
+                    room_summaries.insert(room_id, summary);
+                }
+                _ => warn!("Skipping invalid summary of room {}", room_id),
+            }
+        }
+    }
+
+    Ok(SyncResponse {
+        next_batch: serde_json::from_value(response["next_batch"].take())?,
+        account_data: serde_json::from_value(response["account_data"].take())?,
+        rooms: serde_json::from_value(response["rooms"].take())?,
+        room_summaries,
+    })
+}
+
+/// Upload a filter to refer to in sync requests, returning its ID.
+#[async]
+pub fn upload_filter(
+    http_client: HttpClient,
+    homeserver_url: Url,
+    access_token: String,
+    user_id: UserId,
+    filter: JsonValue,
+) -> Result<String, Error> {
+    let response = await!(request(
+        http_client,
+        Method::POST,
+        endpoint_url(&homeserver_url, &["r0", "user", &user_id.to_string(), "filter"])?,
+        Some(access_token),
+        Some(filter),
+    ))?;
+
+    Ok(serde_json::from_value(response["filter_id"].clone())?)
+}
+
+#[derive(Debug, Deserialize)]
+struct MembersChunk {
+    chunk: Vec<JsonValue>,
+}
+
+/// Get the `m.room.member` events of all current members of a room, which
+/// sync responses leave out when members are lazy-loaded.
+#[async]
+pub fn get_members(
+    http_client: HttpClient,
+    homeserver_url: Url,
+    access_token: String,
+    room_id: RoomId,
+) -> Result<Vec<MemberEvent>, Error> {
+    let response = await!(request(
+        http_client,
+        Method::GET,
+        endpoint_url(&homeserver_url, &["r0", "rooms", &room_id.to_string(), "members"])?,
+        Some(access_token),
+        None,
+    ))?;
+
+    let members: MembersChunk = serde_json::from_value(response)?;
+    Ok(parse_events(members.chunk))
+}
+
+#[derive(Debug, Deserialize)]
+struct MessagesChunk {
+    chunk: Vec<JsonValue>,
+    end: Option<String>,
+    #[serde(default)]
+    state: Vec<JsonValue>,
+}
+
+/// A page of a room's history.
+pub struct Messages {
+    /// The events, newest first.
+    pub events: Vec<RoomEvent>,
+    /// The `m.room.member` events of the senders, if members are
+    /// lazy-loaded.
+    pub members: Vec<MemberEvent>,
+    /// The token to get the page before this one, `None` once the start of
+    /// the room is reached.
+    pub end: Option<String>,
+}
+
+/// Get up to `limit` events of a room that are older than the pagination
+/// token `from`. `filter` is a room event filter like in sync filters.
+#[async]
+pub fn get_messages(
+    http_client: HttpClient,
+    homeserver_url: Url,
+    access_token: String,
+    room_id: RoomId,
+    from: String,
+    limit: u32,
+    filter: JsonValue,
+) -> Result<Messages, Error> {
+    let mut url = endpoint_url(
+        &homeserver_url,
+        &["r0", "rooms", &room_id.to_string(), "messages"],
+    )?;
+    url.query_pairs_mut()
+        .append_pair("from", &from)
+        .append_pair("dir", "b")
+        .append_pair("limit", &limit.to_string())
+        .append_pair("filter", &filter.to_string());
+
+    let response = await!(request(
+        http_client,
+        Method::GET,
+        url,
+        Some(access_token),
+        None,
+    ))?;
+
+    let messages: MessagesChunk = serde_json::from_value(response)?;
+    Ok(Messages {
+        // Some servers keep returning a token after the start of the room,
+        // just without any events
+        end: messages.end.filter(|_| !messages.chunk.is_empty()),
+        events: parse_events(messages.chunk),
+        members: parse_events(messages.state),
+    })
+}
+
+/// Parse the events of a response, skipping (and logging) those that can't
+/// be parsed instead of failing the whole request.
+fn parse_events<T: DeserializeOwned>(events: Vec<JsonValue>) -> Vec<T> {
+    events
+        .into_iter()
+        .filter_map(|event| {
+            serde_json::from_value(event)
+                .map_err(|e| warn!("Skipping invalid event: {}", e))
+                .ok()
+        })
+        .collect()
+}
+
+/// Set the human-readable name of one of the user's devices.
+#[async]
+pub fn set_device_display_name(
+    http_client: HttpClient,
+    homeserver_url: Url,
+    access_token: String,
+    device_id: String,
+    display_name: String,
+) -> Result<(), Error> {
+    await!(request(
+        http_client,
+        Method::PUT,
+        endpoint_url(&homeserver_url, &["r0", "devices", &device_id])?,
+        Some(access_token),
+        Some(json!({ "display_name": display_name })),
+    ))?;
+
+    Ok(())
+}
+
+/// An entry in the public room directory.
+#[derive(Clone, Debug, Deserialize)]
+pub struct PublicRoom {
+    pub room_id: RoomId,
+    pub name: Option<String>,
+    pub canonical_alias: Option<RoomAliasId>,
+    #[serde(default)]
+    pub aliases: Vec<RoomAliasId>,
+    pub topic: Option<String>,
+    pub num_joined_members: u64,
+    pub avatar_url: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PublicRoomsChunk {
+    pub chunk: Vec<PublicRoom>,
+    /// Token for fetching the next page, if there is one.
+    pub next_batch: Option<String>,
+    pub total_room_count_estimate: Option<u64>,
+}
+
+/// Get a page of the public room directory of `server` (or the directory of
+/// the homeserver itself, if `server` is `None`).
+#[async]
+pub fn get_public_rooms(
+    http_client: HttpClient,
+    homeserver_url: Url,
+    access_token: String,
+    server: Option<String>,
+    search_term: Option<String>,
+    since: Option<String>,
+    limit: u32,
+) -> Result<PublicRoomsChunk, Error> {
+    let mut url = endpoint_url(&homeserver_url, &["r0", "publicRooms"])?;
+    if let Some(server) = server {
+        url.query_pairs_mut().append_pair("server", &server);
+    }
+
+    let mut body = json!({ "limit": limit });
+    if let Some(since) = since {
+        body["since"] = json!(since);
+    }
+    if let Some(search_term) = search_term {
+        body["filter"] = json!({ "generic_search_term": search_term });
+    }
+
+    let response = await!(request(
+        http_client,
+        Method::POST,
+        url,
+        Some(access_token),
+        Some(body),
+    ))?;
+
+    Ok(serde_json::from_value(response)?)
+}
+
+/// Join a room by ID or alias.
+///
+/// `server_names` are servers to try joining through, which is necessary for
+/// joining by ID a room none of the homeserver's users are in.
+#[async]
+pub fn join_room(
+    http_client: HttpClient,
+    homeserver_url: Url,
+    access_token: String,
+    room_id_or_alias: String,
+    server_names: Vec<String>,
+) -> Result<RoomId, Error> {
+    let mut url = endpoint_url(&homeserver_url, &["r0", "join", &room_id_or_alias])?;
+    for server_name in &server_names {
+        url.query_pairs_mut().append_pair("server_name", server_name);
+    }
+
+    let response = await!(request(
+        http_client,
+        Method::POST,
+        url,
+        Some(access_token),
+        Some(json!({})),
+    ))?;
+
+    Ok(serde_json::from_value(response["room_id"].clone())?)
+}
+
+/// Whether a new room is published in the homeserver's room directory.
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RoomVisibility {
+    Public,
+    Private,
+}
+
+/// The set of initial state events the homeserver creates a room with.
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RoomPreset {
+    PrivateChat,
+    PublicChat,
+    TrustedPrivateChat,
+}
+
+/// Create a new room.
+///
+/// `alias` is only the localpart of the alias, the homeserver appends its own
+/// server name.
+#[async]
+pub fn create_room(
+    http_client: HttpClient,
+    homeserver_url: Url,
+    access_token: String,
+    name: Option<String>,
+    alias: Option<String>,
+    visibility: RoomVisibility,
+    preset: RoomPreset,
+) -> Result<RoomId, Error> {
+    let mut body = json!({
+        "visibility": visibility,
+        "preset": preset,
+    });
+    if let Some(name) = name {
+        body["name"] = json!(name);
+    }
+    if let Some(alias) = alias {
+        body["room_alias_name"] = json!(alias);
+    }
+
+    let response = await!(request(
+        http_client,
+        Method::POST,
+        endpoint_url(&homeserver_url, &["r0", "createRoom"])?,
+        Some(access_token),
+        Some(body),
+    ))?;
+
+    Ok(serde_json::from_value(response["room_id"].clone())?)
+}
+
+#[async]
+pub fn leave_room(
+    http_client: HttpClient,
+    homeserver_url: Url,
+    access_token: String,
+    room_id: RoomId,
+) -> Result<(), Error> {
+    await!(request(
+        http_client,
+        Method::POST,
+        endpoint_url(&homeserver_url, &["r0", "rooms", &room_id.to_string(), "leave"])?,
+        Some(access_token),
+        Some(json!({})),
+    ))?;
+
+    Ok(())
+}
+
+#[async]
+pub fn invite_user(
+    http_client: HttpClient,
+    homeserver_url: Url,
+    access_token: String,
+    room_id: RoomId,
+    user_id: UserId,
+) -> Result<(), Error> {
+    await!(request(
+        http_client,
+        Method::POST,
+        endpoint_url(&homeserver_url, &["r0", "rooms", &room_id.to_string(), "invite"])?,
+        Some(access_token),
+        Some(json!({ "user_id": user_id })),
+    ))?;
+
+    Ok(())
+}
+
+/// An entry in the user directory.
+#[derive(Clone, Debug, Deserialize)]
+pub struct DirectoryUser {
+    pub user_id: UserId,
+    pub display_name: Option<String>,
+    pub avatar_url: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct UserDirectoryResults {
+    results: Vec<DirectoryUser>,
+}
+
+/// Search the homeserver's user directory, which contains the users that
+/// share a room with the current user and those in public rooms.
+#[async]
+pub fn search_user_directory(
+    http_client: HttpClient,
+    homeserver_url: Url,
+    access_token: String,
+    search_term: String,
+    limit: u32,
+) -> Result<Vec<DirectoryUser>, Error> {
+    let response = await!(request(
+        http_client,
+        Method::POST,
+        endpoint_url(&homeserver_url, &["r0", "user_directory", "search"])?,
+        Some(access_token),
+        Some(json!({ "search_term": search_term, "limit": limit })),
+    ))?;
+
+    let results: UserDirectoryResults = serde_json::from_value(response)?;
+    Ok(results.results)
+}
+
+fn ignored_user_list_url(homeserver_url: &Url, user_id: &UserId) -> Result<Url, Error> {
+    endpoint_url(
+        homeserver_url,
+        &[
+            "r0",
+            "user",
+            &user_id.to_string(),
+            "account_data",
+            "m.ignored_user_list",
+        ],
+    )
+}
+
+#[derive(Debug, Deserialize)]
+struct IgnoredUserList {
+    ignored_users: HashMap<UserId, JsonValue>,
+}
+
+/// Get the list of users whose events the homeserver hides from us.
+#[async]
+pub fn get_ignored_users(
+    http_client: HttpClient,
+    homeserver_url: Url,
+    access_token: String,
+    user_id: UserId,
+) -> Result<Vec<UserId>, Error> {
+    let result = await!(request(
+        http_client,
+        Method::GET,
+        ignored_user_list_url(&homeserver_url, &user_id)?,
+        Some(access_token),
+        None,
+    ));
+
+    match result {
+        Ok(response) => {
+            let list: IgnoredUserList = serde_json::from_value(response)?;
+            Ok(list.ignored_users.into_iter().map(|(user_id, _)| user_id).collect())
+        }
+        // Nobody was ever ignored
+        Err(Error::Matrix { status, .. }) if status == StatusCode::NOT_FOUND => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Replace the list of users whose events the homeserver hides from us.
+#[async]
+pub fn set_ignored_users(
+    http_client: HttpClient,
+    homeserver_url: Url,
+    access_token: String,
+    user_id: UserId,
+    ignored_users: Vec<UserId>,
+) -> Result<(), Error> {
+    let ignored_users: serde_json::Map<_, _> = ignored_users
+        .into_iter()
+        .map(|user_id| (user_id.to_string(), json!({})))
+        .collect();
+
+    await!(request(
+        http_client,
+        Method::PUT,
+        ignored_user_list_url(&homeserver_url, &user_id)?,
+        Some(access_token),
+        Some(json!({ "ignored_users": ignored_users })),
+    ))?;
+
+    Ok(())
+}
+
+/// Download a thumbnail of an image from the content repository.
+///
+/// `mxc_url` is the `mxc://<server>/<media id>` URL of the image.
+#[async]
+pub fn get_thumbnail(
+    http_client: HttpClient,
+    homeserver_url: Url,
+    mxc_url: String,
+    width: u32,
+    height: u32,
+) -> Result<Vec<u8>, Error> {
+    let (server_name, media_id) = match Url::parse(&mxc_url) {
+        Ok(ref url) if url.scheme() == "mxc" => (
+            url.host_str().unwrap_or("").to_owned(),
+            url.path().trim_start_matches('/').to_owned(),
+        ),
+        _ => return Err(Error::InvalidContentUrl(mxc_url)),
+    };
+
+    // Media endpoints are not below /_matrix/client
+    let mut url = homeserver_url.clone();
+    url.path_segments_mut()
+        .map_err(|()| Error::InvalidHomeserverUrl(homeserver_url.to_string()))?
+        .pop_if_empty()
+        .extend(&["_matrix", "media", "r0", "thumbnail", &server_name, &media_id]);
+    url.query_pairs_mut()
+        .append_pair("width", &width.to_string())
+        .append_pair("height", &height.to_string())
+        .append_pair("method", "crop");
+
+    // Media is served without authentication
+    let request = build_request(Method::GET, &url, None, None)?;
+    let response = await!(http_client.request(request))?;
+    let status = response.status();
+    let body = await!(response.into_body().concat2())?;
+
+    if status.is_success() {
+        Ok(body.to_vec())
+    } else {
+        let json: JsonValue = serde_json::from_slice(&body).unwrap_or(JsonValue::Null);
+        Err(Error::Matrix {
+            status,
+            errcode: json["errcode"].as_str().map(ToOwned::to_owned),
+            error: json["error"].as_str().map(ToOwned::to_owned),
+            body: json,
+        })
+    }
+}
+
+/// Send a request to the registration endpoint.
+///
+/// Registration uses user-interactive authentication, so the first attempts
+/// usually fail with a 401 response, whose body describes the stages to
+/// complete. That body is available as `Error::Matrix::body`.
+#[async]
+pub fn register(
+    http_client: HttpClient,
+    homeserver_url: Url,
+    kind: &'static str,
+    body: JsonValue,
+) -> Result<JsonValue, Error> {
+    let mut url = endpoint_url(&homeserver_url, &["r0", "register"])?;
+    url.query_pairs_mut().append_pair("kind", kind);
+
+    await!(request(http_client, Method::POST, url, None, Some(body)))
+}
+
+/// Ask the identity server to send a validation email for registering an
+/// account with `email`.
+///
+/// Returns the session ID to include in the authentication data once the
+/// email address is validated.
+#[async]
+pub fn request_registration_email_token(
+    http_client: HttpClient,
+    homeserver_url: Url,
+    id_server: String,
+    client_secret: String,
+    email: String,
+    send_attempt: u32,
+) -> Result<String, Error> {
+    let response = await!(request(
+        http_client,
+        Method::POST,
+        endpoint_url(&homeserver_url, &["r0", "register", "email", "requestToken"])?,
+        None,
+        Some(json!({
+            "id_server": id_server,
+            "client_secret": client_secret,
+            "email": email,
+            "send_attempt": send_attempt,
+        })),
+    ))?;
+
+    Ok(serde_json::from_value(response["sid"].clone())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sso_redirect_url_escapes_redirect_url() {
+        let homeserver_url = Url::parse("https://example.org").unwrap();
+        let redirect_url = Url::parse("http://127.0.0.1:4321/").unwrap();
+
+        assert_eq!(
+            sso_redirect_url(&homeserver_url, &redirect_url).unwrap().as_str(),
+            "https://example.org/_matrix/client/r0/login/sso/redirect\
+             ?redirectUrl=http%3A%2F%2F127.0.0.1%3A4321%2F"
+        );
+    }
+
+    #[test]
+    fn sso_redirect_url_below_path() {
+        let homeserver_url = Url::parse("https://example.org/matrix/").unwrap();
+        let redirect_url = Url::parse("http://127.0.0.1:4321/").unwrap();
+
+        assert_eq!(
+            sso_redirect_url(&homeserver_url, &redirect_url).unwrap().path(),
+            "/matrix/_matrix/client/r0/login/sso/redirect"
+        );
+    }
+
+    #[test]
+    fn sso_redirect_url_invalid_homeserver_url() {
+        let homeserver_url = Url::parse("data:text/plain,matrix").unwrap();
+        let redirect_url = Url::parse("http://127.0.0.1:4321/").unwrap();
+
+        match sso_redirect_url(&homeserver_url, &redirect_url) {
+            Err(Error::InvalidHomeserverUrl(_)) => {}
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+}
@@ -0,0 +1,203 @@
+use std::{cmp, time::Duration};
+
+use ring::rand::{SecureRandom, SystemRandom};
+
+use super::{api, ConnectionState};
+
+/// The delay before the first retry, in milliseconds.
+const INITIAL_DELAY: u64 = 1000;
+
+/// The longest delay between retries, in milliseconds.
+const MAX_DELAY: u64 = 5 * 60 * 1000;
+
+/// Exponentially growing delays between attempts at something that keeps
+/// failing, like syncing while the homeserver is down.
+///
+/// The delays are randomized, so that clients which lost their connection at
+/// the same time don't all come back at once.
+pub struct Backoff {
+    failures: u32,
+}
+
+impl Backoff {
+    pub fn new() -> Self {
+        Backoff { failures: 0 }
+    }
+
+    /// Start over with short delays once an attempt succeeded.
+    pub fn reset(&mut self) {
+        self.failures = 0;
+    }
+
+    /// The delay before the next attempt. Its upper bound doubles with each
+    /// failure, and it is chosen randomly between half of that and all of it.
+    pub fn next_delay(&mut self) -> Duration {
+        // Anything shifted further would be capped anyway
+        let max_delay = (INITIAL_DELAY << self.failures.min(16)).min(MAX_DELAY);
+        self.failures = self.failures.saturating_add(1);
+
+        let mut bytes = [0; 4];
+        let jitter = match SystemRandom::new().fill(&mut bytes) {
+            Ok(()) => {
+                let random = bytes.iter().fold(0, |n, &b| n << 8 | u64::from(b));
+                random % (max_delay / 2 + 1)
+            }
+            // Not random, but still a valid delay
+            Err(_) => 0,
+        };
+
+        Duration::from_millis(max_delay - jitter)
+    }
+}
+
+/// The connection state after a request failed with `e`, where `delay` is
+/// the next delay of the `Backoff`.
+pub fn state_after_error(e: &api::Error, delay: Duration) -> ConnectionState {
+    if api::is_unknown_token(e) {
+        error!("The access token is no longer valid");
+        return ConnectionState::LoggedOut;
+    }
+
+    match *e {
+        api::Error::Hyper(ref e) if e.is_connect() => {
+            warn!("Can't reach the homeserver: {}", e);
+            ConnectionState::Offline { delay }
+        }
+        _ => match api::retry_after(e) {
+            Some(retry_after) => {
+                warn!("The homeserver limits the rate of requests: {}", e);
+                ConnectionState::Reconnecting {
+                    delay: cmp::max(delay, retry_after),
+                }
+            }
+            None => {
+                warn!("The homeserver has problems: {}", e);
+                ConnectionState::Reconnecting { delay }
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::StatusCode;
+
+    use super::*;
+
+    fn matrix_error(status: u16, errcode: Option<&str>, body: serde_json::Value) -> api::Error {
+        api::Error::Matrix {
+            status: StatusCode::from_u16(status).unwrap(),
+            errcode: errcode.map(ToOwned::to_owned),
+            error: None,
+            body,
+        }
+    }
+
+    fn millis(duration: Duration) -> u64 {
+        duration.as_secs() * 1000 + u64::from(duration.subsec_millis())
+    }
+
+    #[test]
+    fn delays_grow_exponentially() {
+        let mut backoff = Backoff::new();
+
+        for failures in 0..8 {
+            let max_delay = INITIAL_DELAY << failures;
+            let delay = millis(backoff.next_delay());
+            assert!(delay >= max_delay / 2 && delay <= max_delay, "{} ms", delay);
+        }
+    }
+
+    #[test]
+    fn delays_are_capped() {
+        let mut backoff = Backoff::new();
+        for _ in 0..100 {
+            let delay = millis(backoff.next_delay());
+            assert!(delay <= MAX_DELAY, "{} ms", delay);
+        }
+
+        let delay = millis(backoff.next_delay());
+        assert!(delay >= MAX_DELAY / 2, "{} ms", delay);
+    }
+
+    #[test]
+    fn delays_are_jittered() {
+        // At the cap, the jitter can take more than 100000 values
+        let mut backoff = Backoff::new();
+        for _ in 0..20 {
+            backoff.next_delay();
+        }
+
+        let first = backoff.next_delay();
+        assert!((0..64).any(|_| backoff.next_delay() != first));
+    }
+
+    #[test]
+    fn reset_starts_over() {
+        let mut backoff = Backoff::new();
+        for _ in 0..10 {
+            backoff.next_delay();
+        }
+
+        backoff.reset();
+        assert!(millis(backoff.next_delay()) <= INITIAL_DELAY);
+    }
+
+    #[test]
+    fn unknown_token_means_logged_out() {
+        let e = matrix_error(401, Some("M_UNKNOWN_TOKEN"), json!({}));
+        assert_eq!(
+            state_after_error(&e, Duration::from_secs(1)),
+            ConnectionState::LoggedOut
+        );
+    }
+
+    #[test]
+    fn retry_after_overrides_shorter_delay() {
+        let e = matrix_error(
+            429,
+            Some("M_LIMIT_EXCEEDED"),
+            json!({ "retry_after_ms": 120_000 }),
+        );
+        assert_eq!(
+            state_after_error(&e, Duration::from_secs(1)),
+            ConnectionState::Reconnecting {
+                delay: Duration::from_secs(120)
+            }
+        );
+    }
+
+    #[test]
+    fn retry_after_keeps_longer_delay() {
+        let e = matrix_error(
+            429,
+            Some("M_LIMIT_EXCEEDED"),
+            json!({ "retry_after_ms": 500 }),
+        );
+        assert_eq!(
+            state_after_error(&e, Duration::from_secs(4)),
+            ConnectionState::Reconnecting {
+                delay: Duration::from_secs(4)
+            }
+        );
+
+        let e = matrix_error(429, Some("M_LIMIT_EXCEEDED"), json!({}));
+        assert_eq!(
+            state_after_error(&e, Duration::from_secs(4)),
+            ConnectionState::Reconnecting {
+                delay: Duration::from_secs(4)
+            }
+        );
+    }
+
+    #[test]
+    fn server_errors_mean_reconnecting() {
+        let e = matrix_error(502, None, serde_json::Value::Null);
+        assert_eq!(
+            state_after_error(&e, Duration::from_secs(8)),
+            ConnectionState::Reconnecting {
+                delay: Duration::from_secs(8)
+            }
+        );
+    }
+}
@@ -0,0 +1,626 @@
+// A local copy of what we know about the rooms of an account: their state,
+// members and most recent timeline events. It is shown right away on startup,
+// while syncing continues where the previous run stopped.
+
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryFrom,
+    fmt,
+    fs,
+    io,
+};
+
+use ruma_identifiers::{self, RoomId, UserId};
+use rusqlite::{self, types::ToSql, Connection, OptionalExtension, NO_PARAMS};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json;
+
+use super::{
+    rooms::{Member, Room, Rooms, TimelineChunk, TimelineEvent},
+    session_store::{self, account_dir},
+};
+
+const CACHE_FILE_NAME: &str = "cache.sqlite3";
+
+/// How many of the most recent timeline events are kept per room.
+const MAX_EVENTS_PER_ROOM: i64 = 200;
+
+/// The schema changes, in order. The number of migrations applied so far is
+/// stored as the database's `user_version`.
+const MIGRATIONS: &[&str] = &[
+    // Everything is stored as JSON, except for what is looked up by.
+    "CREATE TABLE account (
+        key TEXT PRIMARY KEY,
+        value TEXT NOT NULL
+    );
+    CREATE TABLE rooms (
+        room_id TEXT PRIMARY KEY,
+        summary TEXT NOT NULL
+    );
+    CREATE TABLE members (
+        room_id TEXT NOT NULL,
+        user_id TEXT NOT NULL,
+        member TEXT NOT NULL,
+        PRIMARY KEY (room_id, user_id)
+    );
+    CREATE TABLE timeline_events (
+        room_id TEXT NOT NULL,
+        position INTEGER NOT NULL,
+        event TEXT NOT NULL,
+        PRIMARY KEY (room_id, position)
+    );",
+    // The token to fetch the events before an event, stored with the first
+    // event of each chunk. Older events can't be fetched without it, so they
+    // are dropped.
+    "ALTER TABLE timeline_events ADD COLUMN prev_batch TEXT;
+    DELETE FROM timeline_events;",
+];
+
+#[derive(Debug)]
+pub enum Error {
+    Session(session_store::Error),
+    Io(io::Error),
+    Sqlite(rusqlite::Error),
+    Json(serde_json::Error),
+    InvalidId(ruma_identifiers::Error),
+    /// The cache was written by a newer version of fest.
+    UnknownVersion(i64),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Session(ref e) => write!(f, "{}", e),
+            Error::Io(ref e) => write!(f, "I/O error: {}", e),
+            Error::Sqlite(ref e) => write!(f, "database error: {}", e),
+            Error::Json(ref e) => write!(f, "invalid cached data: {}", e),
+            Error::InvalidId(ref e) => write!(f, "invalid cached ID: {:?}", e),
+            Error::UnknownVersion(version) => write!(f, "unknown cache version {}", version),
+        }
+    }
+}
+
+impl From<session_store::Error> for Error {
+    fn from(e: session_store::Error) -> Self {
+        Error::Session(e)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Self {
+        Error::Sqlite(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Json(e)
+    }
+}
+
+impl From<ruma_identifiers::Error> for Error {
+    fn from(e: ruma_identifiers::Error) -> Self {
+        Error::InvalidId(e)
+    }
+}
+
+/// Everything that was loaded from the cache.
+pub struct CachedData {
+    pub rooms: Rooms,
+    /// The cached timeline events of each room, oldest first.
+    pub timelines: HashMap<RoomId, Vec<TimelineEvent>>,
+    /// The `next_batch` token of the sync response the cache is up to date
+    /// with.
+    pub next_batch: String,
+}
+
+pub struct Cache {
+    conn: Connection,
+}
+
+impl Cache {
+    /// Open the cache of an account, creating it if necessary.
+    pub fn open(user_id: &UserId) -> Result<Self, Error> {
+        let dir = account_dir(user_id)?;
+        fs::create_dir_all(&dir)?;
+
+        Self::with_connection(Connection::open(dir.join(CACHE_FILE_NAME))?)
+    }
+
+    fn with_connection(mut conn: Connection) -> Result<Self, Error> {
+        migrate(&mut conn)?;
+        Ok(Cache { conn })
+    }
+
+    /// Load everything that was cached, if anything was.
+    pub fn load(&self) -> Result<Option<CachedData>, Error> {
+        let next_batch = match self.account_value("next_batch")? {
+            Some(next_batch) => next_batch,
+            None => return Ok(None),
+        };
+        let direct_rooms = self.account_value("direct_rooms")?.unwrap_or_default();
+
+        let mut rooms = HashMap::new();
+        let mut stmt = self.conn.prepare("SELECT room_id, summary FROM rooms")?;
+        let rows = stmt.query_map(NO_PARAMS, |row| {
+            (row.get::<_, String>(0), row.get::<_, String>(1))
+        })?;
+        for row in rows {
+            let (room_id, summary) = row?;
+            let room: Room = serde_json::from_str(&summary)?;
+            rooms.insert(RoomId::try_from(room_id.as_str())?, room);
+        }
+
+        let mut stmt = self
+            .conn
+            .prepare("SELECT room_id, user_id, member FROM members")?;
+        let rows = stmt.query_map(NO_PARAMS, |row| {
+            (
+                row.get::<_, String>(0),
+                row.get::<_, String>(1),
+                row.get::<_, String>(2),
+            )
+        })?;
+        for row in rows {
+            let (room_id, user_id, member) = row?;
+            let member: Member = serde_json::from_str(&member)?;
+            if let Some(room) = rooms.get_mut(&RoomId::try_from(room_id.as_str())?) {
+                room.set_member(UserId::try_from(user_id.as_str())?, member);
+            }
+        }
+
+        let mut timelines = HashMap::new();
+        let mut stmt = self.conn.prepare(
+            "SELECT room_id, event, prev_batch FROM timeline_events ORDER BY room_id, position",
+        )?;
+        let rows = stmt.query_map(NO_PARAMS, |row| {
+            (
+                row.get::<_, String>(0),
+                row.get::<_, String>(1),
+                row.get::<_, Option<String>>(2),
+            )
+        })?;
+        for row in rows {
+            let (room_id, event, prev_batch) = row?;
+            let room_id = RoomId::try_from(room_id.as_str())?;
+            let event: TimelineEvent = serde_json::from_str(&event)?;
+
+            let events = timelines.entry(room_id.clone()).or_insert_with(Vec::new);
+            if events.is_empty() {
+                // The oldest event, which older events are fetched from
+                if let Some(room) = rooms.get_mut(&room_id) {
+                    room.start_timeline(prev_batch);
+                }
+            }
+            events.push(event);
+        }
+
+        Ok(Some(CachedData {
+            rooms: Rooms::from_cache(direct_rooms, rooms),
+            timelines,
+            next_batch,
+        }))
+    }
+
+    /// Write everything that changed in `rooms` since the last call, together
+    /// with the `next_batch` token of the sync response that changed it.
+    ///
+    /// If this fails, the cache is missing the changes and should be cleared.
+    pub fn store(&mut self, rooms: &mut Rooms, next_batch: &str) -> Result<(), Error> {
+        let changes = rooms.take_changes();
+        let tx = self.conn.transaction()?;
+
+        if changes.account_data {
+            set_account_value(&tx, "direct_rooms", rooms.direct_rooms())?;
+        }
+
+        for room_id in &changes.rooms {
+            if let Some(room) = rooms.room(room_id) {
+                tx.execute(
+                    "INSERT OR REPLACE INTO rooms (room_id, summary) VALUES (?, ?)",
+                    &[&room_id.to_string() as &dyn ToSql, &serde_json::to_string(room)?],
+                )?;
+            }
+        }
+
+        for (room_id, user_ids) in &changes.members {
+            let room = match rooms.room(room_id) {
+                Some(room) => room,
+                None => continue,
+            };
+
+            for user_id in user_ids {
+                if let Some(member) = room.member(user_id) {
+                    tx.execute(
+                        "INSERT OR REPLACE INTO members (room_id, user_id, member)
+                         VALUES (?, ?, ?)",
+                        &[
+                            &room_id.to_string() as &dyn ToSql,
+                            &user_id.to_string(),
+                            &serde_json::to_string(member)?,
+                        ],
+                    )?;
+                }
+            }
+        }
+
+        for room_id in &changes.cleared_timelines {
+            tx.execute(
+                "DELETE FROM timeline_events WHERE room_id = ?",
+                &[&room_id.to_string() as &dyn ToSql],
+            )?;
+        }
+
+        for (room_id, chunks) in &changes.prepended_events {
+            for chunk in chunks {
+                insert_chunk(&tx, room_id, chunk, true)?;
+            }
+        }
+
+        for (room_id, chunks) in &changes.appended_events {
+            for chunk in chunks {
+                insert_chunk(&tx, room_id, chunk, false)?;
+            }
+        }
+
+        let changed_timelines = changes
+            .prepended_events
+            .keys()
+            .chain(changes.appended_events.keys())
+            .collect::<HashSet<_>>();
+        for room_id in changed_timelines {
+            trim_timeline(&tx, room_id)?;
+        }
+
+        set_account_value(&tx, "next_batch", next_batch)?;
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    /// Delete everything that was cached, e.g. because the account is synced
+    /// from scratch.
+    pub fn clear(&self) -> Result<(), Error> {
+        self.conn.execute_batch(
+            "BEGIN;
+             DELETE FROM account;
+             DELETE FROM rooms;
+             DELETE FROM members;
+             DELETE FROM timeline_events;
+             COMMIT;",
+        )?;
+
+        Ok(())
+    }
+
+    fn account_value<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>, Error> {
+        let value: Option<String> = self
+            .conn
+            .query_row(
+                "SELECT value FROM account WHERE key = ?",
+                &[&key as &dyn ToSql],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        match value {
+            Some(value) => Ok(Some(serde_json::from_str(&value)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Add a chunk of events before the oldest event of a room if `prepend` is
+/// set, or after the newest one otherwise.
+fn insert_chunk(
+    conn: &Connection,
+    room_id: &RoomId,
+    chunk: &TimelineChunk,
+    prepend: bool,
+) -> Result<(), Error> {
+    let sql = if prepend {
+        "INSERT INTO timeline_events (room_id, position, event, prev_batch)
+         SELECT ?1, IFNULL(MIN(position), 1) - 1, ?2, ?3
+         FROM timeline_events WHERE room_id = ?1"
+    } else {
+        "INSERT INTO timeline_events (room_id, position, event, prev_batch)
+         SELECT ?1, IFNULL(MAX(position), 0) + 1, ?2, ?3
+         FROM timeline_events WHERE room_id = ?1"
+    };
+    let room_id = room_id.to_string();
+
+    let mut events: Vec<_> = chunk.events.iter().enumerate().collect();
+    // Prepended events are inserted from the newest to the oldest
+    if prepend {
+        events.reverse();
+    }
+
+    for (i, event) in events {
+        let prev_batch = chunk.prev_batch.as_ref().filter(|_| i == 0);
+        conn.execute(
+            sql,
+            &[
+                &room_id as &dyn ToSql,
+                &serde_json::to_string(event)?,
+                &prev_batch,
+            ],
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Drop the oldest events of a room if there are more than
+/// `MAX_EVENTS_PER_ROOM`.
+fn trim_timeline(conn: &Connection, room_id: &RoomId) -> Result<(), Error> {
+    let room_id = room_id.to_string();
+
+    let dropped = conn.execute(
+        "DELETE FROM timeline_events WHERE room_id = ?1 AND position <=
+         (SELECT MAX(position) FROM timeline_events WHERE room_id = ?1) - ?2",
+        &[&room_id as &dyn ToSql, &MAX_EVENTS_PER_ROOM],
+    )?;
+    if dropped > 0 {
+        // Start with the first event of a chunk again, so the dropped events
+        // can still be fetched
+        conn.execute(
+            "DELETE FROM timeline_events WHERE room_id = ?1 AND position <
+             (SELECT MIN(position) FROM timeline_events
+              WHERE room_id = ?1 AND prev_batch IS NOT NULL)",
+            &[&room_id as &dyn ToSql],
+        )?;
+    }
+
+    Ok(())
+}
+
+fn set_account_value<T: Serialize + ?Sized>(
+    conn: &Connection,
+    key: &str,
+    value: &T,
+) -> Result<(), Error> {
+    conn.execute(
+        "INSERT OR REPLACE INTO account (key, value) VALUES (?, ?)",
+        &[&key as &dyn ToSql, &serde_json::to_string(value)?],
+    )?;
+
+    Ok(())
+}
+
+/// Bring the schema up to date, applying each migration in its own
+/// transaction.
+fn migrate(conn: &mut Connection) -> Result<(), Error> {
+    let version: i64 = conn.query_row("PRAGMA user_version", NO_PARAMS, |row| row.get(0))?;
+    if version < 0 || version as usize > MIGRATIONS.len() {
+        return Err(Error::UnknownVersion(version));
+    }
+
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        info!("Migrating cache to version {}", i + 1);
+
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        // PRAGMA doesn't support parameters
+        tx.execute_batch(&format!("PRAGMA user_version = {}", i + 1))?;
+        tx.commit()?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use ruma_identifiers::EventId;
+
+    use super::*;
+    use crate::bg_thread::TimelineEventContent;
+
+    fn room_id() -> RoomId {
+        RoomId::try_from("!room:example.org").unwrap()
+    }
+
+    fn event(n: u32) -> TimelineEvent {
+        TimelineEvent {
+            event_id: EventId::try_from(format!("${}:example.org", n).as_str()).unwrap(),
+            sender: UserId::try_from("@alice:example.org").unwrap(),
+            sender_name: None,
+            origin_server_ts: u64::from(n),
+            transaction_id: None,
+            content: TimelineEventContent::Text {
+                body: n.to_string(),
+            },
+        }
+    }
+
+    fn body(event: &TimelineEvent) -> &str {
+        match event.content {
+            TimelineEventContent::Text { ref body } => body,
+            _ => panic!("not a text message"),
+        }
+    }
+
+    fn chunk(events: &[TimelineEvent], prev_batch: Option<&str>) -> TimelineChunk {
+        TimelineChunk {
+            events: events.to_vec(),
+            prev_batch: prev_batch.map(ToOwned::to_owned),
+        }
+    }
+
+    fn user_version(conn: &Connection) -> i64 {
+        conn.query_row("PRAGMA user_version", NO_PARAMS, |row| row.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn migrate_new_database() {
+        let cache = Cache::with_connection(Connection::open_in_memory().unwrap()).unwrap();
+
+        assert_eq!(user_version(&cache.conn), MIGRATIONS.len() as i64);
+        assert!(cache.load().unwrap().is_none());
+    }
+
+    #[test]
+    fn migrate_current_database() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        set_account_value(&conn, "next_batch", "s1").unwrap();
+
+        let cache = Cache::with_connection(conn).unwrap();
+        assert_eq!(user_version(&cache.conn), MIGRATIONS.len() as i64);
+        assert_eq!(cache.load().unwrap().unwrap().next_batch, "s1");
+    }
+
+    #[test]
+    fn migrate_from_version_1() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(MIGRATIONS[0]).unwrap();
+        conn.execute_batch("PRAGMA user_version = 1").unwrap();
+        set_account_value(&conn, "next_batch", "s1").unwrap();
+        conn.execute(
+            "INSERT INTO timeline_events (room_id, position, event) VALUES (?, 1, ?)",
+            &[
+                &room_id().to_string() as &dyn ToSql,
+                &serde_json::to_string(&event(1)).unwrap(),
+            ],
+        )
+        .unwrap();
+
+        let cache = Cache::with_connection(conn).unwrap();
+        assert_eq!(user_version(&cache.conn), 2);
+        // The events can't be paginated from without a prev_batch token
+        let cached = cache.load().unwrap().unwrap();
+        assert_eq!(cached.next_batch, "s1");
+        assert!(cached.timelines.is_empty());
+    }
+
+    #[test]
+    fn reject_unknown_version() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(&format!("PRAGMA user_version = {}", MIGRATIONS.len() + 1))
+            .unwrap();
+
+        match Cache::with_connection(conn) {
+            Err(Error::UnknownVersion(version)) => {
+                assert_eq!(version, MIGRATIONS.len() as i64 + 1)
+            }
+            _ => panic!("opened a cache of an unknown version"),
+        }
+    }
+
+    #[test]
+    fn keep_newest_events() {
+        let cache = Cache::with_connection(Connection::open_in_memory().unwrap()).unwrap();
+        set_account_value(&cache.conn, "next_batch", "s1").unwrap();
+
+        let events: Vec<_> = (0..250).map(event).collect();
+        insert_chunk(&cache.conn, &room_id(), &chunk(&events[..150], None), false).unwrap();
+        insert_chunk(&cache.conn, &room_id(), &chunk(&events[150..], None), false).unwrap();
+        trim_timeline(&cache.conn, &room_id()).unwrap();
+
+        let timeline = cache.load().unwrap().unwrap().timelines.remove(&room_id()).unwrap();
+        assert_eq!(timeline.len(), MAX_EVENTS_PER_ROOM as usize);
+        assert_eq!(body(&timeline[0]), "50");
+        assert_eq!(body(timeline.last().unwrap()), "249");
+    }
+
+    #[test]
+    fn keep_prev_batch_of_oldest_events() {
+        let cache = Cache::with_connection(Connection::open_in_memory().unwrap()).unwrap();
+        let events: Vec<_> = (0..9).map(event).collect();
+
+        insert_chunk(&cache.conn, &room_id(), &chunk(&events[3..6], Some("t3")), false).unwrap();
+        insert_chunk(&cache.conn, &room_id(), &chunk(&events[6..], Some("t6")), false).unwrap();
+        insert_chunk(&cache.conn, &room_id(), &chunk(&events[..3], Some("t0")), true).unwrap();
+
+        let mut stmt = cache
+            .conn
+            .prepare("SELECT event, prev_batch FROM timeline_events ORDER BY position")
+            .unwrap();
+        let rows: Vec<(TimelineEvent, Option<String>)> = stmt
+            .query_map(NO_PARAMS, |row| {
+                (
+                    serde_json::from_str(&row.get::<_, String>(0)).unwrap(),
+                    row.get(1),
+                )
+            })
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+
+        let bodies: Vec<_> = rows.iter().map(|(event, _)| body(event)).collect();
+        assert_eq!(bodies, ["0", "1", "2", "3", "4", "5", "6", "7", "8"]);
+        let prev_batches: Vec<_> = rows
+            .iter()
+            .map(|(_, prev_batch)| prev_batch.as_ref().map(String::as_str))
+            .collect();
+        assert_eq!(
+            prev_batches,
+            [
+                Some("t0"),
+                None,
+                None,
+                Some("t3"),
+                None,
+                None,
+                Some("t6"),
+                None,
+                None,
+            ]
+        );
+    }
+
+    #[test]
+    fn trim_to_start_of_chunk() {
+        let cache = Cache::with_connection(Connection::open_in_memory().unwrap()).unwrap();
+        set_account_value(&cache.conn, "next_batch", "s1").unwrap();
+        let events: Vec<_> = (0..250).map(event).collect();
+
+        insert_chunk(&cache.conn, &room_id(), &chunk(&events[..150], Some("a")), false).unwrap();
+        insert_chunk(&cache.conn, &room_id(), &chunk(&events[150..], Some("b")), false).unwrap();
+        trim_timeline(&cache.conn, &room_id()).unwrap();
+
+        // The rest of the first chunk couldn't be paginated from
+        let timeline = cache.load().unwrap().unwrap().timelines.remove(&room_id()).unwrap();
+        assert_eq!(timeline.len(), 100);
+        assert_eq!(body(&timeline[0]), "150");
+    }
+
+    #[test]
+    fn keep_start_of_room() {
+        let cache = Cache::with_connection(Connection::open_in_memory().unwrap()).unwrap();
+        set_account_value(&cache.conn, "next_batch", "s1").unwrap();
+        let events: Vec<_> = (0..4).map(event).collect();
+
+        insert_chunk(&cache.conn, &room_id(), &chunk(&events[2..], Some("a")), false).unwrap();
+        // Nothing is before the first event of the room
+        insert_chunk(&cache.conn, &room_id(), &chunk(&events[..2], None), true).unwrap();
+        trim_timeline(&cache.conn, &room_id()).unwrap();
+
+        let timeline = cache.load().unwrap().unwrap().timelines.remove(&room_id()).unwrap();
+        assert_eq!(timeline.len(), 4);
+        assert_eq!(body(&timeline[0]), "0");
+    }
+
+    #[test]
+    fn clear_everything() {
+        let cache = Cache::with_connection(Connection::open_in_memory().unwrap()).unwrap();
+        set_account_value(&cache.conn, "next_batch", "s1").unwrap();
+        insert_chunk(&cache.conn, &room_id(), &chunk(&[event(1)], Some("t1")), false).unwrap();
+
+        cache.clear().unwrap();
+        assert!(cache.load().unwrap().is_none());
+        let count: i64 = cache
+            .conn
+            .query_row("SELECT COUNT(*) FROM timeline_events", NO_PARAMS, |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(count, 0);
+    }
+}
@@ -0,0 +1,150 @@
+// Finding the client-server API of a homeserver from what the user entered.
+
+use std::convert::TryFrom;
+
+use futures::prelude::{async, await};
+use ruma_identifiers::UserId;
+use url::Url;
+
+use super::api::{self, HttpClient};
+
+/// Where to find a homeserver, as entered by the user.
+#[derive(Clone, Debug, PartialEq)]
+pub enum HomeserverAddress {
+    /// The base URL of the client-server API, used as is.
+    Url(Url),
+    /// A server name like `example.org`, the part of Matrix IDs after the
+    /// colon. The base URL is looked up in its `.well-known` file.
+    ServerName(String),
+}
+
+impl HomeserverAddress {
+    /// Interpret a homeserver URL, a server name or a Matrix user ID.
+    pub fn parse(input: &str) -> Option<Self> {
+        let input = input.trim();
+
+        if input.contains("://") {
+            return match Url::parse(input) {
+                Ok(ref url) if url.scheme() != "https" && url.scheme() != "http" => None,
+                Ok(url) => Some(HomeserverAddress::Url(url)),
+                Err(_) => None,
+            };
+        }
+
+        let server_name = if input.starts_with('@') {
+            UserId::try_from(input).ok()?;
+            input.splitn(2, ':').nth(1)?
+        } else {
+            input
+        };
+
+        // Make sure the server name can be turned into a URL later
+        Url::parse(&format!("https://{}", server_name)).ok()?;
+        if server_name.is_empty() || server_name.contains('/') {
+            return None;
+        }
+
+        Some(HomeserverAddress::ServerName(server_name.to_owned()))
+    }
+
+    /// The base URL to use until discovery found the right one.
+    pub fn initial_url(&self) -> Option<Url> {
+        match *self {
+            HomeserverAddress::Url(ref url) => Some(url.clone()),
+            HomeserverAddress::ServerName(ref server_name) => {
+                Url::parse(&format!("https://{}", server_name)).ok()
+            }
+        }
+    }
+}
+
+/// A homeserver whose client-server API was found.
+pub struct Homeserver {
+    pub base_url: Url,
+    /// Only known up front if the user entered a server name rather than a
+    /// URL. Otherwise it is learned from the user ID after logging in.
+    pub server_name: Option<String>,
+}
+
+/// Find the client-server API of a homeserver and check that it is reachable.
+#[async]
+pub fn discover(
+    http_client: HttpClient,
+    address: HomeserverAddress,
+) -> Result<Homeserver, api::Error> {
+    let (base_url, server_name) = match address {
+        HomeserverAddress::Url(url) => (url, None),
+        HomeserverAddress::ServerName(server_name) => {
+            let well_known = await!(api::get_well_known(
+                http_client.clone(),
+                server_name.clone()
+            ))?;
+
+            // Without a .well-known file, the server is expected to serve the
+            // API itself
+            let base_url = match well_known {
+                Some(base_url) => base_url,
+                None => Url::parse(&format!("https://{}", server_name))
+                    .map_err(|_| api::Error::InvalidHomeserverUrl(server_name.clone()))?,
+            };
+
+            (base_url, Some(server_name))
+        }
+    };
+
+    let versions = await!(api::get_versions(http_client, base_url.clone()))?;
+    debug!("{} supports client-server API versions {:?}", base_url, versions);
+
+    Ok(Homeserver {
+        base_url,
+        server_name,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn url(url: &str) -> Option<HomeserverAddress> {
+        Some(HomeserverAddress::Url(Url::parse(url).unwrap()))
+    }
+
+    fn server_name(server_name: &str) -> Option<HomeserverAddress> {
+        Some(HomeserverAddress::ServerName(server_name.to_owned()))
+    }
+
+    #[test]
+    fn parse() {
+        let cases = [
+            ("https://matrix.example.org", url("https://matrix.example.org")),
+            ("http://localhost:8008", url("http://localhost:8008")),
+            ("https://example.org/matrix/", url("https://example.org/matrix/")),
+            ("ftp://x", None),
+            ("https://", None),
+            ("example.org", server_name("example.org")),
+            ("  example.org  ", server_name("example.org")),
+            ("example.org:8448", server_name("example.org:8448")),
+            ("example.org/path", None),
+            ("@alice:example.org", server_name("example.org")),
+            ("@alice:example.org:8448", server_name("example.org:8448")),
+            ("@alice", None),
+            ("", None),
+        ];
+
+        for &(input, ref expected) in cases.iter() {
+            assert_eq!(HomeserverAddress::parse(input), *expected, "input: {:?}", input);
+        }
+    }
+
+    #[test]
+    fn initial_url() {
+        assert_eq!(
+            HomeserverAddress::ServerName("example.org:8448".to_owned()).initial_url(),
+            Url::parse("https://example.org:8448").ok()
+        );
+        assert_eq!(
+            HomeserverAddress::Url(Url::parse("http://localhost:8008").unwrap()).initial_url(),
+            Url::parse("http://localhost:8008").ok()
+        );
+    }
+}
@@ -0,0 +1,2036 @@
+mod api;
+mod backoff;
+mod cache;
+mod discovery;
+mod register;
+mod rooms;
+mod secret_store;
+mod session_store;
+mod sso;
+mod txn_id;
+
+use std::{
+    self,
+    cell::RefCell,
+    collections::hash_map::{Entry as HashMapEntry, HashMap},
+    mem,
+    rc::Rc,
+    time::Duration,
+};
+
+use futures::{
+    self,
+    prelude::{async, await},
+    Future,
+};
+use glib;
+use hyper::client::HttpConnector;
+use hyper_tls::HttpsConnector;
+use ruma_client::{self, api::r0};
+use ruma_events::{
+    room::message::{MessageEventContent, MessageType, TextMessageEventContent},
+    EventType,
+};
+use ruma_identifiers::{RoomId, UserId};
+use serde_json::Value as JsonValue;
+use tokio_core;
+use url::Url;
+
+use crate::app::FrontendCommand;
+
+use self::{
+    backoff::Backoff,
+    cache::Cache,
+    register::RegistrationData,
+    rooms::Rooms,
+    session_store::StoredFilter,
+    txn_id::TxnIdGenerator,
+};
+pub use self::{
+    api::{ConnectError, DirectoryUser, PublicRoom, RoomPreset, RoomVisibility},
+    discovery::HomeserverAddress,
+    register::{Policy, RegistrationResponse, RegistrationStage},
+    rooms::{RoomSection, RoomStateChange, TimelineEvent, TimelineEventContent},
+    session_store::{load_sessions, StoredSession},
+};
+
+/// How often sending an event is attempted before giving up.
+const SEND_ATTEMPTS: u32 = 3;
+
+/// How many rooms to fetch from the room directory at once.
+const DIRECTORY_PAGE_SIZE: u32 = 30;
+
+/// How many users to fetch from the user directory when autocompleting.
+const USER_SEARCH_LIMIT: u32 = 10;
+
+/// How many of the latest events of a room's timeline sync responses contain.
+const SYNC_TIMELINE_LIMIT: u32 = 50;
+
+const PASSWORD_LOGIN_TYPE: &str = "m.login.password";
+
+// We refer to users with numerical IDs (a simple counter) internally, because
+// using the matrix user id to refer to users would involve a roundtrip to the
+// homeserver when registering as a guest.
+pub type InternalUserId = u32;
+
+/// Sends `FrontendCommand`s about one account, tagged with its
+/// `InternalUserId`.
+#[derive(Clone)]
+pub struct FrontendSender {
+    user_id: InternalUserId,
+    chan_tx: std::sync::mpsc::Sender<(InternalUserId, FrontendCommand)>,
+}
+
+impl FrontendSender {
+    fn new(
+        user_id: InternalUserId,
+        chan_tx: std::sync::mpsc::Sender<(InternalUserId, FrontendCommand)>,
+    ) -> Self {
+        FrontendSender { user_id, chan_tx }
+    }
+
+    pub fn send(
+        &self,
+        command: FrontendCommand,
+    ) -> Result<(), std::sync::mpsc::SendError<(InternalUserId, FrontendCommand)>> {
+        self.chan_tx.send((self.user_id, command))
+    }
+}
+
+pub enum MatrixCommand {
+    Connect {
+        homeserver: HomeserverAddress,
+        connection_method: ConnectionMethod,
+    },
+    // This is not a UserSpecificCommand because it deletes user data rather
+    // than just accessing it.
+    Disconnect(InternalUserId),
+    /// Invalidate the access token and forget everything stored about the
+    /// account.
+    Logout {
+        user_id: InternalUserId,
+        /// Also log out all other devices of the account.
+        all_devices: bool,
+    },
+    UserSpecificCommand {
+        user_id: InternalUserId,
+        command: UserSpecificCommand,
+    },
+    Quit,
+}
+
+pub enum UserSpecificCommand {
+    FetchDirectory {
+        /// The server whose directory to fetch, `None` for the user's own
+        /// homeserver.
+        server: Option<String>,
+        search_term: Option<String>,
+        /// Pagination token from the previous page of results.
+        since: Option<String>,
+        /// Sent back with the results, to tell them apart from those of
+        /// earlier requests.
+        generation: u32,
+    },
+    SendTextMessage {
+        room_id: RoomId,
+        message_content: String,
+    },
+    /// Send a message again after sending it failed before.
+    RetryTextMessage {
+        room_id: RoomId,
+        /// The local ID the message was displayed with, which is also the
+        /// transaction ID it was sent with.
+        local_id: String,
+        message_content: String,
+    },
+    JoinRoom {
+        /// A room ID or alias.
+        room_id_or_alias: String,
+        /// Servers to try joining through.
+        server_names: Vec<String>,
+    },
+    CreateRoom {
+        name: Option<String>,
+        /// The localpart of the alias to create for the room.
+        alias: Option<String>,
+        visibility: RoomVisibility,
+        preset: RoomPreset,
+    },
+    LeaveRoom {
+        room_id: RoomId,
+    },
+    InviteUser {
+        room_id: RoomId,
+        user_id: UserId,
+    },
+    SearchUserDirectory {
+        search_term: String,
+    },
+    /// Add a user to the ignore list, which hides their messages and invites.
+    IgnoreUser {
+        user_id: UserId,
+    },
+    FetchThumbnail {
+        /// The `mxc://` URL of the image.
+        mxc_url: String,
+        width: u32,
+        height: u32,
+    },
+    /// Turn the guest account into a full account with the given user name
+    /// and password.
+    UpgradeGuest {
+        username: String,
+        password: String,
+        email: Option<String>,
+        /// Host name of the identity server to validate `email` with.
+        id_server: Option<String>,
+    },
+    /// Answer the registration stage the user was asked about with
+    /// `FrontendCommand::RegistrationStage`.
+    AnswerRegistrationStage(RegistrationResponse),
+    /// Fetch all members of a room, since sync responses only contain those
+    /// needed to show its timeline.
+    FetchMembers {
+        room_id: RoomId,
+    },
+    /// Fetch up to `limit` events that are older than those already shown.
+    PaginateBackwards {
+        room_id: RoomId,
+        limit: u32,
+    },
+    /// Forget everything synced so far and start over with an initial sync,
+    /// e.g. because the rooms shown are wrong.
+    FullResync,
+    // [...]
+}
+
+pub enum ConnectionMethod {
+    Login { username: String, password: String },
+    /// Log in on a web page of the homeserver.
+    SingleSignOn,
+    Guest,
+    Register {
+        username: String,
+        password: String,
+        /// Required by homeservers that only allow registering with an email
+        /// address.
+        email: Option<String>,
+        /// Host name of the identity server to validate `email` with.
+        id_server: Option<String>,
+    },
+    /// Continue using a session from a previous run of fest.
+    RestoreSession(StoredSession),
+}
+
+/// Whether an account receives updates from its homeserver.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConnectionState {
+    Connected,
+    /// Syncing failed, and is attempted again after `delay`.
+    Reconnecting { delay: Duration },
+    /// The homeserver can't be reached, most likely because there is no
+    /// network connection. Syncing is attempted again after `delay`.
+    Offline { delay: Duration },
+    /// The access token is no longer valid, so the account has to be logged
+    /// into again. Syncing stopped.
+    LoggedOut,
+}
+
+pub struct UserData {
+    client: ruma_client::Client<HttpsConnector<HttpConnector>>,
+    /// For requests ruma_client doesn't support.
+    http_client: api::HttpClient,
+    homeserver_url: Url,
+    /// The part of the user's Matrix ID after the colon, which can differ
+    /// from the host of `homeserver_url`.
+    server_name: Option<String>,
+    /// The ID of the device this session belongs to, known after logging in.
+    device_id: Option<String>,
+    /// Known after logging in.
+    user_id: Option<UserId>,
+    display_name: Option<String>,
+    rooms: Rooms,
+    /// Available after logging in, unless it couldn't be opened.
+    cache: Option<Cache>,
+    /// Available after logging in.
+    txn_ids: Option<TxnIdGenerator>,
+    /// Available after logging in.
+    stored_session: Option<StoredSession>,
+    /// Forwards the user's answers to a registration that is in progress.
+    registration_response_tx: Option<futures::sync::mpsc::UnboundedSender<RegistrationResponse>>,
+    /// Set by `UserSpecificCommand::FullResync` until the sync starts over.
+    full_resync_requested: bool,
+}
+
+impl UserData {
+    fn access_token(&self) -> Option<String> {
+        self.client
+            .session()
+            .map(|session| session.access_token().to_owned())
+    }
+
+    /// Everything needed for a request with the functions in `api`, if
+    /// logged in.
+    fn api_params(&self) -> Option<(api::HttpClient, Url, String)> {
+        Some((
+            self.http_client.clone(),
+            self.homeserver_url.clone(),
+            self.access_token()?,
+        ))
+    }
+}
+
+/// The name other clients show for this device.
+fn device_display_name() -> String {
+    format!(
+        "fest on {}",
+        glib::get_host_name().unwrap_or_else(|| "unknown host".into())
+    )
+}
+
+/// Tell the frontend why connecting failed.
+fn report_connect_failure(frontend_chan_tx: &FrontendSender, reason: ConnectError) {
+    match reason {
+        ConnectError::Cancelled => info!("Connecting was cancelled"),
+        ref reason => error!("Failed to connect: {:?}", reason),
+    }
+
+    // TODO: Handle channel send errors?
+    let _ = frontend_chan_tx.send(FrontendCommand::ConnectFailed { reason });
+}
+
+fn invalid_response() -> ConnectError {
+    ConnectError::Other("The homeserver sent an invalid response.".to_owned())
+}
+
+/// Check that the homeserver supports logging in with `connection_method`,
+/// switching to single sign-on if it doesn't accept passwords.
+fn check_login_method(
+    connection_method: ConnectionMethod,
+    login_types: &[String],
+) -> Result<ConnectionMethod, &'static str> {
+    let supports = |login_type: &str| login_types.iter().any(|t| t == login_type);
+
+    match connection_method {
+        ConnectionMethod::Login { .. } if !supports(PASSWORD_LOGIN_TYPE) => {
+            if supports(sso::SSO_LOGIN_TYPE) {
+                info!("The homeserver doesn't support passwords, using single sign-on");
+                Ok(ConnectionMethod::SingleSignOn)
+            } else {
+                Err("This homeserver doesn't support logging in with a password.")
+            }
+        }
+        ConnectionMethod::SingleSignOn if !supports(sso::SSO_LOGIN_TYPE) => {
+            Err("This homeserver doesn't support single sign-on.")
+        }
+        connection_method => Ok(connection_method),
+    }
+}
+
+#[async]
+fn sync(
+    tokio_handle: tokio_core::reactor::Handle,
+    homeserver: HomeserverAddress,
+    connection_method: ConnectionMethod,
+    user_data: Rc<RefCell<UserData>>,
+    frontend_chan_tx: FrontendSender,
+) -> Result<(), ()> {
+    let is_new_session = match connection_method {
+        ConnectionMethod::RestoreSession(_) => false,
+        _ => true,
+    };
+
+    // The URL of a restored session was discovered when it was created
+    let homeserver_url = if is_new_session {
+        let http_client = user_data.borrow().http_client.clone();
+        let homeserver = await!(discovery::discover(http_client, homeserver))
+            .map_err(|e| report_connect_failure(&frontend_chan_tx, e.into()))?;
+
+        let client = ruma_client::Client::https(homeserver.base_url.clone(), None).map_err(|e| {
+            error!("Failed to create client: {:?}", e);
+            report_connect_failure(
+                &frontend_chan_tx,
+                ConnectError::Other("Couldn't connect to the homeserver.".to_owned()),
+            );
+        })?;
+
+        let mut user_data = user_data.borrow_mut();
+        user_data.client = client;
+        user_data.homeserver_url = homeserver.base_url.clone();
+        user_data.server_name = homeserver.server_name;
+        homeserver.base_url
+    } else {
+        user_data.borrow().homeserver_url.clone()
+    };
+
+    let is_login = match connection_method {
+        ConnectionMethod::Login { .. } | ConnectionMethod::SingleSignOn => true,
+        _ => false,
+    };
+    let connection_method = if is_login {
+        let http_client = user_data.borrow().http_client.clone();
+        let login_types = await!(api::get_login_types(http_client, homeserver_url.clone()))
+            .map_err(|e| report_connect_failure(&frontend_chan_tx, e.into()))?;
+
+        check_login_method(connection_method, &login_types).map_err(|message| {
+            report_connect_failure(
+                &frontend_chan_tx,
+                ConnectError::Unsupported(message.to_owned()),
+            )
+        })?
+    } else {
+        connection_method
+    };
+
+    let (stored_session, is_new_device) = match connection_method {
+        ConnectionMethod::Login { username, password } => {
+            let device_id = match session_store::device_id(&username, &homeserver_url) {
+                Ok((device_id, _)) => Some(device_id),
+                Err(e) => {
+                    // Not being able to reuse the device ID is annoying, but
+                    // not a reason to fail logging in
+                    error!("Failed to get a device ID for {}: {}", username, e);
+                    None
+                }
+            };
+
+            let http_client = user_data.borrow().http_client.clone();
+            let response = await!(api::log_in_with_password(
+                http_client,
+                homeserver_url.clone(),
+                username,
+                password,
+                device_id,
+                device_display_name(),
+            ))
+            .map_err(|e| {
+                report_connect_failure(&frontend_chan_tx, ConnectError::from_login_error(e))
+            })?;
+            let session = register::session_from_response(&response)
+                .map_err(|()| report_connect_failure(&frontend_chan_tx, invalid_response()))?;
+            let stored_session = StoredSession::new(homeserver_url.clone(), &session);
+
+            if let Err(e) = session_store::save_device_id(
+                &stored_session.user_id,
+                &stored_session.homeserver_url,
+                &stored_session.device_id,
+            ) {
+                error!("Failed to save device ID of {}: {}", stored_session.user_id, e);
+            }
+
+            let client = ruma_client::Client::https(homeserver_url, Some(session)).map_err(|e| {
+                error!("Failed to create client: {:?}", e);
+            })?;
+            user_data.borrow_mut().client = client;
+
+            // A new device got its display name when logging in
+            (stored_session, false)
+        }
+        ConnectionMethod::SingleSignOn => {
+            let device_id = match session_store::homeserver_device_id(&homeserver_url) {
+                Ok((device_id, _)) => Some(device_id),
+                Err(e) => {
+                    error!("Failed to get a device ID for {}: {}", homeserver_url, e);
+                    None
+                }
+            };
+
+            let http_client = user_data.borrow().http_client.clone();
+            let session = await!(sso::log_in(
+                tokio_handle.clone(),
+                http_client,
+                homeserver_url.clone(),
+                device_id,
+                frontend_chan_tx.clone(),
+            ))
+            .map_err(|reason| report_connect_failure(&frontend_chan_tx, reason))?;
+            let stored_session = StoredSession::new(homeserver_url.clone(), &session);
+
+            if let Err(e) = session_store::save_device_id(
+                &stored_session.user_id,
+                &stored_session.homeserver_url,
+                &stored_session.device_id,
+            ) {
+                error!("Failed to save device ID of {}: {}", stored_session.user_id, e);
+            }
+
+            let client = ruma_client::Client::https(homeserver_url, Some(session)).map_err(|e| {
+                error!("Failed to create client: {:?}", e);
+            })?;
+            user_data.borrow_mut().client = client;
+
+            // The device got its display name when logging in
+            (stored_session, false)
+        }
+        ConnectionMethod::Guest => {
+            let http_client = user_data.borrow().http_client.clone();
+            let response = await!(api::register(
+                http_client,
+                homeserver_url.clone(),
+                "guest",
+                json!({ "initial_device_display_name": device_display_name() }),
+            ))
+            .map_err(|e| report_connect_failure(&frontend_chan_tx, e.into()))?;
+            let session = register::session_from_response(&response)
+                .map_err(|()| report_connect_failure(&frontend_chan_tx, invalid_response()))?;
+
+            let mut stored_session = StoredSession::new(homeserver_url.clone(), &session);
+            stored_session.is_guest = true;
+
+            let client = ruma_client::Client::https(homeserver_url, Some(session)).map_err(|e| {
+                error!("Failed to create client: {:?}", e);
+            })?;
+            user_data.borrow_mut().client = client;
+
+            // The device got its display name when registering
+            (stored_session, false)
+        }
+        ConnectionMethod::Register {
+            username,
+            password,
+            email,
+            id_server,
+        } => {
+            let (device_id, is_new_device) =
+                match session_store::device_id(&username, &homeserver_url) {
+                    Ok((device_id, is_new_device)) => (Some(device_id), is_new_device),
+                    Err(e) => {
+                        error!("Failed to get a device ID for {}: {}", username, e);
+                        (None, true)
+                    }
+                };
+
+            let (response_tx, response_rx) = futures::sync::mpsc::unbounded();
+            let http_client = {
+                let mut user_data = user_data.borrow_mut();
+                user_data.registration_response_tx = Some(response_tx);
+                user_data.http_client.clone()
+            };
+
+            let result = await!(register::register(
+                http_client,
+                homeserver_url.clone(),
+                RegistrationData {
+                    username,
+                    password,
+                    email,
+                    id_server,
+                    device_id,
+                    guest_access_token: None,
+                },
+                response_rx,
+                frontend_chan_tx.clone(),
+            ));
+            user_data.borrow_mut().registration_response_tx = None;
+            let session =
+                result.map_err(|reason| report_connect_failure(&frontend_chan_tx, reason))?;
+            let stored_session = StoredSession::new(homeserver_url.clone(), &session);
+
+            if let Err(e) = session_store::save_device_id(
+                &stored_session.user_id,
+                &stored_session.homeserver_url,
+                &stored_session.device_id,
+            ) {
+                error!("Failed to save device ID of {}: {}", stored_session.user_id, e);
+            }
+
+            // ruma_client only gets a session by logging in or registering
+            // itself, so a new client is needed for the registered account.
+            let client =
+                ruma_client::Client::https(homeserver_url, Some(stored_session.to_ruma_session()))
+                    .map_err(|e| {
+                        error!("Failed to create client: {:?}", e);
+                    })?;
+            user_data.borrow_mut().client = client;
+
+            // TODO: Handle channel send errors?
+            let _ = frontend_chan_tx.send(FrontendCommand::RegistrationCompleted {
+                user_id: stored_session.user_id.clone(),
+            });
+
+            (stored_session, is_new_device)
+        }
+        ConnectionMethod::RestoreSession(stored_session) => {
+            let user_id = stored_session.user_id.clone();
+            let stored_session = await!(secret_store::run(move |secret_store| {
+                session_store::restore_access_token(stored_session, secret_store)
+            }))
+            .ok()
+            .and_then(|stored_session| stored_session)
+            .ok_or_else(|| {
+                report_connect_failure(
+                    &frontend_chan_tx,
+                    ConnectError::Other(format!(
+                        "Couldn't load the access token of {}. Log in again.",
+                        user_id
+                    )),
+                );
+            })?;
+
+            let client = ruma_client::Client::https(
+                homeserver_url,
+                Some(stored_session.to_ruma_session()),
+            )
+            .map_err(|e| {
+                error!("Failed to create client: {:?}", e);
+            })?;
+            user_data.borrow_mut().client = client;
+
+            (stored_session, false)
+        }
+    };
+
+    {
+        let mut user_data = user_data.borrow_mut();
+        user_data.device_id = Some(stored_session.device_id.clone());
+        user_data.user_id = Some(stored_session.user_id.clone());
+        user_data.txn_ids = Some(TxnIdGenerator::load(&stored_session.user_id));
+        user_data.server_name = stored_session
+            .user_id
+            .to_string()
+            .splitn(2, ':')
+            .nth(1)
+            .map(ToOwned::to_owned);
+    }
+
+    if is_new_device {
+        if let Err(e) = await!(api::set_device_display_name(
+            user_data.borrow().http_client.clone(),
+            stored_session.homeserver_url.clone(),
+            stored_session.access_token.clone(),
+            stored_session.device_id.clone(),
+            device_display_name(),
+        )) {
+            warn!(
+                "Failed to set display name of device {}: {}",
+                stored_session.device_id, e
+            );
+        }
+    }
+
+    if is_new_session {
+        let user_id = stored_session.user_id.clone();
+        let access_token = stored_session.access_token.clone();
+        // Not being able to store the access token only means having to log
+        // in again next time
+        let _ = await!(secret_store::run(move |secret_store| {
+            if let Err(e) = secret_store.store(&user_id, &access_token) {
+                error!("Failed to store access token of {}: {}", user_id, e);
+            }
+        }));
+    }
+
+    if let Err(e) = stored_session.save() {
+        error!("Failed to save session of {}: {}", stored_session.user_id, e);
+    }
+
+    // TODO: Handle channel send errors?
+    let _ = frontend_chan_tx.send(FrontendCommand::Connected {
+        matrix_user_id: stored_session.user_id.clone(),
+        device_id: stored_session.device_id.clone(),
+        is_guest: stored_session.is_guest,
+    });
+
+    // Continue where the last run of a restored session stopped. New
+    // sessions start with an initial sync.
+    let next_batch = stored_session.next_batch.clone();
+
+    let mut since = {
+        let mut user_data = user_data.borrow_mut();
+        user_data.rooms.set_own_user_id(stored_session.user_id.clone());
+        user_data.stored_session = Some(stored_session);
+
+        open_cache(&mut user_data, next_batch, &frontend_chan_tx)
+    };
+
+    // TODO: Fill in user metadata
+
+    let mut backoff = Backoff::new();
+    let mut is_connected = false;
+    let mut filter_id = None;
+
+    loop {
+        // Uploading it is attempted again if it failed, e.g. while offline
+        if filter_id.is_none() {
+            filter_id = await!(sync_filter_id(user_data.clone()))?;
+        }
+
+        let (http_client, homeserver_url, access_token) = match user_data.borrow().api_params() {
+            Some(params) => params,
+            None => return Err(()),
+        };
+        let result = await!(api::sync(
+            http_client,
+            homeserver_url,
+            access_token.clone(),
+            filter_id.clone(),
+            since.clone(),
+        ));
+
+        match result {
+            Ok(response) => {
+                trace!("synchronization response: {:?}", response);
+
+                if !is_connected {
+                    is_connected = true;
+                    backoff.reset();
+                    // TODO: Handle channel send errors?
+                    let _ = frontend_chan_tx.send(FrontendCommand::ConnectionStateChanged {
+                        state: ConnectionState::Connected,
+                    });
+                }
+
+                let next_batch = response.next_batch.clone();
+                let mut user_data = user_data.borrow_mut();
+                let user_data = &mut *user_data;
+                user_data
+                    .rooms
+                    .process_sync_response(response, &frontend_chan_tx);
+
+                update_cache(user_data, &next_batch);
+                if let Some(ref mut stored_session) = user_data.stored_session {
+                    stored_session.next_batch = Some(next_batch.clone());
+                    if let Err(e) = stored_session.save() {
+                        error!("Failed to save session of {}: {}", stored_session.user_id, e);
+                    }
+                }
+                since = Some(next_batch);
+            }
+            // The request that was pending when a guest account got upgraded
+            // fails, because it used the guest's access token.
+            Err(_) if user_data.borrow().access_token() != Some(access_token) => {}
+            Err(e) => {
+                error!("Error in sync_events: {}", e);
+                is_connected = false;
+
+                let state = backoff::state_after_error(&e, backoff.next_delay());
+                // TODO: Handle channel send errors?
+                let _ = frontend_chan_tx.send(FrontendCommand::ConnectionStateChanged { state });
+
+                let delay = match state {
+                    ConnectionState::Reconnecting { delay }
+                    | ConnectionState::Offline { delay } => delay,
+                    ConnectionState::Connected | ConnectionState::LoggedOut => return Err(()),
+                };
+                info!("Syncing again in {} seconds", delay.as_secs());
+
+                await!(
+                    tokio_core::reactor::Timeout::new(delay, &tokio_handle).map_err(|e| {
+                        error!("Failed to create timeout: {}", e);
+                    })?
+                )
+                .map_err(|e| {
+                    error!("Timeout failed: {}", e);
+                })?;
+            }
+        }
+
+        if user_data.borrow().full_resync_requested {
+            reset_rooms(&mut user_data.borrow_mut(), &frontend_chan_tx);
+            since = None;
+        }
+    }
+}
+
+/// A filter for room events that only includes the event types we show.
+/// Members are lazy-loaded, because large rooms have thousands of them.
+fn room_event_filter() -> JsonValue {
+    json!({
+        "types": [
+            "m.room.message",
+            "m.room.name",
+            "m.room.topic",
+            "m.room.canonical_alias",
+            "m.room.avatar",
+            "m.room.member",
+        ],
+        "lazy_load_members": true,
+    })
+}
+
+/// The filter for sync requests.
+fn sync_filter() -> JsonValue {
+    let mut timeline_filter = room_event_filter();
+    timeline_filter["limit"] = json!(SYNC_TIMELINE_LIMIT);
+
+    json!({
+        "room": {
+            "state": room_event_filter(),
+            "timeline": timeline_filter,
+            "ephemeral": { "types": [] },
+        },
+        "presence": { "types": [] },
+    })
+}
+
+/// The ID of the sync filter, which is uploaded once per account and again
+/// whenever `sync_filter` changes. `None` if uploading it failed, in which
+/// case syncing works just as well, only with bigger responses.
+#[async]
+fn sync_filter_id(user_data: Rc<RefCell<UserData>>) -> Result<Option<String>, ()> {
+    let definition = sync_filter();
+    let (http_client, homeserver_url, access_token, user_id) = {
+        let user_data = user_data.borrow();
+        let stored_session = match user_data.stored_session {
+            Some(ref stored_session) => stored_session,
+            None => return Ok(None),
+        };
+
+        if let Some(ref filter) = stored_session.sync_filter {
+            if filter.definition == definition {
+                return Ok(Some(filter.id.clone()));
+            }
+        }
+
+        let (http_client, homeserver_url, access_token) = match user_data.api_params() {
+            Some(params) => params,
+            None => return Ok(None),
+        };
+        (http_client, homeserver_url, access_token, stored_session.user_id.clone())
+    };
+
+    let result = await!(api::upload_filter(
+        http_client,
+        homeserver_url,
+        access_token,
+        user_id,
+        definition.clone(),
+    ));
+    let id = match result {
+        Ok(id) => id,
+        Err(e) => {
+            error!("Uploading the sync filter failed, syncing without it: {}", e);
+            return Ok(None);
+        }
+    };
+
+    if let Some(ref mut stored_session) = user_data.borrow_mut().stored_session {
+        stored_session.sync_filter = Some(StoredFilter {
+            id: id.clone(),
+            definition,
+        });
+        if let Err(e) = stored_session.save() {
+            error!("Failed to save session of {}: {}", stored_session.user_id, e);
+        }
+    }
+
+    Ok(Some(id))
+}
+
+/// Open the cache of the account that was just connected. If it is up to
+/// date with `next_batch`, the token the session continues syncing from, the
+/// cached rooms are shown right away.
+///
+/// Returns the token to sync from, which is `None` if the rooms have to be
+/// filled in by an initial sync.
+fn open_cache(
+    user_data: &mut UserData,
+    next_batch: Option<String>,
+    frontend_chan_tx: &FrontendSender,
+) -> Option<String> {
+    let user_id = user_data.stored_session.as_ref()?.user_id.clone();
+    let cache = match Cache::open(&user_id) {
+        Ok(cache) => cache,
+        Err(e) => {
+            error!("Failed to open the cache of {}: {}", user_id, e);
+            return None;
+        }
+    };
+
+    // A new session starts with an initial sync, even if something is left
+    // over from an earlier one
+    let cached = match next_batch {
+        Some(next_batch) => match cache.load() {
+            Ok(Some(ref cached)) if cached.next_batch != next_batch => {
+                warn!(
+                    "The cache of {} is out of date, starting with an initial sync",
+                    user_id
+                );
+                None
+            }
+            Ok(cached) => cached,
+            Err(e) => {
+                error!(
+                    "Failed to load the cache of {}, starting with an initial sync: {}",
+                    user_id, e
+                );
+                None
+            }
+        },
+        None => None,
+    };
+
+    let since = match cached {
+        Some(cached) => {
+            let mut rooms = cached.rooms;
+            rooms.set_own_user_id(user_id.clone());
+            rooms.send_all(frontend_chan_tx);
+
+            for (room_id, events) in cached.timelines {
+                // TODO: Handle channel send errors?
+                let _ = frontend_chan_tx.send(FrontendCommand::TimelineEventsAppended {
+                    room_id,
+                    events,
+                });
+            }
+
+            user_data.rooms = rooms;
+            Some(cached.next_batch)
+        }
+        None => {
+            if let Err(e) = cache.clear() {
+                error!("Failed to clear the cache of {}: {}", user_id, e);
+                return None;
+            }
+            None
+        }
+    };
+
+    user_data.cache = Some(cache);
+    since
+}
+
+/// Write what changed with the last sync response to the cache. If that
+/// fails, the cache is cleared and not used anymore.
+fn update_cache(user_data: &mut UserData, next_batch: &str) {
+    let result = match user_data.cache {
+        Some(ref mut cache) => cache.store(&mut user_data.rooms, next_batch),
+        None => {
+            // Don't let them pile up
+            user_data.rooms.take_changes();
+            return;
+        }
+    };
+
+    if let Err(e) = result {
+        error!("Failed to update the cache, not using it anymore: {}", e);
+        if let Some(cache) = user_data.cache.take() {
+            if let Err(e) = cache.clear() {
+                error!("Failed to clear the cache: {}", e);
+            }
+        }
+    }
+}
+
+/// Forget all rooms and the sync token, so everything is filled in again by
+/// an initial sync.
+fn reset_rooms(user_data: &mut UserData, frontend_chan_tx: &FrontendSender) {
+    info!("Starting a full resync");
+    user_data.full_resync_requested = false;
+
+    // Rooms that are still there are added again by the initial sync
+    for room_id in user_data.rooms.room_ids() {
+        // TODO: Handle channel send errors?
+        let _ = frontend_chan_tx.send(FrontendCommand::RoomLeft { room_id });
+    }
+    user_data.rooms = Rooms::default();
+
+    if let Some(ref mut stored_session) = user_data.stored_session {
+        user_data.rooms.set_own_user_id(stored_session.user_id.clone());
+
+        stored_session.next_batch = None;
+        if let Err(e) = stored_session.save() {
+            error!("Failed to save session of {}: {}", stored_session.user_id, e);
+        }
+    }
+
+    if let Some(ref cache) = user_data.cache {
+        if let Err(e) = cache.clear() {
+            error!("Failed to clear the cache: {}", e);
+        }
+    }
+}
+
+#[async]
+fn upgrade_guest(
+    user_data: Rc<RefCell<UserData>>,
+    frontend_chan_tx: FrontendSender,
+    username: String,
+    password: String,
+    email: Option<String>,
+    id_server: Option<String>,
+) -> Result<(), ()> {
+    let (http_client, homeserver_url, access_token) = match user_data.borrow().api_params() {
+        Some(params) => params,
+        None => return Err(upgrade_failed(&frontend_chan_tx, "not logged in yet")),
+    };
+    let device_id = match user_data.borrow().stored_session {
+        Some(ref stored_session) if stored_session.is_guest => stored_session.device_id.clone(),
+        _ => return Err(upgrade_failed(&frontend_chan_tx, "this is not a guest account")),
+    };
+
+    let (response_tx, response_rx) = futures::sync::mpsc::unbounded();
+    user_data.borrow_mut().registration_response_tx = Some(response_tx);
+
+    let result = await!(register::register(
+        http_client,
+        homeserver_url.clone(),
+        RegistrationData {
+            username,
+            password,
+            email,
+            id_server,
+            device_id: Some(device_id),
+            guest_access_token: Some(access_token),
+        },
+        response_rx,
+        frontend_chan_tx.clone(),
+    ));
+    user_data.borrow_mut().registration_response_tx = None;
+    let session = result.map_err(|reason| match reason {
+        ConnectError::Cancelled => info!("Upgrading the guest account was cancelled"),
+        reason => upgrade_failed(&frontend_chan_tx, &reason.to_string()),
+    })?;
+
+    let user_id = session.user_id().clone();
+    let access_token = session.access_token().to_owned();
+    {
+        let user_id = user_id.clone();
+        let access_token = access_token.clone();
+        let _ = await!(secret_store::run(move |secret_store| {
+            if let Err(e) = secret_store.store(&user_id, &access_token) {
+                error!("Failed to store access token of {}: {}", user_id, e);
+            }
+        }));
+    }
+
+    // The sync notices the new client and continues with it
+    let client = ruma_client::Client::https(homeserver_url, Some(session)).map_err(|e| {
+        error!("Failed to create client: {:?}", e);
+    })?;
+
+    let guest_user_id = {
+        let mut user_data = user_data.borrow_mut();
+        let user_data = &mut *user_data;
+        user_data.client = client;
+        user_data.user_id = Some(user_id.clone());
+        user_data.rooms.set_own_user_id(user_id.clone());
+
+        let mut guest_user_id = None;
+        if let Some(ref mut stored_session) = user_data.stored_session {
+            if stored_session.user_id != user_id {
+                user_data.txn_ids = Some(TxnIdGenerator::load(&user_id));
+
+                // The cache is kept with the rest of the guest's data, so
+                // everything is written to a new one with the next sync
+                // response
+                user_data.cache = match Cache::open(&user_id) {
+                    Ok(cache) => {
+                        user_data.rooms.mark_all_changed();
+                        Some(cache)
+                    }
+                    Err(e) => {
+                        error!("Failed to open the cache of {}: {}", user_id, e);
+                        None
+                    }
+                };
+                guest_user_id = Some(mem::replace(&mut stored_session.user_id, user_id.clone()));
+            }
+            stored_session.access_token = access_token;
+            stored_session.is_guest = false;
+
+            if let Err(e) = stored_session.save() {
+                error!("Failed to save session of {}: {}", user_id, e);
+            }
+        }
+        guest_user_id
+    };
+
+    // The account got a new user ID, so the guest's session is stored under
+    // a name nothing will look for anymore
+    if let Some(guest_user_id) = guest_user_id {
+        if let Err(e) = session_store::remove(&guest_user_id) {
+            error!("Failed to remove session of {}: {}", guest_user_id, e);
+        }
+        let _ = await!(secret_store::run(move |secret_store| {
+            if let Err(e) = secret_store.delete(&guest_user_id) {
+                error!("Failed to delete access token of {}: {}", guest_user_id, e);
+            }
+        }));
+    }
+
+    // TODO: Handle channel send errors?
+    let _ = frontend_chan_tx.send(FrontendCommand::GuestUpgraded { user_id });
+
+    Ok(())
+}
+
+fn upgrade_failed(frontend_chan_tx: &FrontendSender, message: &str) {
+    error!("Upgrading the guest account failed: {}", message);
+    // TODO: Handle channel send errors?
+    let _ = frontend_chan_tx.send(FrontendCommand::RegistrationFailed {
+        message: message.to_owned(),
+    });
+}
+
+#[async]
+fn fetch_directory(
+    user_data: Rc<RefCell<UserData>>,
+    frontend_chan_tx: FrontendSender,
+    server: Option<String>,
+    search_term: Option<String>,
+    since: Option<String>,
+    generation: u32,
+) -> Result<(), ()> {
+    let (http_client, homeserver_url, access_token) =
+        user_data.borrow().api_params().ok_or_else(|| {
+            error!("fetch_directory: Not logged in yet!");
+            // TODO: Handle channel send errors?
+            let _ = frontend_chan_tx.send(FrontendCommand::DirectoryFetchFailed {
+                generation,
+                message: "not logged in yet".to_owned(),
+            });
+        })?;
+
+    let is_next_page = since.is_some();
+    let result = await!(api::get_public_rooms(
+        http_client,
+        homeserver_url,
+        access_token,
+        server,
+        search_term,
+        since,
+        DIRECTORY_PAGE_SIZE,
+    ));
+
+    // TODO: Handle channel send errors?
+    match result {
+        Ok(response) => {
+            let _ = frontend_chan_tx.send(FrontendCommand::DirectoryResults {
+                generation,
+                rooms: response.chunk,
+                next_batch: response.next_batch,
+                is_next_page,
+            });
+
+            Ok(())
+        }
+        Err(e) => {
+            error!("Fetching the room directory failed: {}", e);
+            let _ = frontend_chan_tx.send(FrontendCommand::DirectoryFetchFailed {
+                generation,
+                message: e.to_string(),
+            });
+
+            Err(())
+        }
+    }
+}
+
+#[async]
+fn join_room(
+    user_data: Rc<RefCell<UserData>>,
+    frontend_chan_tx: FrontendSender,
+    room_id_or_alias: String,
+    server_names: Vec<String>,
+) -> Result<(), ()> {
+    let (http_client, homeserver_url, access_token) =
+        user_data.borrow().api_params().ok_or_else(|| {
+            error!("join_room: Not logged in yet!");
+        })?;
+
+    let result = await!(api::join_room(
+        http_client,
+        homeserver_url,
+        access_token,
+        room_id_or_alias.clone(),
+        server_names,
+    ));
+
+    // TODO: Handle channel send errors?
+    match result {
+        Ok(room_id) => {
+            let _ = frontend_chan_tx.send(FrontendCommand::RoomJoinSucceeded { room_id });
+            Ok(())
+        }
+        Err(e) => {
+            error!("Joining {} failed: {}", room_id_or_alias, e);
+            let _ = frontend_chan_tx.send(FrontendCommand::RoomJoinFailed {
+                room_id_or_alias,
+                message: e.to_string(),
+            });
+            Err(())
+        }
+    }
+}
+
+#[async]
+fn create_room(
+    user_data: Rc<RefCell<UserData>>,
+    frontend_chan_tx: FrontendSender,
+    name: Option<String>,
+    alias: Option<String>,
+    visibility: RoomVisibility,
+    preset: RoomPreset,
+) -> Result<(), ()> {
+    let (http_client, homeserver_url, access_token) =
+        user_data.borrow().api_params().ok_or_else(|| {
+            error!("create_room: Not logged in yet!");
+        })?;
+
+    let result = await!(api::create_room(
+        http_client,
+        homeserver_url,
+        access_token,
+        name,
+        alias,
+        visibility,
+        preset,
+    ));
+
+    // TODO: Handle channel send errors?
+    match result {
+        Ok(room_id) => {
+            let _ = frontend_chan_tx.send(FrontendCommand::RoomCreated { room_id });
+            Ok(())
+        }
+        Err(e) => {
+            error!("Creating a room failed: {}", e);
+            let _ = frontend_chan_tx.send(FrontendCommand::RoomCreationFailed {
+                message: e.to_string(),
+            });
+            Err(())
+        }
+    }
+}
+
+#[async]
+fn log_out(
+    user_data: Rc<RefCell<UserData>>,
+    frontend_chan_tx: FrontendSender,
+    all_devices: bool,
+) -> Result<(), ()> {
+    let (api_params, own_user_id, room_ids) = {
+        let user_data = user_data.borrow();
+        let own_user_id = user_data
+            .client
+            .session()
+            .map(|session| session.user_id().clone());
+
+        (user_data.api_params(), own_user_id, user_data.rooms.room_ids())
+    };
+
+    // Without an access token, logging in didn't finish and there is nothing
+    // to invalidate
+    let mut server_error = None;
+    if let Some((http_client, homeserver_url, access_token)) = api_params {
+        if let Err(e) = await!(api::log_out(
+            http_client,
+            homeserver_url,
+            access_token,
+            all_devices,
+        )) {
+            // The account is forgotten anyway, the user asked to get rid of it
+            warn!("Failed to invalidate the access token: {}", e);
+            server_error = Some(e.to_string());
+        }
+    }
+
+    if let Some(user_id) = own_user_id {
+        if let Err(e) = session_store::remove(&user_id) {
+            error!("Failed to delete session of {}: {}", user_id, e);
+        }
+        let _ = await!(secret_store::run(move |secret_store| {
+            if let Err(e) = secret_store.delete(&user_id) {
+                error!("Failed to delete access token of {}: {}", user_id, e);
+            }
+        }));
+    }
+
+    // TODO: Handle channel send errors?
+    let _ = frontend_chan_tx.send(FrontendCommand::LoggedOut {
+        room_ids,
+        server_error,
+    });
+
+    Ok(())
+}
+
+#[async]
+fn leave_room(
+    user_data: Rc<RefCell<UserData>>,
+    frontend_chan_tx: FrontendSender,
+    room_id: RoomId,
+) -> Result<(), ()> {
+    let (http_client, homeserver_url, access_token) =
+        user_data.borrow().api_params().ok_or_else(|| {
+            error!("leave_room: Not logged in yet!");
+        })?;
+
+    let result = await!(api::leave_room(
+        http_client,
+        homeserver_url,
+        access_token,
+        room_id.clone(),
+    ));
+
+    // The room is removed from the room list once the leave event comes in
+    // through sync.
+    // TODO: Handle channel send errors?
+    match result {
+        Ok(()) => {
+            let _ = frontend_chan_tx.send(FrontendCommand::RoomLeaveSucceeded { room_id });
+            Ok(())
+        }
+        Err(e) => {
+            error!("Leaving {} failed: {}", room_id, e);
+            let _ = frontend_chan_tx.send(FrontendCommand::RoomLeaveFailed {
+                room_id,
+                message: e.to_string(),
+            });
+            Err(())
+        }
+    }
+}
+
+#[async]
+fn invite_user(
+    user_data: Rc<RefCell<UserData>>,
+    frontend_chan_tx: FrontendSender,
+    room_id: RoomId,
+    user_id: UserId,
+) -> Result<(), ()> {
+    let (http_client, homeserver_url, access_token) =
+        user_data.borrow().api_params().ok_or_else(|| {
+            error!("invite_user: Not logged in yet!");
+        })?;
+
+    let result = await!(api::invite_user(
+        http_client,
+        homeserver_url,
+        access_token,
+        room_id.clone(),
+        user_id.clone(),
+    ));
+
+    // TODO: Handle channel send errors?
+    match result {
+        Ok(()) => {
+            let _ = frontend_chan_tx.send(FrontendCommand::InviteSucceeded { room_id, user_id });
+            Ok(())
+        }
+        Err(e) => {
+            error!("Inviting {} to {} failed: {}", user_id, room_id, e);
+            let _ = frontend_chan_tx.send(FrontendCommand::InviteFailed {
+                room_id,
+                user_id,
+                message: e.to_string(),
+            });
+            Err(())
+        }
+    }
+}
+
+#[async]
+fn fetch_members(
+    user_data: Rc<RefCell<UserData>>,
+    frontend_chan_tx: FrontendSender,
+    room_id: RoomId,
+) -> Result<(), ()> {
+    // The frontend already knows about them, either from the cache or from
+    // an earlier fetch
+    if user_data.borrow().rooms.members_loaded(&room_id) {
+        return Ok(());
+    }
+
+    let (http_client, homeserver_url, access_token) = match user_data.borrow().api_params() {
+        Some(params) => params,
+        None => {
+            error!("fetch_members: Not logged in yet!");
+            // TODO: Handle channel send errors?
+            let _ = frontend_chan_tx.send(FrontendCommand::MembersFetchFailed { room_id });
+            return Err(());
+        }
+    };
+
+    let events = match await!(api::get_members(
+        http_client,
+        homeserver_url,
+        access_token,
+        room_id.clone(),
+    )) {
+        Ok(events) => events,
+        Err(e) => {
+            error!("Fetching the members of {} failed: {}", room_id, e);
+            // TODO: Handle channel send errors?
+            let _ = frontend_chan_tx.send(FrontendCommand::MembersFetchFailed { room_id });
+            return Err(());
+        }
+    };
+
+    user_data
+        .borrow_mut()
+        .rooms
+        .add_members(&room_id, &events, &frontend_chan_tx);
+
+    Ok(())
+}
+
+#[async]
+fn paginate_backwards(
+    user_data: Rc<RefCell<UserData>>,
+    frontend_chan_tx: FrontendSender,
+    room_id: RoomId,
+    limit: u32,
+) -> Result<(), ()> {
+    let (http_client, homeserver_url, access_token) = match user_data.borrow().api_params() {
+        Some(params) => params,
+        None => {
+            error!("paginate_backwards: Not logged in yet!");
+            // TODO: Handle channel send errors?
+            let _ = frontend_chan_tx.send(FrontendCommand::PaginationFailed { room_id });
+            return Err(());
+        }
+    };
+
+    // Nothing to paginate from before the room showed up in a sync response
+    if !user_data.borrow().rooms.has_timeline(&room_id) {
+        // TODO: Handle channel send errors?
+        let _ = frontend_chan_tx.send(FrontendCommand::TimelineEventsPrepended {
+            room_id,
+            events: Vec::new(),
+            reached_start: false,
+        });
+        return Ok(());
+    }
+
+    // Pages without any events we show are skipped, since the frontend only
+    // asks for more when it got something to show
+    let (events, reached_start) = loop {
+        let from = match user_data.borrow().rooms.prev_batch(&room_id) {
+            Some(from) => from,
+            None => break (Vec::new(), true),
+        };
+
+        let result = await!(api::get_messages(
+            http_client.clone(),
+            homeserver_url.clone(),
+            access_token.clone(),
+            room_id.clone(),
+            from.clone(),
+            limit,
+            room_event_filter(),
+        ));
+        let messages = match result {
+            Ok(messages) => messages,
+            Err(e) => {
+                error!("Fetching older events of {} failed: {}", room_id, e);
+                // TODO: Handle channel send errors?
+                let _ = frontend_chan_tx.send(FrontendCommand::PaginationFailed { room_id });
+                return Err(());
+            }
+        };
+        let reached_start = messages.end.is_none();
+
+        let events = user_data.borrow_mut().rooms.prepend_timeline_events(
+            &room_id,
+            &from,
+            messages,
+            &frontend_chan_tx,
+        );
+        match events {
+            Some(ref events) if events.is_empty() && !reached_start => continue,
+            Some(events) => break (events, reached_start),
+            // The events are already outdated, because of a gap
+            None => break (Vec::new(), false),
+        }
+    };
+
+    // TODO: Handle channel send errors?
+    let _ = frontend_chan_tx.send(FrontendCommand::TimelineEventsPrepended {
+        room_id,
+        events,
+        reached_start,
+    });
+
+    Ok(())
+}
+
+#[async]
+fn search_user_directory(
+    user_data: Rc<RefCell<UserData>>,
+    frontend_chan_tx: FrontendSender,
+    search_term: String,
+) -> Result<(), ()> {
+    let (http_client, homeserver_url, access_token) =
+        user_data.borrow().api_params().ok_or_else(|| {
+            error!("search_user_directory: Not logged in yet!");
+        })?;
+
+    let users = await!(api::search_user_directory(
+        http_client,
+        homeserver_url,
+        access_token,
+        search_term.clone(),
+        USER_SEARCH_LIMIT,
+    ))
+    .map_err(|e| {
+        // Autocompletion is just a convenience, so this isn't shown in the UI
+        error!("Searching the user directory failed: {}", e);
+    })?;
+
+    // TODO: Handle channel send errors?
+    let _ = frontend_chan_tx.send(FrontendCommand::UserDirectoryResults { search_term, users });
+
+    Ok(())
+}
+
+#[async]
+fn ignore_user(
+    user_data: Rc<RefCell<UserData>>,
+    frontend_chan_tx: FrontendSender,
+    user_id: UserId,
+) -> Result<(), ()> {
+    let ((http_client, homeserver_url, access_token), own_user_id) = {
+        let user_data = user_data.borrow();
+        let own_user_id = user_data
+            .client
+            .session()
+            .map(|session| session.user_id().clone());
+
+        (
+            user_data.api_params().ok_or_else(|| {
+                error!("ignore_user: Not logged in yet!");
+            })?,
+            own_user_id.ok_or_else(|| {
+                error!("ignore_user: Not logged in yet!");
+            })?,
+        )
+    };
+
+    // The whole list is replaced, so it's fetched first instead of relying on
+    // sync, which might not have delivered it yet right after logging in
+    let result = match await!(api::get_ignored_users(
+        http_client.clone(),
+        homeserver_url.clone(),
+        access_token.clone(),
+        own_user_id.clone(),
+    )) {
+        Ok(ref ignored_users) if ignored_users.contains(&user_id) => Ok(()),
+        Ok(mut ignored_users) => {
+            ignored_users.push(user_id.clone());
+            await!(api::set_ignored_users(
+                http_client,
+                homeserver_url,
+                access_token,
+                own_user_id,
+                ignored_users,
+            ))
+        }
+        Err(e) => Err(e),
+    };
+
+    // TODO: Handle channel send errors?
+    match result {
+        Ok(()) => {
+            let _ = frontend_chan_tx.send(FrontendCommand::UserIgnored { user_id });
+            Ok(())
+        }
+        Err(e) => {
+            error!("Ignoring {} failed: {}", user_id, e);
+            let _ = frontend_chan_tx.send(FrontendCommand::IgnoreUserFailed {
+                user_id,
+                message: e.to_string(),
+            });
+            Err(())
+        }
+    }
+}
+
+#[async]
+fn fetch_thumbnail(
+    user_data: Rc<RefCell<UserData>>,
+    frontend_chan_tx: FrontendSender,
+    mxc_url: String,
+    width: u32,
+    height: u32,
+) -> Result<(), ()> {
+    let (http_client, homeserver_url) = {
+        let user_data = user_data.borrow();
+        (
+            user_data.http_client.clone(),
+            user_data.homeserver_url.clone(),
+        )
+    };
+
+    let data = await!(api::get_thumbnail(
+        http_client,
+        homeserver_url,
+        mxc_url.clone(),
+        width,
+        height,
+    ))
+    .map_err(|e| {
+        error!("Fetching thumbnail of {} failed: {}", mxc_url, e);
+    })?;
+
+    // TODO: Handle channel send errors?
+    let _ = frontend_chan_tx.send(FrontendCommand::ThumbnailFetched { mxc_url, data });
+
+    Ok(())
+}
+
+#[async]
+fn send_text_message(
+    tokio_handle: tokio_core::reactor::Handle,
+    user_data: Rc<RefCell<UserData>>,
+    frontend_chan_tx: FrontendSender,
+    room_id: RoomId,
+    message_content: String,
+) -> Result<(), ()> {
+    let (txn_id, author_name) = {
+        let mut user_data = user_data.borrow_mut();
+        let user_id = user_data.user_id.clone().ok_or_else(|| {
+            error!("send_text_message: Not logged in yet!");
+        })?;
+        let author_name = user_data
+            .display_name
+            .clone()
+            .unwrap_or_else(|| user_id.to_string());
+        let txn_id = user_data
+            .txn_ids
+            .as_mut()
+            .ok_or_else(|| {
+                error!("send_text_message: Not logged in yet!");
+            })?
+            .next();
+
+        (txn_id, author_name)
+    };
+
+    // The transaction ID doubles as the local ID of the message in the
+    // frontend, which is how it finds the local echo again.
+    // TODO: Handle channel send errors?
+    let _ = frontend_chan_tx.send(FrontendCommand::DisplayTextMessage {
+        room_id: room_id.clone(),
+        author_name,
+        message_content: message_content.clone(),
+        local_id: txn_id.clone(),
+    });
+
+    await!(send_text_message_with_txn_id(
+        tokio_handle,
+        user_data,
+        frontend_chan_tx,
+        room_id,
+        txn_id,
+        message_content,
+    ))
+}
+
+/// Send a text message, retrying a few times if that fails.
+///
+/// All attempts use the same transaction ID, so the homeserver doesn't create
+/// a second event if it received the message but we didn't get the response.
+#[async]
+fn send_text_message_with_txn_id(
+    tokio_handle: tokio_core::reactor::Handle,
+    user_data: Rc<RefCell<UserData>>,
+    frontend_chan_tx: FrontendSender,
+    room_id: RoomId,
+    txn_id: String,
+    message_content: String,
+) -> Result<(), ()> {
+    let mut attempt = 1;
+
+    loop {
+        let result = await!(r0::send::send_message_event::call(
+            user_data.borrow().client.clone(),
+            r0::send::send_message_event::Request {
+                room_id: room_id.clone(),
+                event_type: EventType::RoomMessage,
+                txn_id: txn_id.clone(),
+                data: MessageEventContent::Text(TextMessageEventContent {
+                    body: message_content.clone(),
+                    msgtype: MessageType::Text,
+                }),
+            }
+        ));
+
+        match result {
+            Ok(response) => {
+                let _ = frontend_chan_tx.send(FrontendCommand::LocalEchoSent {
+                    room_id,
+                    local_id: txn_id,
+                    event_id: response.event_id,
+                });
+
+                return Ok(());
+            }
+            Err(e) if attempt < SEND_ATTEMPTS => {
+                warn!(
+                    "Sending a text message to {} failed (attempt {} of {}): {:?}",
+                    room_id, attempt, SEND_ATTEMPTS, e
+                );
+
+                let delay = Duration::from_secs(1 << attempt);
+                await!(
+                    tokio_core::reactor::Timeout::new(delay, &tokio_handle).map_err(|e| {
+                        error!("Failed to create timeout: {}", e);
+                    })?
+                )
+                .map_err(|e| {
+                    error!("Timeout failed: {}", e);
+                })?;
+
+                attempt += 1;
+            }
+            Err(e) => {
+                error!("Sending a text message to {} failed: {:?}", room_id, e);
+
+                let _ = frontend_chan_tx.send(FrontendCommand::LocalEchoFailed {
+                    room_id,
+                    local_id: txn_id,
+                });
+
+                return Err(());
+            }
+        }
+    }
+}
+
+// TODO: This function should have Result::Error = Error, but we currently never
+// return Err(_) anywhere
+#[async]
+fn bg_main(
+    tokio_handle: tokio_core::reactor::Handle,
+    backend_chan_rx: futures::sync::mpsc::Receiver<MatrixCommand>,
+    frontend_chan_tx: std::sync::mpsc::Sender<(InternalUserId, FrontendCommand)>,
+) -> Result<(), ()> {
+    let mut next_user_id = 0;
+    // Shared with the spawned syncs, which remove their account if they fail
+    // to connect
+    let sync_cancel_chan_txs = Rc::new(RefCell::new(HashMap::new()));
+    let user_data_map = Rc::new(RefCell::new(HashMap::new()));
+
+    #[async]
+    for command in backend_chan_rx {
+        match command {
+            MatrixCommand::Connect {
+                homeserver,
+                connection_method,
+            } => {
+                let frontend_chan_tx = FrontendSender::new(next_user_id, frontend_chan_tx.clone());
+
+                // Replaced once the homeserver's actual URL is discovered
+                let homeserver_url = match homeserver.initial_url() {
+                    Some(url) => url,
+                    None => {
+                        error!("Invalid homeserver address: {:?}", homeserver);
+                        report_connect_failure(
+                            &frontend_chan_tx,
+                            ConnectError::Other("The homeserver address is not valid.".to_owned()),
+                        );
+                        continue;
+                    }
+                };
+
+                // Restored sessions get their access token from the secret
+                // store before syncing
+                let client = match ruma_client::Client::https(homeserver_url.clone(), None) {
+                    Ok(client) => client,
+                    Err(e) => {
+                        error!("Failed to create client: {:?}", e);
+                        report_connect_failure(
+                            &frontend_chan_tx,
+                            ConnectError::Other("Couldn't connect to the homeserver.".to_owned()),
+                        );
+                        continue;
+                    }
+                };
+
+                let (sync_cancel_chan_tx, sync_cancel_chan_rx) = futures::sync::oneshot::channel();
+                sync_cancel_chan_txs
+                    .borrow_mut()
+                    .insert(next_user_id, sync_cancel_chan_tx);
+
+                let user_data = Rc::new(RefCell::new(UserData {
+                    client,
+                    http_client: api::http_client(),
+                    homeserver_url,
+                    // Known after discovery or logging in
+                    server_name: None,
+                    device_id: None,
+                    user_id: None,
+                    display_name: None,
+                    rooms: Rooms::default(),
+                    cache: None,
+                    txn_ids: None,
+                    registration_response_tx: None,
+                    full_resync_requested: false,
+                    stored_session: None,
+                }));
+                user_data_map
+                    .borrow_mut()
+                    .insert(next_user_id, user_data.clone());
+
+                tokio_handle.spawn(
+                    sync(
+                        tokio_handle.clone(),
+                        homeserver,
+                        connection_method,
+                        user_data,
+                        frontend_chan_tx.clone(),
+                    )
+                        .select(sync_cancel_chan_rx.map_err(|e| {
+                            error!("some error occured with a rx sync channel: {}", e);
+                        }))
+                        .then({
+                            let user_id = next_user_id;
+                            let sync_cancel_chan_txs = sync_cancel_chan_txs.clone();
+                            let user_data_map = user_data_map.clone();
+
+                            move |_| {
+                                // Sync never terminates successfully, so we only reach this when
+                                // an error occurs (which is logged inside sync), the sync is
+                                // cancelled or receiving a message from sync_cancel_chan_rx
+                                // failed (logged in map_err above). If connecting failed, the
+                                // frontend forgets the account, so do the same here.
+                                let connected = user_data_map
+                                    .borrow()
+                                    .get(&user_id)
+                                    .map_or(false, |user_data| {
+                                        user_data.borrow().stored_session.is_some()
+                                    });
+                                if !connected {
+                                    sync_cancel_chan_txs.borrow_mut().remove(&user_id);
+                                    user_data_map.borrow_mut().remove(&user_id);
+                                }
+
+                                Ok(())
+                            }
+                        }),
+                );
+
+                next_user_id += 1;
+            }
+            MatrixCommand::Disconnect(user_id) => match sync_cancel_chan_txs
+                .borrow_mut()
+                .entry(user_id)
+            {
+                HashMapEntry::Vacant(_) => {
+                    error!("Tried to disconnect unknown user with user_id {}!", user_id);
+                }
+                HashMapEntry::Occupied(o) => {
+                    let (_, sync_cancel_chan_tx) = o.remove_entry();
+                    let _ = sync_cancel_chan_tx.send(());
+                    user_data_map.borrow_mut().remove(&user_id);
+                }
+            },
+            MatrixCommand::Logout {
+                user_id,
+                all_devices,
+            } => {
+                // Stop syncing first, the sync would fail once the access
+                // token is invalidated
+                let sync_cancel_chan_tx = sync_cancel_chan_txs.borrow_mut().remove(&user_id);
+                if let Some(sync_cancel_chan_tx) = sync_cancel_chan_tx {
+                    let _ = sync_cancel_chan_tx.send(());
+                }
+
+                let user_data = user_data_map.borrow_mut().remove(&user_id);
+                match user_data {
+                    Some(user_data) => {
+                        tokio_handle.spawn(log_out(
+                            user_data,
+                            FrontendSender::new(user_id, frontend_chan_tx.clone()),
+                            all_devices,
+                        ));
+                    }
+                    None => error!("Tried to log out unknown user with user_id {}!", user_id),
+                }
+            }
+            MatrixCommand::UserSpecificCommand { user_id, command } => match user_data_map
+                .borrow()
+                .get(&user_id)
+            {
+                Some(user_data) => {
+                    let frontend_chan_tx = FrontendSender::new(user_id, frontend_chan_tx.clone());
+
+                    match command {
+                        UserSpecificCommand::FetchDirectory {
+                            server,
+                            search_term,
+                            since,
+                            generation,
+                        } => {
+                            tokio_handle.spawn(fetch_directory(
+                                user_data.clone(),
+                                frontend_chan_tx.clone(),
+                                server,
+                                search_term,
+                                since,
+                                generation,
+                            ));
+                        }
+                        UserSpecificCommand::SendTextMessage {
+                            room_id,
+                            message_content,
+                        } => {
+                            tokio_handle.spawn(send_text_message(
+                                tokio_handle.clone(),
+                                user_data.clone(),
+                                frontend_chan_tx.clone(),
+                                room_id,
+                                message_content,
+                            ));
+                        }
+                        UserSpecificCommand::RetryTextMessage {
+                            room_id,
+                            local_id,
+                            message_content,
+                        } => {
+                            tokio_handle.spawn(send_text_message_with_txn_id(
+                                tokio_handle.clone(),
+                                user_data.clone(),
+                                frontend_chan_tx.clone(),
+                                room_id,
+                                local_id,
+                                message_content,
+                            ));
+                        }
+                        UserSpecificCommand::JoinRoom {
+                            room_id_or_alias,
+                            server_names,
+                        } => {
+                            tokio_handle.spawn(join_room(
+                                user_data.clone(),
+                                frontend_chan_tx.clone(),
+                                room_id_or_alias,
+                                server_names,
+                            ));
+                        }
+                        UserSpecificCommand::CreateRoom {
+                            name,
+                            alias,
+                            visibility,
+                            preset,
+                        } => {
+                            tokio_handle.spawn(create_room(
+                                user_data.clone(),
+                                frontend_chan_tx.clone(),
+                                name,
+                                alias,
+                                visibility,
+                                preset,
+                            ));
+                        }
+                        UserSpecificCommand::LeaveRoom { room_id } => {
+                            tokio_handle.spawn(leave_room(
+                                user_data.clone(),
+                                frontend_chan_tx.clone(),
+                                room_id,
+                            ));
+                        }
+                        UserSpecificCommand::InviteUser { room_id, user_id } => {
+                            tokio_handle.spawn(invite_user(
+                                user_data.clone(),
+                                frontend_chan_tx.clone(),
+                                room_id,
+                                user_id,
+                            ));
+                        }
+                        UserSpecificCommand::FetchMembers { room_id } => {
+                            tokio_handle.spawn(fetch_members(
+                                user_data.clone(),
+                                frontend_chan_tx.clone(),
+                                room_id,
+                            ));
+                        }
+                        UserSpecificCommand::PaginateBackwards { room_id, limit } => {
+                            tokio_handle.spawn(paginate_backwards(
+                                user_data.clone(),
+                                frontend_chan_tx.clone(),
+                                room_id,
+                                limit,
+                            ));
+                        }
+                        UserSpecificCommand::SearchUserDirectory { search_term } => {
+                            tokio_handle.spawn(search_user_directory(
+                                user_data.clone(),
+                                frontend_chan_tx.clone(),
+                                search_term,
+                            ));
+                        }
+                        UserSpecificCommand::IgnoreUser { user_id } => {
+                            tokio_handle.spawn(ignore_user(
+                                user_data.clone(),
+                                frontend_chan_tx.clone(),
+                                user_id,
+                            ));
+                        }
+                        UserSpecificCommand::FetchThumbnail {
+                            mxc_url,
+                            width,
+                            height,
+                        } => {
+                            tokio_handle.spawn(fetch_thumbnail(
+                                user_data.clone(),
+                                frontend_chan_tx.clone(),
+                                mxc_url,
+                                width,
+                                height,
+                            ));
+                        }
+                        UserSpecificCommand::UpgradeGuest {
+                            username,
+                            password,
+                            email,
+                            id_server,
+                        } => {
+                            tokio_handle.spawn(upgrade_guest(
+                                user_data.clone(),
+                                frontend_chan_tx.clone(),
+                                username,
+                                password,
+                                email,
+                                id_server,
+                            ));
+                        }
+                        UserSpecificCommand::AnswerRegistrationStage(response) => {
+                            match user_data.borrow().registration_response_tx {
+                                Some(ref response_tx) => {
+                                    let _ = response_tx.unbounded_send(response);
+                                }
+                                None => error!("No registration in progress to answer"),
+                            }
+                        }
+                        UserSpecificCommand::FullResync => {
+                            // Picked up by the sync loop after the current request
+                            user_data.borrow_mut().full_resync_requested = true;
+                        }
+                    }
+                }
+                None => {
+                    error!(
+                        "UserSpecificCommand requested for unknown user with user_id {}",
+                        user_id
+                    );
+                }
+            },
+            MatrixCommand::Quit => break,
+        }
+    }
+
+    for (_, sync_cancel_chan_tx) in sync_cancel_chan_txs.borrow_mut().drain() {
+        let _ = sync_cancel_chan_tx.send(());
+    }
+
+    Ok(())
+}
+
+pub fn run(
+    backend_chan_rx: futures::sync::mpsc::Receiver<MatrixCommand>,
+    frontend_chan_tx: std::sync::mpsc::Sender<(InternalUserId, FrontendCommand)>,
+) {
+    let mut core = tokio_core::reactor::Core::new().unwrap();
+    let tokio_handle = core.handle();
+
+    match core.run(bg_main(tokio_handle, backend_chan_rx, frontend_chan_tx)) {
+        Ok(_) => {}
+        Err(()) => {
+            // TODO: Show error message in UI. Quit / restart thread?
+            error!("background thread crashed!");
+        }
+    };
+}
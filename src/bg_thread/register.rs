@@ -0,0 +1,342 @@
+// Account registration, including the user-interactive authentication stages
+// the homeserver may ask for.
+
+use futures::{
+    self,
+    prelude::{async, await},
+    Stream,
+};
+use ring::rand::{SecureRandom, SystemRandom};
+use ruma_client::Session;
+use serde_json::{self, Value as JsonValue};
+use url::Url;
+
+use super::{
+    api::{self, ConnectError, HttpClient},
+    FrontendSender,
+};
+use crate::app::FrontendCommand;
+
+const DUMMY_STAGE: &str = "m.login.dummy";
+const TERMS_STAGE: &str = "m.login.terms";
+const EMAIL_STAGE: &str = "m.login.email.identity";
+
+/// A stage of the registration that needs input from the user.
+pub enum RegistrationStage {
+    /// The user has to accept the homeserver's policies.
+    AcceptTerms { policies: Vec<Policy> },
+    /// An email with a validation link was sent to `email`, which the user
+    /// has to open before continuing.
+    ValidateEmail { email: String },
+}
+
+pub struct Policy {
+    pub name: String,
+    pub url: String,
+}
+
+/// The user's answer to a `RegistrationStage`.
+pub enum RegistrationResponse {
+    /// The user accepted the policies, or opened the validation link.
+    Continue,
+    Cancel,
+}
+
+/// Everything needed to register an account.
+pub struct RegistrationData {
+    pub username: String,
+    pub password: String,
+    /// Required if the homeserver only allows registering with an email
+    /// address.
+    pub email: Option<String>,
+    /// Host name of the identity server that validates the email address.
+    pub id_server: Option<String>,
+    pub device_id: Option<String>,
+    /// The access token of the guest account to upgrade to a full account,
+    /// if any. The guest's rooms are kept.
+    pub guest_access_token: Option<String>,
+}
+
+/// Register a new account.
+///
+/// Stages that need input from the user are reported to the frontend with
+/// `FrontendCommand::RegistrationStage`, and the user's answers are read from
+/// `response_rx`. Cancelling them fails with `ConnectError::Cancelled`.
+#[async]
+pub fn register(
+    http_client: HttpClient,
+    homeserver_url: Url,
+    data: RegistrationData,
+    response_rx: futures::sync::mpsc::UnboundedReceiver<RegistrationResponse>,
+    frontend_chan_tx: FrontendSender,
+) -> Result<Session, ConnectError> {
+    let mut body = json!({
+        "username": data.username,
+        "password": data.password,
+        "initial_device_display_name": super::device_display_name(),
+    });
+    if let Some(ref device_id) = data.device_id {
+        body["device_id"] = json!(device_id);
+    }
+    if let Some(ref guest_access_token) = data.guest_access_token {
+        body["guest_access_token"] = json!(guest_access_token);
+    }
+
+    await!(complete_auth(
+        http_client,
+        homeserver_url,
+        body,
+        data.email,
+        data.id_server,
+        response_rx,
+        frontend_chan_tx,
+    ))
+}
+
+/// Send a registration request and complete all authentication stages the
+/// homeserver asks for, returning the new session.
+#[async]
+fn complete_auth(
+    http_client: HttpClient,
+    homeserver_url: Url,
+    mut body: JsonValue,
+    email: Option<String>,
+    id_server: Option<String>,
+    mut response_rx: futures::sync::mpsc::UnboundedReceiver<RegistrationResponse>,
+    frontend_chan_tx: FrontendSender,
+) -> Result<Session, ConnectError> {
+    // Identifies us to the identity server when validating the email address
+    let client_secret = random_string(32).map_err(|()| {
+        ConnectError::Other("Couldn't generate a secret for the identity server.".to_owned())
+    })?;
+    let mut email_sid = None;
+    let mut email_send_attempt = 0;
+
+    loop {
+        let result = await!(api::register(
+            http_client.clone(),
+            homeserver_url.clone(),
+            "user",
+            body.clone(),
+        ));
+
+        let auth_info = match result {
+            Ok(response) => {
+                return session_from_response(&response).map_err(|()| {
+                    ConnectError::Other("The homeserver sent an invalid response.".to_owned())
+                })
+            }
+            Err(api::Error::Matrix {
+                status, ref body, ..
+            }) if status.as_u16() == 401 && body["flows"].is_array() => body.clone(),
+            Err(e) => return Err(e.into()),
+        };
+
+        // Set if the previous attempt at a stage failed
+        let stage_error = auth_info["error"].as_str().map(ToOwned::to_owned);
+        let session = auth_info["session"].as_str().map(ToOwned::to_owned);
+        let completed: Vec<String> = auth_info["completed"]
+            .as_array()
+            .map(|stages| {
+                stages
+                    .iter()
+                    .filter_map(|s| s.as_str().map(ToOwned::to_owned))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let flow = match choose_flow(&auth_info, email.is_some()) {
+            Some(flow) => flow,
+            None => {
+                return Err(ConnectError::Unsupported(
+                    "The homeserver requires a registration step fest doesn't support.".to_owned(),
+                ))
+            }
+        };
+        let stage = match flow.iter().find(|stage| !completed.contains(stage)) {
+            Some(stage) => stage.clone(),
+            // Everything is completed, but the server still wants more
+            None => {
+                return Err(ConnectError::Other(
+                    auth_info["error"]
+                        .as_str()
+                        .unwrap_or("Authentication failed")
+                        .to_owned(),
+                ))
+            }
+        };
+
+        let mut auth = json!({ "type": stage });
+        if let Some(session) = session {
+            auth["session"] = json!(session);
+        }
+
+        match stage.as_str() {
+            DUMMY_STAGE => {}
+            TERMS_STAGE => {
+                // TODO: Handle channel send errors?
+                let _ = frontend_chan_tx.send(FrontendCommand::RegistrationStage {
+                    stage: RegistrationStage::AcceptTerms {
+                        policies: policies(&auth_info),
+                    },
+                    error: stage_error,
+                });
+
+                response_rx = await!(wait_for_continue(response_rx))?;
+            }
+            EMAIL_STAGE => {
+                let email = email.clone().ok_or_else(|| {
+                    ConnectError::Other(
+                        "Chose a registration flow with email validation without email address"
+                            .to_owned(),
+                    )
+                })?;
+                let id_server = match id_server {
+                    Some(ref id_server) => id_server.clone(),
+                    None => {
+                        return Err(ConnectError::Other(
+                            "An identity server is required to validate the email address."
+                                .to_owned(),
+                        ))
+                    }
+                };
+
+                if email_sid.is_none() {
+                    email_send_attempt += 1;
+                    let sid = await!(api::request_registration_email_token(
+                        http_client.clone(),
+                        homeserver_url.clone(),
+                        id_server.clone(),
+                        client_secret.clone(),
+                        email.clone(),
+                        email_send_attempt,
+                    ))
+                    .map_err(ConnectError::from)?;
+
+                    email_sid = Some(sid);
+                }
+
+                let _ = frontend_chan_tx.send(FrontendCommand::RegistrationStage {
+                    stage: RegistrationStage::ValidateEmail { email },
+                    error: stage_error,
+                });
+
+                response_rx = await!(wait_for_continue(response_rx))?;
+
+                auth["threepid_creds"] = json!({
+                    "sid": email_sid,
+                    "client_secret": client_secret,
+                    "id_server": id_server,
+                });
+            }
+            _ => unreachable!("choose_flow only returns flows with supported stages"),
+        }
+
+        body["auth"] = auth;
+    }
+}
+
+/// Pick the first flow that only consists of stages we support.
+fn choose_flow(auth_info: &JsonValue, has_email: bool) -> Option<Vec<String>> {
+    let flows = auth_info["flows"].as_array()?;
+
+    flows
+        .iter()
+        .filter_map(|flow| {
+            flow["stages"]
+                .as_array()?
+                .iter()
+                .map(|stage| stage.as_str().map(ToOwned::to_owned))
+                .collect::<Option<Vec<_>>>()
+        })
+        .find(|stages| {
+            stages.iter().all(|stage| match stage.as_str() {
+                DUMMY_STAGE | TERMS_STAGE => true,
+                EMAIL_STAGE => has_email,
+                _ => false,
+            })
+        })
+}
+
+/// Extract the policies to accept from the parameters of the terms stage,
+/// preferring the English versions.
+fn policies(auth_info: &JsonValue) -> Vec<Policy> {
+    let policies = match auth_info["params"][TERMS_STAGE]["policies"].as_object() {
+        Some(policies) => policies,
+        None => return Vec::new(),
+    };
+
+    policies
+        .iter()
+        .filter_map(|(id, policy)| {
+            let translation = policy["en"].as_object().or_else(|| {
+                policy
+                    .as_object()?
+                    .values()
+                    .find_map(|value| value.as_object())
+            })?;
+
+            Some(Policy {
+                name: translation
+                    .get("name")
+                    .and_then(|name| name.as_str())
+                    .unwrap_or(id)
+                    .to_owned(),
+                url: translation.get("url")?.as_str()?.to_owned(),
+            })
+        })
+        .collect()
+}
+
+/// Wait for the user to answer a stage, failing if they cancelled.
+#[async]
+fn wait_for_continue(
+    response_rx: futures::sync::mpsc::UnboundedReceiver<RegistrationResponse>,
+) -> Result<futures::sync::mpsc::UnboundedReceiver<RegistrationResponse>, ConnectError> {
+    let (response, response_rx) =
+        await!(response_rx.into_future()).map_err(|_| ConnectError::Cancelled)?;
+
+    match response {
+        Some(RegistrationResponse::Continue) => Ok(response_rx),
+        Some(RegistrationResponse::Cancel) | None => {
+            info!("Registration cancelled");
+            Err(ConnectError::Cancelled)
+        }
+    }
+}
+
+/// Get the session from the response to logging in or registering.
+pub(super) fn session_from_response(response: &JsonValue) -> Result<Session, ()> {
+    let user_id = serde_json::from_value(response["user_id"].clone()).map_err(|e| {
+        error!("Invalid user_id in login response: {}", e);
+    })?;
+
+    match (
+        response["access_token"].as_str(),
+        response["device_id"].as_str(),
+    ) {
+        (Some(access_token), Some(device_id)) => Ok(Session::new(
+            access_token.to_owned(),
+            user_id,
+            device_id.to_owned(),
+        )),
+        _ => {
+            error!("Incomplete login response: {}", response);
+            Err(())
+        }
+    }
+}
+
+fn random_string(len: usize) -> Result<String, ()> {
+    const CHARS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+
+    let mut bytes = vec![0; len];
+    SystemRandom::new().fill(&mut bytes).map_err(|_| {
+        error!("Failed to generate random data");
+    })?;
+
+    Ok(bytes
+        .iter()
+        .map(|b| CHARS[*b as usize % CHARS.len()] as char)
+        .collect())
+}
@@ -0,0 +1,1146 @@
+use std::{
+    cmp,
+    collections::{HashMap, HashSet},
+    convert::TryFrom,
+    mem,
+};
+
+use ruma_client::api::r0::sync::sync_events;
+use ruma_events::{
+    collections::{
+        all::{RoomEvent, StateEvent},
+        only::Event,
+    },
+    room::{
+        member::{MemberEvent, MembershipState},
+        message::{MessageEvent, MessageEventContent},
+    },
+    stripped::StrippedState,
+};
+use ruma_identifiers::{EventId, RoomAliasId, RoomId, UserId};
+
+use super::{
+    api::{self, Messages},
+    FrontendSender,
+};
+use crate::app::FrontendCommand;
+
+/// A timeline event, in the form the frontend displays it.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TimelineEvent {
+    pub event_id: EventId,
+    pub sender: UserId,
+    /// The display name of the sender at the time the event was processed.
+    pub sender_name: Option<String>,
+    /// Milliseconds since the unix epoch, according to the sender's homeserver.
+    pub origin_server_ts: u64,
+    /// The transaction ID the event was sent with, if it was sent by this
+    /// session.
+    pub transaction_id: Option<String>,
+    pub content: TimelineEventContent,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub enum TimelineEventContent {
+    Text { body: String },
+    Emote { body: String },
+    Notice { body: String },
+    /// A message type we can't render properly yet (images, files, ...).
+    /// `body` is the textual fallback sent along with it.
+    OtherMessage { body: String },
+    StateChange(RoomStateChange),
+}
+
+/// A change to the state of a room that is relevant for the frontend.
+#[derive(Clone, Serialize, Deserialize)]
+pub enum RoomStateChange {
+    Name(Option<String>),
+    Topic(String),
+    CanonicalAlias(RoomAliasId),
+    Avatar(String),
+    Member {
+        user_id: UserId,
+        display_name: Option<String>,
+        membership: MembershipState,
+    },
+}
+
+/// The section of the room list a room is shown in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum RoomSection {
+    Favourites,
+    DirectChats,
+    Rooms,
+    Invites,
+    LowPriority,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(super) struct Member {
+    pub display_name: Option<String>,
+    pub membership: MembershipState,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct RoomState {
+    name: Option<String>,
+    topic: Option<String>,
+    canonical_alias: Option<RoomAliasId>,
+    avatar_url: Option<String>,
+    /// The members the server suggests to name the room after, since with
+    /// lazy-loaded members we may not know the others.
+    #[serde(default)]
+    heroes: Vec<UserId>,
+    /// The counts of all members, which we may not know either.
+    #[serde(default)]
+    joined_member_count: Option<u64>,
+    #[serde(default)]
+    invited_member_count: Option<u64>,
+    /// Cached separately from the rest, because there can be a lot of them.
+    #[serde(skip)]
+    members: HashMap<UserId, Member>,
+}
+
+impl RoomState {
+    fn apply(&mut self, change: &RoomStateChange) {
+        match *change {
+            RoomStateChange::Name(ref name) => self.name = name.clone(),
+            RoomStateChange::Topic(ref topic) => self.topic = Some(topic.clone()),
+            RoomStateChange::CanonicalAlias(ref alias) => self.canonical_alias = Some(alias.clone()),
+            RoomStateChange::Avatar(ref url) => self.avatar_url = Some(url.clone()),
+            RoomStateChange::Member {
+                ref user_id,
+                ref display_name,
+                ref membership,
+            } => {
+                self.members.insert(
+                    user_id.clone(),
+                    Member {
+                        display_name: display_name.clone(),
+                        membership: membership.clone(),
+                    },
+                );
+            }
+        }
+    }
+
+    /// The changes that lead from an empty state to this one.
+    fn changes(&self) -> Vec<RoomStateChange> {
+        let mut changes = Vec::new();
+        if let Some(ref name) = self.name {
+            changes.push(RoomStateChange::Name(Some(name.clone())));
+        }
+        if let Some(ref topic) = self.topic {
+            changes.push(RoomStateChange::Topic(topic.clone()));
+        }
+        if let Some(ref alias) = self.canonical_alias {
+            changes.push(RoomStateChange::CanonicalAlias(alias.clone()));
+        }
+        if let Some(ref url) = self.avatar_url {
+            changes.push(RoomStateChange::Avatar(url.clone()));
+        }
+        changes.extend(self.members.iter().map(|(user_id, member)| {
+            RoomStateChange::Member {
+                user_id: user_id.clone(),
+                display_name: member.display_name.clone(),
+                membership: member.membership.clone(),
+            }
+        }));
+
+        changes
+    }
+
+    /// Take over what a sync response's summary of the room says. Anything
+    /// it leaves out didn't change.
+    fn apply_summary(&mut self, summary: api::RoomSummary) {
+        if let Some(heroes) = summary.heroes {
+            self.heroes = heroes;
+        }
+        if let Some(count) = summary.joined_member_count {
+            self.joined_member_count = Some(count);
+        }
+        if let Some(count) = summary.invited_member_count {
+            self.invited_member_count = Some(count);
+        }
+    }
+
+    fn member_name(&self, user_id: &UserId) -> Option<String> {
+        self.members
+            .get(user_id)
+            .and_then(|member| member.display_name.clone())
+    }
+
+    /// Calculate the name to show for the room, roughly following the
+    /// algorithm from the client-server spec.
+    fn display_name(&self, own_user_id: Option<&UserId>) -> String {
+        if let Some(ref name) = self.name {
+            if !name.is_empty() {
+                return name.clone();
+            }
+        }
+
+        if let Some(ref alias) = self.canonical_alias {
+            return alias.to_string();
+        }
+
+        let mut other_members: Vec<_> = if self.heroes.is_empty() {
+            let mut names: Vec<_> = self
+                .members
+                .iter()
+                .filter(|&(user_id, member)| {
+                    Some(user_id) != own_user_id
+                        && (member.membership == MembershipState::Join
+                            || member.membership == MembershipState::Invite)
+                })
+                .map(|(user_id, member)| {
+                    member
+                        .display_name
+                        .clone()
+                        .unwrap_or_else(|| user_id.to_string())
+                })
+                .collect();
+            names.sort();
+            names
+        } else {
+            // The server already put them in order
+            self.heroes
+                .iter()
+                .filter(|&user_id| Some(user_id) != own_user_id)
+                .map(|user_id| {
+                    self.member_name(user_id)
+                        .unwrap_or_else(|| user_id.to_string())
+                })
+                .collect()
+        };
+
+        // The heroes are only some of the members of bigger rooms
+        let other_member_count = match (self.joined_member_count, self.invited_member_count) {
+            (Some(joined), Some(invited)) => {
+                cmp::max((joined + invited).saturating_sub(1) as usize, other_members.len())
+            }
+            _ => other_members.len(),
+        };
+
+        match (other_member_count, other_members.len()) {
+            (_, 0) => "Empty room".to_owned(),
+            (1, _) => other_members.remove(0),
+            (2, 1) => format!("{} and 1 other", other_members[0]),
+            (2, _) => format!("{} and {}", other_members[0], other_members[1]),
+            (n, _) => format!("{} and {} others", other_members[0], n - 1),
+        }
+    }
+}
+
+/// What we know about a room. Everything but the members is cached as one
+/// JSON object.
+#[derive(Serialize, Deserialize)]
+pub(super) struct Room {
+    /// `None` until the room shows up in a sync response.
+    membership: Option<MembershipState>,
+    /// Who invited us, if `membership` is `Invite`.
+    inviter: Option<UserId>,
+    state: RoomState,
+    tags: HashSet<String>,
+    display_name: String,
+    section: RoomSection,
+    notification_count: u64,
+    highlight_count: u64,
+    /// Whether all members were fetched, instead of just those that sync
+    /// responses contain because of lazy loading.
+    #[serde(default)]
+    members_loaded: bool,
+    /// Whether we know where the timeline starts, i.e. it was in a sync
+    /// response or loaded from the cache.
+    #[serde(skip)]
+    has_timeline: bool,
+    /// The token to fetch the events before the oldest one we know, `None`
+    /// if there are none.
+    #[serde(skip)]
+    prev_batch: Option<String>,
+}
+
+impl Room {
+    fn new() -> Self {
+        Room {
+            membership: None,
+            inviter: None,
+            state: RoomState::default(),
+            tags: HashSet::new(),
+            display_name: String::new(),
+            section: RoomSection::Rooms,
+            notification_count: 0,
+            highlight_count: 0,
+            members_loaded: false,
+            has_timeline: false,
+            prev_batch: None,
+        }
+    }
+
+    pub fn member(&self, user_id: &UserId) -> Option<&Member> {
+        self.state.members.get(user_id)
+    }
+
+    pub fn set_member(&mut self, user_id: UserId, member: Member) {
+        self.state.members.insert(user_id, member);
+    }
+
+    /// Start the timeline with events whose predecessors can be fetched with
+    /// `prev_batch`.
+    pub fn start_timeline(&mut self, prev_batch: Option<String>) {
+        self.has_timeline = true;
+        self.prev_batch = prev_batch;
+    }
+}
+
+/// Events that were added to the start or the end of a room's timeline
+/// together.
+pub(super) struct TimelineChunk {
+    /// Oldest first.
+    pub events: Vec<TimelineEvent>,
+    /// The token to fetch the events before the first one.
+    pub prev_batch: Option<String>,
+}
+
+/// What changed since the rooms were last written to the cache.
+#[derive(Default)]
+pub(super) struct CacheChanges {
+    /// Whether the direct chats changed.
+    pub account_data: bool,
+    /// Rooms whose summary, i.e. anything but the members, changed.
+    pub rooms: HashSet<RoomId>,
+    pub members: HashMap<RoomId, HashSet<UserId>>,
+    /// Rooms whose earlier timeline events were dropped because of a gap.
+    pub cleared_timelines: HashSet<RoomId>,
+    /// New events of each room, in the order they were synced.
+    pub appended_events: HashMap<RoomId, Vec<TimelineChunk>>,
+    /// Older events of each room, in the order they were fetched.
+    pub prepended_events: HashMap<RoomId, Vec<TimelineChunk>>,
+}
+
+impl CacheChanges {
+    fn clear_timeline(&mut self, room_id: &RoomId) {
+        self.cleared_timelines.insert(room_id.clone());
+        self.appended_events.remove(room_id);
+        self.prepended_events.remove(room_id);
+    }
+}
+
+/// The rooms of one account, as far as we know about them from sync
+/// responses.
+///
+/// They are kept between runs in the `cache`.
+#[derive(Default)]
+pub struct Rooms {
+    own_user_id: Option<UserId>,
+    /// Rooms that are marked as direct chats in the `m.direct` account data.
+    direct_rooms: HashSet<RoomId>,
+    rooms: HashMap<RoomId, Room>,
+    changes: CacheChanges,
+}
+
+impl Rooms {
+    pub(super) fn from_cache(direct_rooms: HashSet<RoomId>, rooms: HashMap<RoomId, Room>) -> Self {
+        Rooms {
+            own_user_id: None,
+            direct_rooms,
+            rooms,
+            changes: CacheChanges::default(),
+        }
+    }
+
+    pub(super) fn room(&self, room_id: &RoomId) -> Option<&Room> {
+        self.rooms.get(room_id)
+    }
+
+    pub(super) fn direct_rooms(&self) -> &HashSet<RoomId> {
+        &self.direct_rooms
+    }
+
+    /// Everything that changed since the last call.
+    pub(super) fn take_changes(&mut self) -> CacheChanges {
+        mem::replace(&mut self.changes, CacheChanges::default())
+    }
+
+    /// Treat everything as changed, so all of it is written to a new cache.
+    pub(super) fn mark_all_changed(&mut self) {
+        self.changes.account_data = true;
+        for (room_id, room) in &self.rooms {
+            self.changes.rooms.insert(room_id.clone());
+            self.changes.members.insert(
+                room_id.clone(),
+                room.state.members.keys().cloned().collect(),
+            );
+        }
+    }
+
+    pub fn set_own_user_id(&mut self, user_id: UserId) {
+        self.own_user_id = Some(user_id);
+    }
+
+    /// All rooms the account is in or was invited to.
+    pub fn room_ids(&self) -> Vec<RoomId> {
+        self.rooms.keys().cloned().collect()
+    }
+
+    /// Tell the frontend about all rooms, as if they just showed up in a
+    /// sync response. Used after loading them from the cache.
+    pub fn send_all(&self, frontend_chan_tx: &FrontendSender) {
+        for (room_id, room) in &self.rooms {
+            match room.membership {
+                Some(MembershipState::Join) | Some(MembershipState::Invite) => {}
+                _ => continue,
+            }
+
+            // TODO: Handle channel send errors?
+            let changes = room.state.changes();
+            if !changes.is_empty() {
+                let _ = frontend_chan_tx.send(FrontendCommand::RoomStateChanged {
+                    room_id: room_id.clone(),
+                    changes,
+                });
+            }
+
+            let _ = frontend_chan_tx.send(membership_command(room_id, room));
+
+            if room.notification_count != 0 || room.highlight_count != 0 {
+                let _ = frontend_chan_tx.send(FrontendCommand::RoomUnreadCountChanged {
+                    room_id: room_id.clone(),
+                    notification_count: room.notification_count,
+                    highlight_count: room.highlight_count,
+                });
+            }
+        }
+    }
+
+    pub fn members_loaded(&self, room_id: &RoomId) -> bool {
+        self.rooms
+            .get(room_id)
+            .map_or(false, |room| room.members_loaded)
+    }
+
+    /// Add the members fetched with `api::get_members` and notify the
+    /// frontend about them.
+    pub fn add_members(
+        &mut self,
+        room_id: &RoomId,
+        events: &[MemberEvent],
+        frontend_chan_tx: &FrontendSender,
+    ) {
+        match self.rooms.get_mut(room_id) {
+            Some(room) => room.members_loaded = true,
+            None => return,
+        }
+        self.changes.rooms.insert(room_id.clone());
+
+        self.add_unknown_members(room_id, events, frontend_chan_tx);
+        self.update_room_summary(room_id, frontend_chan_tx);
+    }
+
+    /// Whether the timeline of a room was started by a sync response or the
+    /// cache, so there is a `prev_batch` token unless there are no older
+    /// events.
+    pub fn has_timeline(&self, room_id: &RoomId) -> bool {
+        self.rooms.get(room_id).map_or(false, |room| room.has_timeline)
+    }
+
+    /// The token to fetch the events before the oldest one we know of a room,
+    /// `None` if there are none or it isn't known yet.
+    pub fn prev_batch(&self, room_id: &RoomId) -> Option<String> {
+        self.rooms.get(room_id)?.prev_batch.clone()
+    }
+
+    /// Add a page of older events fetched from `from` with
+    /// `api::get_messages`.
+    ///
+    /// Returns the events that can be shown, oldest first, or `None` if the
+    /// timeline was restarted in the meantime because of a gap, so the page
+    /// doesn't fit anymore.
+    pub fn prepend_timeline_events(
+        &mut self,
+        room_id: &RoomId,
+        from: &str,
+        messages: Messages,
+        frontend_chan_tx: &FrontendSender,
+    ) -> Option<Vec<TimelineEvent>> {
+        if self.rooms.get(room_id)?.prev_batch.as_ref().map(String::as_str) != Some(from) {
+            return None;
+        }
+
+        self.add_unknown_members(room_id, &messages.members, frontend_chan_tx);
+
+        let room = self.rooms.get_mut(room_id)?;
+        // Older events don't change the current state, so unlike new events
+        // they are all shown with the current sender names
+        let events: Vec<_> = messages
+            .events
+            .iter()
+            .rev()
+            .filter_map(|event| timeline_event(event, &room.state))
+            .collect();
+        room.prev_batch = messages.end.clone();
+
+        if !events.is_empty() {
+            self.changes
+                .prepended_events
+                .entry(room_id.clone())
+                .or_insert_with(Vec::new)
+                .push(TimelineChunk {
+                    events: events.clone(),
+                    prev_batch: messages.end,
+                });
+        }
+
+        Some(events)
+    }
+
+    /// Update the known rooms from a sync response and notify the frontend
+    /// about everything that changed.
+    pub fn process_sync_response(
+        &mut self,
+        response: api::SyncResponse,
+        frontend_chan_tx: &FrontendSender,
+    ) {
+        for event in &response.account_data.events {
+            if let Event::Direct(ref ev) = *event {
+                self.direct_rooms = ev.content.values().flat_map(|rooms| rooms.clone()).collect();
+                self.changes.account_data = true;
+
+                let room_ids: Vec<_> = self.rooms.keys().cloned().collect();
+                for room_id in room_ids {
+                    self.update_room_summary(&room_id, frontend_chan_tx);
+                }
+            }
+        }
+
+        let mut room_summaries = response.room_summaries;
+        for (room_id, joined_room) in response.rooms.join {
+            let room = self.rooms.entry(room_id.clone()).or_insert_with(Room::new);
+            if let Some(summary) = room_summaries.remove(&room_id) {
+                room.state.apply_summary(summary);
+            }
+            self.changes.rooms.insert(room_id.clone());
+            self.apply_state_events(&room_id, &joined_room.state.events, frontend_chan_tx);
+            let timeline_events =
+                self.convert_timeline_events(&room_id, joined_room.timeline, frontend_chan_tx);
+
+            for event in &joined_room.account_data.events {
+                if let Event::Tag(ref ev) = *event {
+                    if let Some(room) = self.rooms.get_mut(&room_id) {
+                        room.tags = ev.content.tags.keys().cloned().collect();
+                    }
+                }
+            }
+
+            self.set_membership(&room_id, MembershipState::Join, frontend_chan_tx);
+            self.update_room_summary(&room_id, frontend_chan_tx);
+            self.set_unread_counts(
+                &room_id,
+                joined_room
+                    .unread_notifications
+                    .notification_count
+                    .map_or(0, Into::into),
+                joined_room
+                    .unread_notifications
+                    .highlight_count
+                    .map_or(0, Into::into),
+                frontend_chan_tx,
+            );
+            send_timeline_events(&room_id, timeline_events, frontend_chan_tx);
+        }
+
+        for (room_id, invited_room) in response.rooms.invite {
+            self.rooms.entry(room_id.clone()).or_insert_with(Room::new);
+            self.changes.rooms.insert(room_id.clone());
+
+            let changes: Vec<_> = invited_room
+                .invite_state
+                .events
+                .iter()
+                .filter_map(stripped_state_change)
+                .collect();
+            self.apply_state_changes(&room_id, changes, frontend_chan_tx);
+
+            // The invite itself is part of the stripped state
+            let own_user_id = self.own_user_id.as_ref().map(ToString::to_string);
+            let inviter = invited_room
+                .invite_state
+                .events
+                .iter()
+                .filter_map(|event| match *event {
+                    StrippedState::RoomMember(ref ev)
+                        if ev.content.membership == MembershipState::Invite
+                            && Some(&ev.state_key) == own_user_id.as_ref() =>
+                    {
+                        Some(ev.sender.clone())
+                    }
+                    _ => None,
+                })
+                .last();
+            if let Some(room) = self.rooms.get_mut(&room_id) {
+                room.inviter = inviter;
+            }
+
+            self.set_membership(&room_id, MembershipState::Invite, frontend_chan_tx);
+            self.update_room_summary(&room_id, frontend_chan_tx);
+        }
+
+        for (room_id, left_room) in response.rooms.leave {
+            // Rooms we never saw before are not interesting once left
+            if !self.rooms.contains_key(&room_id) {
+                continue;
+            }
+            self.changes.rooms.insert(room_id.clone());
+
+            self.apply_state_events(&room_id, &left_room.state.events, frontend_chan_tx);
+            let timeline_events =
+                self.convert_timeline_events(&room_id, left_room.timeline, frontend_chan_tx);
+            send_timeline_events(&room_id, timeline_events, frontend_chan_tx);
+            self.set_membership(&room_id, MembershipState::Leave, frontend_chan_tx);
+        }
+    }
+
+    fn set_membership(
+        &mut self,
+        room_id: &RoomId,
+        membership: MembershipState,
+        frontend_chan_tx: &FrontendSender,
+    ) {
+        let own_user_id = self.own_user_id.as_ref();
+        let direct_rooms = &self.direct_rooms;
+        let room = match self.rooms.get_mut(room_id) {
+            Some(room) => room,
+            None => return,
+        };
+
+        if room.membership.as_ref() == Some(&membership) {
+            return;
+        }
+
+        room.membership = Some(membership.clone());
+        room.display_name = room.state.display_name(own_user_id);
+        room.section = section(room_id, room, direct_rooms);
+
+        // TODO: Handle channel send errors?
+        let _ = frontend_chan_tx.send(membership_command(room_id, room));
+    }
+
+    /// Recalculate the display name and section of a room, and notify the
+    /// frontend if either of them changed.
+    fn update_room_summary(&mut self, room_id: &RoomId, frontend_chan_tx: &FrontendSender) {
+        let own_user_id = self.own_user_id.as_ref();
+        let direct_rooms = &self.direct_rooms;
+        let room = match self.rooms.get_mut(room_id) {
+            Some(room) => room,
+            None => return,
+        };
+
+        match room.membership {
+            Some(MembershipState::Join) | Some(MembershipState::Invite) => {}
+            _ => return,
+        }
+
+        let display_name = room.state.display_name(own_user_id);
+        if display_name != room.display_name {
+            room.display_name = display_name.clone();
+            self.changes.rooms.insert(room_id.clone());
+            let _ = frontend_chan_tx.send(FrontendCommand::RoomRenamed {
+                room_id: room_id.clone(),
+                display_name,
+            });
+        }
+
+        let section = section(room_id, room, direct_rooms);
+        if section != room.section {
+            room.section = section;
+            self.changes.rooms.insert(room_id.clone());
+            let _ = frontend_chan_tx.send(FrontendCommand::RoomSectionChanged {
+                room_id: room_id.clone(),
+                section,
+            });
+        }
+    }
+
+    fn set_unread_counts(
+        &mut self,
+        room_id: &RoomId,
+        notification_count: u64,
+        highlight_count: u64,
+        frontend_chan_tx: &FrontendSender,
+    ) {
+        let room = match self.rooms.get_mut(room_id) {
+            Some(room) => room,
+            None => return,
+        };
+
+        if room.notification_count != notification_count || room.highlight_count != highlight_count
+        {
+            room.notification_count = notification_count;
+            room.highlight_count = highlight_count;
+
+            let _ = frontend_chan_tx.send(FrontendCommand::RoomUnreadCountChanged {
+                room_id: room_id.clone(),
+                notification_count,
+                highlight_count,
+            });
+        }
+    }
+
+    fn apply_state_events(
+        &mut self,
+        room_id: &RoomId,
+        events: &[StateEvent],
+        frontend_chan_tx: &FrontendSender,
+    ) {
+        let changes = events.iter().filter_map(state_change).collect();
+        self.apply_state_changes(room_id, changes, frontend_chan_tx);
+    }
+
+    /// Add members that were sent along with events, e.g. by
+    /// `api::get_messages`. Members that are already known are left alone,
+    /// because they come from sync responses, which may be newer.
+    fn add_unknown_members(
+        &mut self,
+        room_id: &RoomId,
+        events: &[MemberEvent],
+        frontend_chan_tx: &FrontendSender,
+    ) {
+        let changes = match self.rooms.get(room_id) {
+            Some(room) => events
+                .iter()
+                .filter_map(member_change)
+                .filter(|change| match *change {
+                    RoomStateChange::Member { ref user_id, .. } => room.member(user_id).is_none(),
+                    _ => false,
+                })
+                .collect(),
+            None => return,
+        };
+
+        self.apply_state_changes(room_id, changes, frontend_chan_tx);
+    }
+
+    fn apply_state_changes(
+        &mut self,
+        room_id: &RoomId,
+        changes: Vec<RoomStateChange>,
+        frontend_chan_tx: &FrontendSender,
+    ) {
+        if changes.is_empty() {
+            return;
+        }
+
+        if let Some(room) = self.rooms.get_mut(room_id) {
+            for change in &changes {
+                room.state.apply(change);
+                record_member_change(&mut self.changes, room_id, change);
+            }
+        }
+
+        let _ = frontend_chan_tx.send(FrontendCommand::RoomStateChanged {
+            room_id: room_id.clone(),
+            changes,
+        });
+    }
+
+    /// Apply the state changes of a timeline from a sync response and convert
+    /// its events to the form the frontend displays.
+    ///
+    /// If events were left out before the timeline (it is `limited`), the
+    /// earlier events don't connect to it anymore. They are dropped, and the
+    /// frontend is told about the gap.
+    fn convert_timeline_events(
+        &mut self,
+        room_id: &RoomId,
+        timeline: sync_events::Timeline,
+        frontend_chan_tx: &FrontendSender,
+    ) -> Vec<TimelineEvent> {
+        let room = match self.rooms.get_mut(room_id) {
+            Some(room) => room,
+            None => return Vec::new(),
+        };
+
+        if timeline.limited == Some(true) {
+            if room.has_timeline {
+                debug!("Gap in the timeline of {}", room_id);
+                self.changes.clear_timeline(room_id);
+                // TODO: Handle channel send errors?
+                let _ = frontend_chan_tx.send(FrontendCommand::TimelineGap {
+                    room_id: room_id.clone(),
+                });
+            }
+            room.start_timeline(timeline.prev_batch.clone());
+        } else if !room.has_timeline {
+            room.start_timeline(timeline.prev_batch.clone());
+        }
+
+        let mut timeline_events = Vec::new();
+        for event in timeline.events {
+            // State events in the timeline update the room state before we
+            // look at the next event, so sender names are correct even if a
+            // user changed their display name in the same sync response.
+            if let Some(change) = room_event_state_change(&event) {
+                room.state.apply(&change);
+                record_member_change(&mut self.changes, room_id, &change);
+            }
+
+            match timeline_event(&event, &room.state) {
+                Some(timeline_event) => timeline_events.push(timeline_event),
+                None => trace!("Skipping timeline event without visible content: {:?}", event),
+            }
+        }
+
+        if !timeline_events.is_empty() {
+            self.changes
+                .appended_events
+                .entry(room_id.clone())
+                .or_insert_with(Vec::new)
+                .push(TimelineChunk {
+                    events: timeline_events.clone(),
+                    prev_batch: timeline.prev_batch,
+                });
+        }
+
+        timeline_events
+    }
+}
+
+fn record_member_change(changes: &mut CacheChanges, room_id: &RoomId, change: &RoomStateChange) {
+    if let RoomStateChange::Member { ref user_id, .. } = *change {
+        changes
+            .members
+            .entry(room_id.clone())
+            .or_insert_with(HashSet::new)
+            .insert(user_id.clone());
+    }
+}
+
+/// The command that tells the frontend about the membership of a room.
+fn membership_command(room_id: &RoomId, room: &Room) -> FrontendCommand {
+    match room.membership {
+        Some(MembershipState::Join) => FrontendCommand::RoomJoined {
+            room_id: room_id.clone(),
+            display_name: room.display_name.clone(),
+            section: room.section,
+        },
+        Some(MembershipState::Invite) => FrontendCommand::RoomInvited {
+            room_id: room_id.clone(),
+            display_name: room.display_name.clone(),
+            inviter_name: room
+                .inviter
+                .as_ref()
+                .and_then(|inviter| room.state.member_name(inviter)),
+            inviter: room.inviter.clone(),
+            avatar_url: room.state.avatar_url.clone(),
+        },
+        _ => FrontendCommand::RoomLeft {
+            room_id: room_id.clone(),
+        },
+    }
+}
+
+fn section(room_id: &RoomId, room: &Room, direct_rooms: &HashSet<RoomId>) -> RoomSection {
+    if room.membership == Some(MembershipState::Invite) {
+        RoomSection::Invites
+    } else if room.tags.contains("m.favourite") {
+        RoomSection::Favourites
+    } else if room.tags.contains("m.lowpriority") {
+        RoomSection::LowPriority
+    } else if direct_rooms.contains(room_id) {
+        RoomSection::DirectChats
+    } else {
+        RoomSection::Rooms
+    }
+}
+
+fn send_timeline_events(
+    room_id: &RoomId,
+    events: Vec<TimelineEvent>,
+    frontend_chan_tx: &FrontendSender,
+) {
+    if !events.is_empty() {
+        let _ = frontend_chan_tx.send(FrontendCommand::TimelineEventsAppended {
+            room_id: room_id.clone(),
+            events,
+        });
+    }
+}
+
+fn member_change(event: &MemberEvent) -> Option<RoomStateChange> {
+    let user_id = UserId::try_from(event.state_key.as_str())
+        .map_err(|e| {
+            warn!("Invalid state key in m.room.member event: {:?}", e);
+        })
+        .ok()?;
+
+    Some(RoomStateChange::Member {
+        user_id,
+        display_name: event.content.displayname.clone(),
+        membership: event.content.membership.clone(),
+    })
+}
+
+fn state_change(event: &StateEvent) -> Option<RoomStateChange> {
+    match *event {
+        StateEvent::RoomName(ref ev) => Some(RoomStateChange::Name(ev.content.name.clone())),
+        StateEvent::RoomTopic(ref ev) => Some(RoomStateChange::Topic(ev.content.topic.clone())),
+        StateEvent::RoomCanonicalAlias(ref ev) => {
+            Some(RoomStateChange::CanonicalAlias(ev.content.alias.clone()))
+        }
+        StateEvent::RoomAvatar(ref ev) => Some(RoomStateChange::Avatar(ev.content.url.clone())),
+        StateEvent::RoomMember(ref ev) => member_change(ev),
+        _ => None,
+    }
+}
+
+fn room_event_state_change(event: &RoomEvent) -> Option<RoomStateChange> {
+    match *event {
+        RoomEvent::RoomName(ref ev) => Some(RoomStateChange::Name(ev.content.name.clone())),
+        RoomEvent::RoomTopic(ref ev) => Some(RoomStateChange::Topic(ev.content.topic.clone())),
+        RoomEvent::RoomCanonicalAlias(ref ev) => {
+            Some(RoomStateChange::CanonicalAlias(ev.content.alias.clone()))
+        }
+        RoomEvent::RoomAvatar(ref ev) => Some(RoomStateChange::Avatar(ev.content.url.clone())),
+        RoomEvent::RoomMember(ref ev) => member_change(ev),
+        _ => None,
+    }
+}
+
+fn stripped_state_change(event: &StrippedState) -> Option<RoomStateChange> {
+    match *event {
+        StrippedState::RoomName(ref ev) => Some(RoomStateChange::Name(ev.content.name.clone())),
+        StrippedState::RoomTopic(ref ev) => Some(RoomStateChange::Topic(ev.content.topic.clone())),
+        StrippedState::RoomCanonicalAlias(ref ev) => {
+            Some(RoomStateChange::CanonicalAlias(ev.content.alias.clone()))
+        }
+        StrippedState::RoomAvatar(ref ev) => Some(RoomStateChange::Avatar(ev.content.url.clone())),
+        StrippedState::RoomMember(ref ev) => {
+            let user_id = UserId::try_from(ev.state_key.as_str()).ok()?;
+            Some(RoomStateChange::Member {
+                user_id,
+                display_name: ev.content.displayname.clone(),
+                membership: ev.content.membership.clone(),
+            })
+        }
+        _ => None,
+    }
+}
+
+fn message_content(event: &MessageEvent) -> TimelineEventContent {
+    match event.content {
+        MessageEventContent::Text(ref c) => TimelineEventContent::Text {
+            body: c.body.clone(),
+        },
+        MessageEventContent::Emote(ref c) => TimelineEventContent::Emote {
+            body: c.body.clone(),
+        },
+        MessageEventContent::Notice(ref c) => TimelineEventContent::Notice {
+            body: c.body.clone(),
+        },
+        MessageEventContent::Audio(ref c) => TimelineEventContent::OtherMessage {
+            body: c.body.clone(),
+        },
+        MessageEventContent::File(ref c) => TimelineEventContent::OtherMessage {
+            body: c.body.clone(),
+        },
+        MessageEventContent::Image(ref c) => TimelineEventContent::OtherMessage {
+            body: c.body.clone(),
+        },
+        MessageEventContent::Location(ref c) => TimelineEventContent::OtherMessage {
+            body: c.body.clone(),
+        },
+        MessageEventContent::Video(ref c) => TimelineEventContent::OtherMessage {
+            body: c.body.clone(),
+        },
+    }
+}
+
+/// Convert a room event into the representation used by the frontend.
+///
+/// `state` has to be the room state *after* the event, if it is a state event.
+fn timeline_event(event: &RoomEvent, state: &RoomState) -> Option<TimelineEvent> {
+    let mut transaction_id = None;
+    let (event_id, sender, origin_server_ts, content) = match *event {
+        RoomEvent::RoomMessage(ref ev) => {
+            transaction_id = ev
+                .unsigned
+                .as_ref()
+                .and_then(|unsigned| unsigned["transaction_id"].as_str())
+                .map(ToOwned::to_owned);
+
+            (
+                &ev.event_id,
+                &ev.sender,
+                ev.origin_server_ts,
+                message_content(ev),
+            )
+        }
+        RoomEvent::RoomName(ref ev) => (
+            &ev.event_id,
+            &ev.sender,
+            ev.origin_server_ts,
+            TimelineEventContent::StateChange(room_event_state_change(event)?),
+        ),
+        RoomEvent::RoomTopic(ref ev) => (
+            &ev.event_id,
+            &ev.sender,
+            ev.origin_server_ts,
+            TimelineEventContent::StateChange(room_event_state_change(event)?),
+        ),
+        RoomEvent::RoomMember(ref ev) => (
+            &ev.event_id,
+            &ev.sender,
+            ev.origin_server_ts,
+            TimelineEventContent::StateChange(room_event_state_change(event)?),
+        ),
+        _ => return None,
+    };
+
+    Some(TimelineEvent {
+        event_id: event_id.clone(),
+        sender: sender.clone(),
+        sender_name: state.member_name(sender),
+        origin_server_ts: origin_server_ts.into(),
+        transaction_id,
+        content,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user_id(id: &str) -> UserId {
+        UserId::try_from(id).unwrap()
+    }
+
+    fn room_state(members: &[(&str, Option<&str>, MembershipState)]) -> RoomState {
+        let mut state = RoomState::default();
+        for &(id, display_name, ref membership) in members {
+            state.apply(&RoomStateChange::Member {
+                user_id: user_id(id),
+                display_name: display_name.map(ToOwned::to_owned),
+                membership: membership.clone(),
+            });
+        }
+
+        state
+    }
+
+    #[test]
+    fn display_name_prefers_name_over_alias() {
+        let mut state = room_state(&[("@bob:example.org", None, MembershipState::Join)]);
+        state.apply(&RoomStateChange::CanonicalAlias(
+            RoomAliasId::try_from("#room:example.org").unwrap(),
+        ));
+        assert_eq!(state.display_name(None), "#room:example.org");
+
+        state.apply(&RoomStateChange::Name(Some(String::new())));
+        assert_eq!(state.display_name(None), "#room:example.org");
+
+        state.apply(&RoomStateChange::Name(Some("Room".to_owned())));
+        assert_eq!(state.display_name(None), "Room");
+    }
+
+    #[test]
+    fn display_name_from_members() {
+        let own_user_id = user_id("@alice:example.org");
+        let mut members = vec![
+            ("@alice:example.org", Some("Alice"), MembershipState::Join),
+            ("@bob:example.org", Some("Bob"), MembershipState::Join),
+            ("@carol:example.org", None, MembershipState::Invite),
+            ("@dave:example.org", Some("Dave"), MembershipState::Leave),
+        ];
+        assert_eq!(
+            room_state(&members).display_name(Some(&own_user_id)),
+            "@carol:example.org and Bob"
+        );
+
+        members.push(("@erin:example.org", Some("Erin"), MembershipState::Join));
+        assert_eq!(
+            room_state(&members).display_name(Some(&own_user_id)),
+            "@carol:example.org and 2 others"
+        );
+
+        assert_eq!(room_state(&members[..2]).display_name(Some(&own_user_id)), "Bob");
+    }
+
+    #[test]
+    fn display_name_of_empty_room() {
+        let own_user_id = user_id("@alice:example.org");
+        let state = room_state(&[
+            ("@alice:example.org", Some("Alice"), MembershipState::Join),
+            ("@bob:example.org", Some("Bob"), MembershipState::Leave),
+        ]);
+
+        assert_eq!(state.display_name(Some(&own_user_id)), "Empty room");
+        assert_eq!(RoomState::default().display_name(None), "Empty room");
+    }
+
+    fn summary(
+        heroes: Option<&[&str]>,
+        joined: Option<u64>,
+        invited: Option<u64>,
+    ) -> api::RoomSummary {
+        api::RoomSummary {
+            heroes: heroes.map(|heroes| heroes.iter().map(|id| user_id(id)).collect()),
+            joined_member_count: joined,
+            invited_member_count: invited,
+        }
+    }
+
+    #[test]
+    fn display_name_from_heroes() {
+        let own_user_id = user_id("@alice:example.org");
+        // Only some members are lazy-loaded, the others are just heroes
+        let mut state = room_state(&[
+            ("@alice:example.org", Some("Alice"), MembershipState::Join),
+            ("@bob:example.org", Some("Bob"), MembershipState::Join),
+            ("@zoe:example.org", Some("Zoe"), MembershipState::Join),
+        ]);
+        state.apply_summary(summary(
+            Some(&["@carol:example.org", "@bob:example.org"]),
+            Some(3),
+            Some(0),
+        ));
+        // In the order of the heroes, not sorted, and without Zoe
+        assert_eq!(
+            state.display_name(Some(&own_user_id)),
+            "@carol:example.org and Bob"
+        );
+    }
+
+    #[test]
+    fn display_name_counts_members_that_are_not_loaded() {
+        let own_user_id = user_id("@alice:example.org");
+        let mut state = room_state(&[("@bob:example.org", Some("Bob"), MembershipState::Join)]);
+
+        state.apply_summary(summary(Some(&["@bob:example.org"]), Some(2), Some(0)));
+        assert_eq!(state.display_name(Some(&own_user_id)), "Bob");
+
+        state.apply_summary(summary(None, None, Some(1)));
+        assert_eq!(state.display_name(Some(&own_user_id)), "Bob and 1 other");
+
+        state.apply_summary(summary(
+            Some(&["@bob:example.org", "@carol:example.org"]),
+            Some(50),
+            None,
+        ));
+        assert_eq!(state.display_name(Some(&own_user_id)), "Bob and 49 others");
+    }
+
+    #[test]
+    fn display_name_without_counts() {
+        let own_user_id = user_id("@alice:example.org");
+        let mut state = RoomState::default();
+        state.apply_summary(summary(
+            Some(&["@alice:example.org", "@bob:example.org", "@carol:example.org"]),
+            None,
+            None,
+        ));
+
+        assert_eq!(
+            state.display_name(Some(&own_user_id)),
+            "@bob:example.org and @carol:example.org"
+        );
+    }
+}
@@ -0,0 +1,334 @@
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    thread,
+};
+
+use futures::{sync::oneshot, Future};
+use glib;
+use ring::{
+    aead::{self, OpeningKey, SealingKey, CHACHA20_POLY1305},
+    error::Unspecified,
+    rand::{SecureRandom, SystemRandom},
+};
+use ruma_identifiers::UserId;
+use secret_service::{EncryptionType, SecretService, SsError};
+
+use super::session_store::{self, account_dir};
+
+const KEY_FILE_NAME: &str = "secrets.key";
+const SECRET_FILE_NAME: &str = "access_token.enc";
+/// Where access tokens were stored unencrypted before.
+const LEGACY_SECRET_FILE_NAME: &str = "access_token";
+const NONCE_LEN: usize = 12;
+
+#[derive(Debug)]
+pub enum Error {
+    SecretService(SsError),
+    SessionStore(session_store::Error),
+    Io(io::Error),
+    NoConfigDir,
+    Crypto,
+    InvalidSecret,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::SecretService(ref e) => write!(f, "secret service error: {}", e),
+            Error::SessionStore(ref e) => write!(f, "{}", e),
+            Error::Io(ref e) => write!(f, "I/O error: {}", e),
+            Error::NoConfigDir => write!(f, "could not determine the config directory"),
+            Error::Crypto => write!(f, "failed to encrypt or decrypt secret"),
+            Error::InvalidSecret => write!(f, "stored secret is not valid UTF-8"),
+        }
+    }
+}
+
+impl From<SsError> for Error {
+    fn from(e: SsError) -> Self {
+        Error::SecretService(e)
+    }
+}
+
+impl From<session_store::Error> for Error {
+    fn from(e: session_store::Error) -> Self {
+        Error::SessionStore(e)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<Unspecified> for Error {
+    fn from(_: Unspecified) -> Self {
+        Error::Crypto
+    }
+}
+
+/// A place to keep the access tokens of all accounts.
+pub trait SecretStore {
+    fn store(&self, user_id: &UserId, secret: &str) -> Result<(), Error>;
+    fn load(&self, user_id: &UserId) -> Result<Option<String>, Error>;
+    fn delete(&self, user_id: &UserId) -> Result<(), Error>;
+}
+
+/// Open the secret store to use, preferring the freedesktop Secret Service
+/// (gnome-keyring, KWallet, ...) and falling back to encrypted files if it's
+/// not available.
+pub fn open() -> Box<dyn SecretStore> {
+    match SecretServiceStore::new() {
+        Ok(store) => Box::new(store),
+        Err(e) => {
+            warn!(
+                "Secret Service is not available ({}), storing secrets in encrypted files instead",
+                e
+            );
+            Box::new(EncryptedFileStore)
+        }
+    }
+}
+
+/// Run `f` with the secret store on a thread of its own.
+///
+/// Talking to the Secret Service means blocking D-Bus calls, which would stall
+/// the syncs of all accounts if they were made on the background thread's
+/// event loop.
+pub fn run<F, T>(f: F) -> impl Future<Item = T, Error = ()>
+where
+    F: FnOnce(&dyn SecretStore) -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let (result_tx, result_rx) = oneshot::channel();
+    thread::spawn(move || {
+        let _ = result_tx.send(f(&*open()));
+    });
+
+    result_rx.map_err(|_| {
+        error!("The secret store thread panicked");
+    })
+}
+
+pub struct SecretServiceStore {
+    service: SecretService,
+}
+
+impl SecretServiceStore {
+    pub fn new() -> Result<Self, Error> {
+        Ok(SecretServiceStore {
+            service: SecretService::new(EncryptionType::Dh)?,
+        })
+    }
+
+    fn attributes(user_id: &str) -> Vec<(&str, &str)> {
+        vec![("application", "fest"), ("matrix-user-id", user_id)]
+    }
+}
+
+impl SecretStore for SecretServiceStore {
+    fn store(&self, user_id: &UserId, secret: &str) -> Result<(), Error> {
+        let user_id = user_id.to_string();
+        let collection = self.service.get_default_collection()?;
+        if collection.is_locked()? {
+            collection.unlock()?;
+        }
+
+        collection.create_item(
+            &format!("Fest access token for {}", user_id),
+            Self::attributes(&user_id),
+            secret.as_bytes(),
+            true, // replace an existing token
+            "text/plain",
+        )?;
+
+        Ok(())
+    }
+
+    fn load(&self, user_id: &UserId) -> Result<Option<String>, Error> {
+        let user_id = user_id.to_string();
+        let item = match self
+            .service
+            .search_items(Self::attributes(&user_id))?
+            .into_iter()
+            .next()
+        {
+            Some(item) => item,
+            None => return Ok(None),
+        };
+
+        if item.is_locked()? {
+            item.unlock()?;
+        }
+
+        String::from_utf8(item.get_secret()?)
+            .map(Some)
+            .map_err(|_| Error::InvalidSecret)
+    }
+
+    fn delete(&self, user_id: &UserId) -> Result<(), Error> {
+        let user_id = user_id.to_string();
+        for item in self.service.search_items(Self::attributes(&user_id))? {
+            item.delete()?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Fallback for systems without a Secret Service provider.
+///
+/// Secrets are encrypted with a key that is generated on first use and kept in
+/// the config directory, apart from the encrypted secrets in the data
+/// directory. Both are only readable by the current user. This doesn't protect
+/// against an attacker who can read all of the user's files, but keeps access
+/// tokens out of plain sight and out of copies or backups of the data
+/// directory.
+pub struct EncryptedFileStore;
+
+impl EncryptedFileStore {
+    fn key_path() -> Result<PathBuf, Error> {
+        glib::get_user_config_dir()
+            .map(|dir| dir.join("fest").join(KEY_FILE_NAME))
+            .ok_or(Error::NoConfigDir)
+    }
+
+    fn key() -> Result<[u8; 32], Error> {
+        let path = Self::key_path()?;
+        let mut key = [0; 32];
+
+        match File::open(&path) {
+            Ok(mut file) => {
+                file.read_exact(&mut key)?;
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                SystemRandom::new().fill(&mut key)?;
+
+                if let Some(dir) = path.parent() {
+                    fs::create_dir_all(dir)?;
+                }
+                OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .mode(0o600)
+                    .open(&path)?
+                    .write_all(&key)?;
+            }
+            Err(e) => return Err(e.into()),
+        }
+
+        Ok(key)
+    }
+
+    fn secret_path(user_id: &UserId) -> Result<PathBuf, Error> {
+        Ok(account_dir(user_id)?.join(SECRET_FILE_NAME))
+    }
+
+    /// Encrypt the unencrypted secret an older version of fest left in the
+    /// account directory, if there is one.
+    fn migrate_legacy_secret(&self, user_id: &UserId) -> Result<Option<String>, Error> {
+        let path = account_dir(user_id)?.join(LEGACY_SECRET_FILE_NAME);
+        let secret = match read_file(&path)? {
+            Some(data) => String::from_utf8(data).map_err(|_| Error::InvalidSecret)?,
+            None => return Ok(None),
+        };
+
+        info!("Encrypting the access token of {}", user_id);
+        self.store(user_id, &secret)?;
+        fs::remove_file(path)?;
+
+        Ok(Some(secret))
+    }
+}
+
+impl SecretStore for EncryptedFileStore {
+    fn store(&self, user_id: &UserId, secret: &str) -> Result<(), Error> {
+        let key = SealingKey::new(&CHACHA20_POLY1305, &Self::key()?)?;
+
+        let mut nonce = [0; NONCE_LEN];
+        SystemRandom::new().fill(&mut nonce)?;
+
+        // The user ID is authenticated along with the secret, so the secrets
+        // of two accounts can't be swapped
+        let tag_len = CHACHA20_POLY1305.tag_len();
+        let mut in_out = secret.as_bytes().to_vec();
+        in_out.resize(secret.len() + tag_len, 0);
+        let out_len = aead::seal_in_place(
+            &key,
+            &nonce,
+            user_id.to_string().as_bytes(),
+            &mut in_out,
+            tag_len,
+        )?;
+
+        let path = Self::secret_path(user_id)?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&path)?;
+        file.write_all(&nonce)?;
+        file.write_all(&in_out[..out_len])?;
+
+        Ok(())
+    }
+
+    fn load(&self, user_id: &UserId) -> Result<Option<String>, Error> {
+        let mut data = match read_file(&Self::secret_path(user_id)?)? {
+            Some(data) => data,
+            None => return self.migrate_legacy_secret(user_id),
+        };
+
+        if data.len() < NONCE_LEN {
+            return Err(Error::Crypto);
+        }
+
+        let key = OpeningKey::new(&CHACHA20_POLY1305, &Self::key()?)?;
+        let (nonce, ciphertext) = data.split_at_mut(NONCE_LEN);
+        let plaintext = aead::open_in_place(
+            &key,
+            nonce,
+            user_id.to_string().as_bytes(),
+            0,
+            ciphertext,
+        )?;
+
+        String::from_utf8(plaintext.to_vec())
+            .map(Some)
+            .map_err(|_| Error::InvalidSecret)
+    }
+
+    fn delete(&self, user_id: &UserId) -> Result<(), Error> {
+        let dir = account_dir(user_id)?;
+        for file_name in &[SECRET_FILE_NAME, LEGACY_SECRET_FILE_NAME] {
+            match fs::remove_file(dir.join(file_name)) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                result => result?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Read a whole file, or return `None` if it doesn't exist.
+fn read_file(path: &Path) -> Result<Option<Vec<u8>>, Error> {
+    let mut data = Vec::new();
+    match File::open(path) {
+        Ok(mut file) => file.read_to_end(&mut data)?,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    Ok(Some(data))
+}
@@ -0,0 +1,316 @@
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    fmt,
+    fs::{self, File},
+    io,
+    path::PathBuf,
+};
+
+use glib;
+use ring::rand::{SecureRandom, SystemRandom};
+use ruma_client::Session;
+use ruma_identifiers::UserId;
+use serde_json::{self, Value as JsonValue};
+use url::Url;
+
+use super::secret_store::SecretStore;
+
+const SESSION_FILE_NAME: &str = "session.json";
+const DEVICE_IDS_FILE_NAME: &str = "device_ids.json";
+
+#[derive(Debug)]
+pub enum Error {
+    NoDataDir,
+    Io(io::Error),
+    Json(serde_json::Error),
+    Random,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::NoDataDir => write!(f, "couldn't determine the user data directory"),
+            Error::Io(ref e) => write!(f, "I/O error: {}", e),
+            Error::Json(ref e) => write!(f, "invalid session file: {}", e),
+            Error::Random => write!(f, "failed to generate random data"),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Json(e)
+    }
+}
+
+/// Everything needed to resume using an account without logging in again.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StoredSession {
+    pub homeserver_url: Url,
+    pub user_id: UserId,
+    pub device_id: String,
+    /// The access token is kept in a [`SecretStore`], not in the session file.
+    ///
+    /// It is still deserialized, so session files written before that was
+    /// the case can be migrated.
+    #[serde(default, skip_serializing)]
+    pub access_token: String,
+    /// The `next_batch` token of the last sync response that was processed.
+    pub next_batch: Option<String>,
+    /// Guest accounts can be upgraded to full accounts later.
+    #[serde(default)]
+    pub is_guest: bool,
+    /// The filter for sync requests, once it was uploaded.
+    pub sync_filter: Option<StoredFilter>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StoredFilter {
+    pub id: String,
+    /// What was uploaded, so the filter is uploaded again when that changes.
+    pub definition: JsonValue,
+}
+
+impl StoredSession {
+    pub fn new(homeserver_url: Url, session: &Session) -> Self {
+        StoredSession {
+            homeserver_url,
+            user_id: session.user_id().clone(),
+            device_id: session.device_id().to_owned(),
+            access_token: session.access_token().to_owned(),
+            next_batch: None,
+            is_guest: false,
+            sync_filter: None,
+        }
+    }
+
+    pub fn to_ruma_session(&self) -> Session {
+        Session::new(
+            self.access_token.clone(),
+            self.user_id.clone(),
+            self.device_id.clone(),
+        )
+    }
+
+    pub fn save(&self) -> Result<(), Error> {
+        let dir = account_dir(&self.user_id)?;
+        fs::create_dir_all(&dir)?;
+
+        // Write to a temporary file first, so a crash while writing doesn't
+        // leave us with a corrupted session.
+        let tmp_path = dir.join(format!("{}.tmp", SESSION_FILE_NAME));
+        serde_json::to_writer_pretty(File::create(&tmp_path)?, self)?;
+        fs::rename(tmp_path, dir.join(SESSION_FILE_NAME))?;
+
+        Ok(())
+    }
+}
+
+/// The directory all of fest's persistent data is stored in.
+pub fn data_dir() -> Result<PathBuf, Error> {
+    glib::get_user_data_dir()
+        .map(|dir| dir.join("fest"))
+        .ok_or(Error::NoDataDir)
+}
+
+/// A device ID an account was logged into with before.
+#[derive(Serialize, Deserialize)]
+struct StoredDeviceId {
+    homeserver_url: Url,
+    device_id: String,
+}
+
+fn load_device_ids() -> Result<HashMap<UserId, StoredDeviceId>, Error> {
+    match File::open(data_dir()?.join(DEVICE_IDS_FILE_NAME)) {
+        Ok(file) => Ok(serde_json::from_reader(file)?),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(HashMap::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Get the device ID to log in with, generating one if there is none yet.
+///
+/// Device IDs are stored per matrix user ID, which is only known for sure
+/// after logging in. `username` can be a full user ID or just its localpart,
+/// which is looked up among the accounts that were logged into on the same
+/// homeserver before. The second value of the result tells whether the device
+/// ID was just generated; it is saved by `save_device_id` once logging in
+/// succeeded.
+pub fn device_id(username: &str, homeserver_url: &Url) -> Result<(String, bool), Error> {
+    let device_ids = load_device_ids()?;
+
+    let stored_device_id = match UserId::try_from(username) {
+        Ok(user_id) => device_ids.get(&user_id),
+        Err(_) => {
+            // Homeservers treat the localpart as case-insensitive on login
+            let localpart = username.to_lowercase();
+            device_ids
+                .iter()
+                .find(|&(user_id, stored)| {
+                    user_id.localpart() == localpart && stored.homeserver_url == *homeserver_url
+                })
+                .map(|(_, stored)| stored)
+        }
+    };
+
+    match stored_device_id {
+        Some(stored) => Ok((stored.device_id.clone(), false)),
+        None => Ok((generate_device_id()?, true)),
+    }
+}
+
+/// Get the device ID to log in with when the user ID is only known after
+/// logging in, as with single sign-on.
+///
+/// If exactly one account was logged into on `homeserver_url` before, it most
+/// likely is the same one again, so its device ID is reused. Otherwise this
+/// works like `device_id`.
+pub fn homeserver_device_id(homeserver_url: &Url) -> Result<(String, bool), Error> {
+    let device_ids = load_device_ids()?;
+    let mut on_homeserver = device_ids
+        .values()
+        .filter(|stored| stored.homeserver_url == *homeserver_url);
+
+    match (on_homeserver.next(), on_homeserver.next()) {
+        (Some(stored), None) => Ok((stored.device_id.clone(), false)),
+        _ => Ok((generate_device_id()?, true)),
+    }
+}
+
+/// Remember the device ID of an account, to log in with it again next time.
+pub fn save_device_id(
+    user_id: &UserId,
+    homeserver_url: &Url,
+    device_id: &str,
+) -> Result<(), Error> {
+    let mut device_ids = load_device_ids()?;
+    device_ids.insert(
+        user_id.clone(),
+        StoredDeviceId {
+            homeserver_url: homeserver_url.clone(),
+            device_id: device_id.to_owned(),
+        },
+    );
+
+    let dir = data_dir()?;
+    fs::create_dir_all(&dir)?;
+    serde_json::to_writer_pretty(File::create(dir.join(DEVICE_IDS_FILE_NAME))?, &device_ids)?;
+
+    Ok(())
+}
+
+fn generate_device_id() -> Result<String, Error> {
+    let mut bytes = [0; 10];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| Error::Random)?;
+
+    Ok(bytes
+        .iter()
+        .map(|b| (b'A' + b % 26) as char)
+        .collect())
+}
+
+/// The directory the persistent data of a single account is stored in.
+///
+/// Every account gets its own directory below `$XDG_DATA_HOME/fest/accounts`,
+/// named after its matrix user ID.
+pub fn account_dir(user_id: &UserId) -> Result<PathBuf, Error> {
+    Ok(data_dir()?.join("accounts").join(user_id.to_string()))
+}
+
+/// Delete everything stored about an account.
+pub fn remove(user_id: &UserId) -> Result<(), Error> {
+    match fs::remove_dir_all(account_dir(user_id)?) {
+        Ok(()) => Ok(()),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Load all sessions that were saved previously.
+///
+/// Sessions that can't be read are skipped (and logged).
+pub fn load_sessions() -> Vec<StoredSession> {
+    let accounts_dir = match data_dir() {
+        Ok(dir) => dir.join("accounts"),
+        Err(e) => {
+            error!("Can't load stored sessions: {}", e);
+            return Vec::new();
+        }
+    };
+
+    let entries = match fs::read_dir(&accounts_dir) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Vec::new(),
+        Err(e) => {
+            error!("Can't read {}: {}", accounts_dir.display(), e);
+            return Vec::new();
+        }
+    };
+
+    entries
+        .filter_map(|entry| {
+            let path = entry.ok()?.path().join(SESSION_FILE_NAME);
+            let result = File::open(&path)
+                .map_err(Error::from)
+                .and_then(|file| serde_json::from_reader(file).map_err(Error::from));
+
+            match result {
+                Ok(session) => Some(session),
+                Err(Error::Io(ref e)) if e.kind() == io::ErrorKind::NotFound => None,
+                Err(e) => {
+                    error!("Can't load session from {}: {}", path.display(), e);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// Fill in the access token of a session loaded from disk, or move it into the
+/// secret store if it comes from an old session file that still contains it.
+///
+/// `load_sessions` leaves this to `secret_store::run`, because it runs on the
+/// GTK thread and talking to the secret store can block.
+pub fn restore_access_token(
+    mut session: StoredSession,
+    secret_store: &dyn SecretStore,
+) -> Option<StoredSession> {
+    if !session.access_token.is_empty() {
+        info!("Moving access token of {} to the secret store", session.user_id);
+
+        match secret_store.store(&session.user_id, &session.access_token) {
+            Ok(()) => {
+                if let Err(e) = session.save() {
+                    error!("Failed to save session of {}: {}", session.user_id, e);
+                }
+            }
+            Err(e) => error!("Failed to store access token of {}: {}", session.user_id, e),
+        }
+
+        return Some(session);
+    }
+
+    match secret_store.load(&session.user_id) {
+        Ok(Some(access_token)) => {
+            session.access_token = access_token;
+            Some(session)
+        }
+        Ok(None) => {
+            warn!("No access token stored for {}, ignoring its session", session.user_id);
+            None
+        }
+        Err(e) => {
+            error!("Failed to load access token of {}: {}", session.user_id, e);
+            None
+        }
+    }
+}
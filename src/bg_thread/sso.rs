@@ -0,0 +1,182 @@
+// Single sign-on: the user logs in on a web page of the homeserver, which then
+// redirects the browser to a small HTTP server on the loopback interface,
+// passing a token to log in with.
+
+use std::{net::SocketAddr, time::Duration};
+
+use futures::{
+    self,
+    prelude::{async, await},
+    Future,
+    Stream,
+};
+use hyper::{self, server::conn::Http, service::service_fn, Body, Request, Response, StatusCode};
+use ruma_client::Session;
+use tokio_core::{
+    net::TcpListener,
+    reactor::{Handle, Timeout},
+};
+use url::Url;
+
+use super::{
+    api::{self, ConnectError, HttpClient},
+    register,
+    FrontendSender,
+};
+use crate::app::FrontendCommand;
+
+pub const SSO_LOGIN_TYPE: &str = "m.login.sso";
+
+/// How long to wait for the user to finish logging in in the browser, in
+/// seconds.
+const SSO_TIMEOUT: u64 = 10 * 60;
+
+const SUCCESS_PAGE: &str = "<!DOCTYPE html>
+<html>
+<head><meta charset=\"utf-8\"><title>fest</title></head>
+<body><p>You are logged in. You can close this page and return to fest.</p></body>
+</html>
+";
+
+/// Log in via single sign-on.
+///
+/// The page to log in on is passed to the frontend with
+/// `FrontendCommand::SingleSignOnStarted`, which opens it in the browser.
+#[async]
+pub fn log_in(
+    tokio_handle: Handle,
+    http_client: HttpClient,
+    homeserver_url: Url,
+    device_id: Option<String>,
+    frontend_chan_tx: FrontendSender,
+) -> Result<Session, ConnectError> {
+    let listener = TcpListener::bind(&SocketAddr::from(([127, 0, 0, 1], 0)), &tokio_handle)
+        .map_err(|e| ConnectError::Other(format!("Couldn't listen for the login token: {}", e)))?;
+    let redirect_url = listener
+        .local_addr()
+        .ok()
+        .and_then(|addr| Url::parse(&format!("http://{}/", addr)).ok())
+        .ok_or_else(|| ConnectError::Other("Couldn't listen for the login token.".to_owned()))?;
+
+    let sso_url =
+        api::sso_redirect_url(&homeserver_url, &redirect_url).map_err(ConnectError::from)?;
+
+    // TODO: Handle channel send errors?
+    let _ = frontend_chan_tx.send(FrontendCommand::SingleSignOnStarted {
+        url: sso_url.into_string(),
+    });
+
+    let token = await!(receive_login_token(tokio_handle, listener)).map_err(|()| {
+        ConnectError::Other("Didn't receive a login token from the browser.".to_owned())
+    })?;
+
+    let response = await!(api::log_in_with_token(
+        http_client,
+        homeserver_url,
+        token,
+        device_id,
+        super::device_display_name(),
+    ))
+    .map_err(ConnectError::from_login_error)?;
+
+    register::session_from_response(&response).map_err(|()| {
+        ConnectError::Other("The homeserver sent an invalid response.".to_owned())
+    })
+}
+
+/// Serve HTTP on `listener` until the browser is redirected there with a
+/// login token.
+#[async]
+fn receive_login_token(tokio_handle: Handle, listener: TcpListener) -> Result<String, ()> {
+    let (token_tx, token_rx) = futures::sync::mpsc::unbounded();
+
+    let http = Http::new();
+    let server_handle = tokio_handle.clone();
+    let server = listener.incoming().for_each(move |(stream, _)| {
+        let token_tx = token_tx.clone();
+        let service = service_fn(move |request: Request<Body>| {
+            let response = match login_token(&request) {
+                Some(token) => {
+                    let _ = token_tx.unbounded_send(token);
+                    Response::new(Body::from(SUCCESS_PAGE))
+                }
+                // Most likely the browser asking for a favicon
+                None => {
+                    let mut response = Response::new(Body::empty());
+                    *response.status_mut() = StatusCode::NOT_FOUND;
+                    response
+                }
+            };
+
+            Ok::<_, hyper::Error>(response)
+        });
+
+        server_handle.spawn(http.serve_connection(stream, service).map_err(|e| {
+            warn!("Error serving the single sign-on redirect: {}", e);
+        }));
+
+        Ok(())
+    });
+
+    // The server stops when this is dropped
+    let (_server_stop_tx, server_stop_rx) = futures::sync::oneshot::channel::<()>();
+    tokio_handle.spawn(
+        server
+            .map_err(|e| error!("Error listening for the login token: {}", e))
+            .select(server_stop_rx.map_err(|_| ()))
+            .then(|_| Ok(())),
+    );
+
+    let timeout = Timeout::new(Duration::from_secs(SSO_TIMEOUT), &tokio_handle).map_err(|e| {
+        error!("Failed to create timeout: {}", e);
+    })?;
+
+    let token = await!(token_rx
+        .into_future()
+        .map(|(token, _)| token)
+        .map_err(|_| ())
+        .select(timeout.map(|()| None).map_err(|e| {
+            error!("Timeout failed: {}", e);
+        }))
+        .map(|(token, _)| token)
+        .map_err(|(e, _)| e))?;
+
+    token.ok_or_else(|| {
+        error!("Timed out waiting for single sign-on");
+    })
+}
+
+fn login_token(request: &Request<Body>) -> Option<String> {
+    let url = Url::parse(&format!("http://localhost{}", request.uri())).ok()?;
+    url.query_pairs()
+        .find(|(name, _)| name == "loginToken")
+        .map(|(_, value)| value.into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(uri: &str) -> Request<Body> {
+        Request::builder().uri(uri).body(Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn login_token_from_redirect() {
+        assert_eq!(
+            login_token(&request("/?loginToken=abc123")),
+            Some("abc123".to_owned())
+        );
+        assert_eq!(
+            login_token(&request("/?state=x&loginToken=a%2Bb%3D")),
+            Some("a+b=".to_owned())
+        );
+    }
+
+    #[test]
+    fn login_token_missing() {
+        assert_eq!(login_token(&request("/")), None);
+        assert_eq!(login_token(&request("/favicon.ico")), None);
+        assert_eq!(login_token(&request("/?logintoken=abc123")), None);
+    }
+}
@@ -0,0 +1,132 @@
+use std::{
+    fs,
+    io,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use ruma_identifiers::UserId;
+
+use super::session_store::{self, account_dir};
+
+const TXN_ID_FILE_NAME: &str = "next_txn_id";
+
+/// Generates the transaction IDs for events sent by one account.
+///
+/// The homeserver uses transaction IDs to deduplicate requests per access
+/// token, so they must never repeat, not even across restarts.
+pub struct TxnIdGenerator {
+    path: Option<PathBuf>,
+    next: u64,
+}
+
+impl TxnIdGenerator {
+    pub fn load(user_id: &UserId) -> Self {
+        let path = account_dir(user_id)
+            .map(|dir| dir.join(TXN_ID_FILE_NAME))
+            .map_err(|e| {
+                error!("Can't persist transaction IDs of {}: {}", user_id, e);
+            })
+            .ok();
+
+        Self::load_from(path)
+    }
+
+    fn load_from(path: Option<PathBuf>) -> Self {
+        let stored = path
+            .as_ref()
+            .and_then(|path| match fs::read_to_string(path) {
+                Ok(content) => content.trim().parse().ok(),
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+                Err(e) => {
+                    error!("Can't read {}: {}", path.display(), e);
+                    None
+                }
+            })
+            .unwrap_or(0);
+
+        // If the stored counter got lost, starting at the current time still
+        // makes collisions with transaction IDs from before very unlikely.
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() * 1000 + u64::from(d.subsec_millis()))
+            .unwrap_or(0);
+
+        TxnIdGenerator {
+            path,
+            next: stored.max(now),
+        }
+    }
+
+    pub fn next(&mut self) -> String {
+        let txn_id = format!("fest.{}", self.next);
+        self.next += 1;
+
+        if let Err(e) = self.save() {
+            error!("Failed to save transaction ID counter: {}", e);
+        }
+
+        txn_id
+    }
+
+    fn save(&self) -> Result<(), session_store::Error> {
+        if let Some(ref path) = self.path {
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+
+            fs::write(path, self.next.to_string())?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+
+    fn counter(txn_id: &str) -> u64 {
+        txn_id.trim_start_matches("fest.").parse().unwrap()
+    }
+
+    fn test_path(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("fest-test-{}", process::id()));
+        let path = dir.join(name);
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn strictly_increasing_across_restarts() {
+        let path = test_path("round_trip");
+
+        let mut txn_ids = TxnIdGenerator::load_from(Some(path.clone()));
+        let first = counter(&txn_ids.next());
+        let second = counter(&txn_ids.next());
+        assert!(second > first);
+        drop(txn_ids);
+
+        let mut txn_ids = TxnIdGenerator::load_from(Some(path.clone()));
+        assert!(counter(&txn_ids.next()) > second);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn stored_counter_ahead_of_clock() {
+        let path = test_path("ahead_of_clock");
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, (u64::max_value() - 10).to_string()).unwrap();
+
+        let mut txn_ids = TxnIdGenerator::load_from(Some(path.clone()));
+        assert_eq!(counter(&txn_ids.next()), u64::max_value() - 10);
+
+        let mut txn_ids = TxnIdGenerator::load_from(Some(path.clone()));
+        assert_eq!(counter(&txn_ids.next()), u64::max_value() - 9);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,19 +1,30 @@
-#![feature(generators)]
+#![feature(generators, try_from)]
 
+extern crate chrono;
 extern crate futures_await as futures;
+extern crate gdk_pixbuf;
 extern crate gio;
 extern crate glib;
 extern crate gtk;
 extern crate hyper;
 extern crate hyper_tls;
+extern crate native_tls;
+extern crate ring;
+extern crate rusqlite;
 extern crate ruma_client;
 extern crate ruma_events;
 extern crate ruma_identifiers;
+extern crate secret_service;
+extern crate serde;
 extern crate tokio_core;
 extern crate url;
 
 #[macro_use]
 extern crate log;
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate serde_json;
 
 #[macro_use]
 mod util;